@@ -0,0 +1,49 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kvs::{BatchOp, KvStore, KvStoreOptions, KvsEngine};
+use tempfile::TempDir;
+
+// 100_000 个 1KB 的 value，log 大约 100MB
+const KEYS: u32 = 100_000;
+const VALUE_LEN: usize = 1024;
+
+// a store of about 100MB with one stale record, so a compaction rewrites all of it
+fn store_with(options: &KvStoreOptions) -> (TempDir, KvStore) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = options.open(temp_dir.path()).unwrap();
+    let value = "v".repeat(VALUE_LEN);
+    for chunk in (0..KEYS).collect::<Vec<_>>().chunks(10_000) {
+        let ops = chunk
+            .iter()
+            .map(|i| BatchOp::Set {
+                key: format!("key{}", i),
+                value: value.clone(),
+            })
+            .collect();
+        store.write_batch(ops).unwrap();
+    }
+    store.remove("key0".to_owned()).unwrap();
+    (temp_dir, store)
+}
+
+// compaction of a 100MB store, with 8KB vs 1MB log buffers
+fn compaction_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction_bench");
+    group.sample_size(10);
+    for &(name, buffer_size) in &[("8KB", 8 * 1024), ("1MB", 1024 * 1024)] {
+        let options = KvStoreOptions::new()
+            .compaction_threshold(u64::MAX)
+            .buffer_size(buffer_size)
+            .clone();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || store_with(&options),
+                |(_temp_dir, store)| store.compact().unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, compaction_bench);
+criterion_main!(benches);
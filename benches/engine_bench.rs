@@ -1,6 +1,8 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{BatchOp, KvStore, KvStoreOptions, KvsEngine, LogEncoding, SledKvsEngine};
 use rand::prelude::*;
+use std::fs;
+use std::path::Path;
 use tempfile::TempDir;
 
 fn set_bench(c: &mut Criterion) {
@@ -11,7 +13,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut store, _temp_dir)| {
+            |(store, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     store.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -25,7 +27,7 @@ fn set_bench(c: &mut Criterion) {
                 let temp_dir = TempDir::new().unwrap();
                 (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -38,10 +40,10 @@ fn set_bench(c: &mut Criterion) {
 
 fn get_bench(c: &mut Criterion) {
     let mut group = c.benchmark_group("get_bench");
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("kvs_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -55,10 +57,10 @@ fn get_bench(c: &mut Criterion) {
             })
         });
     }
-    for i in &vec![8, 12, 16, 20] {
+    for i in &[8, 12, 16, 20] {
         group.bench_with_input(format!("sled_{}", i), i, |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut db = SledKvsEngine::open(temp_dir.path()).unwrap();
+            let db = SledKvsEngine::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 db.set(format!("key{}", key_i), "value".to_string())
                     .unwrap();
@@ -72,5 +74,203 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// one flush per key vs a single `write_batch` for the same bulk load
+fn batch_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("batch_bench");
+    group.bench_function("set", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(store, _temp_dir)| {
+                for i in 0..10_000 {
+                    store.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("write_batch", |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let ops: Vec<_> = (0..10_000)
+                    .map(|i| BatchOp::Set {
+                        key: format!("key{}", i),
+                        value: "value".to_string(),
+                    })
+                    .collect();
+                (KvStore::open(temp_dir.path()).unwrap(), ops, temp_dir)
+            },
+            |(store, ops, _temp_dir)| {
+                store.write_batch(ops).unwrap();
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// one flush per write vs writes left in the buffer until an explicit flush
+fn buffered_writes_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffered_writes_bench");
+    for &buffered in &[false, true] {
+        let name = if buffered {
+            "buffered"
+        } else {
+            "flush_every_write"
+        };
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let store = KvStoreOptions::new()
+                        .buffered_writes(buffered)
+                        .open(temp_dir.path())
+                        .unwrap();
+                    (store, temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    for i in 0..10_000 {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                    store.flush().unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn log_size(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum()
+}
+
+fn log_encoding_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("log_encoding_bench");
+    for encoding in [LogEncoding::Json, LogEncoding::Bincode] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStoreOptions::new()
+            .log_encoding(encoding)
+            .open(temp_dir.path())
+            .unwrap();
+        for i in 0..10_000 {
+            store.set(format!("key{}", i), "value".to_string()).unwrap();
+        }
+        println!(
+            "{:?} log size for 10000 sets: {} bytes",
+            encoding,
+            log_size(temp_dir.path())
+        );
+
+        group.bench_function(format!("{:?}", encoding), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let store = KvStoreOptions::new()
+                        .log_encoding(encoding)
+                        .open(temp_dir.path())
+                        .unwrap();
+                    (store, temp_dir)
+                },
+                |(store, _temp_dir)| {
+                    for i in 0..1000 {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// open of a store with many large generations, its logs loaded by one thread
+// vs all the threads of the pool
+fn open_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    for gen in 0..16 {
+        let store = KvStoreOptions::new()
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+            .unwrap();
+        for i in 0..20_000 {
+            store
+                .set(format!("key{}", i), format!("value{}-{}", gen, i))
+                .unwrap();
+        }
+    }
+
+    let mut group = c.benchmark_group("open_bench");
+    // 只有一个 CPU 时两者的线程数一样，用名字区分
+    for (name, threads) in [("serial", 1), ("parallel", num_cpus())] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| pool.install(|| KvStore::open_read_only(temp_dir.path()).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+// random gets from several threads, under the store lock vs with pooled readers
+fn concurrent_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_get_bench");
+    for pooled in [false, true] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut options = KvStoreOptions::new();
+        if pooled {
+            options.reader_pool(16);
+        }
+        let store = options.open(temp_dir.path()).unwrap();
+        for i in 0..10_000 {
+            store.set(format!("key{}", i), "x".repeat(256)).unwrap();
+        }
+        for threads in [1, 2, 4, 8] {
+            let name = format!("{}_{}", if pooled { "pooled" } else { "locked" }, threads);
+            group.bench_function(name, |b| {
+                b.iter(|| {
+                    std::thread::scope(|s| {
+                        for t in 0..threads {
+                            let store = &store;
+                            s.spawn(move || {
+                                let mut rng = SmallRng::from_seed([t as u8; 16]);
+                                for _ in 0..1000 {
+                                    store
+                                        .get(format!("key{}", rng.gen_range(0, 10_000)))
+                                        .unwrap();
+                                }
+                            });
+                        }
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn num_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    batch_bench,
+    buffered_writes_bench,
+    log_encoding_bench,
+    open_bench,
+    concurrent_get_bench
+);
 criterion_main!(benches);
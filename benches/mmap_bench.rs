@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{BatchOp, KvStoreOptions, KvsEngine};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+// 100_000 个 1KB 的 value，log 大约 100MB
+const KEYS: u32 = 100_000;
+const VALUE_LEN: usize = 1024;
+
+// random `get` over a 100MB store, seek+read vs memory-mapped readers
+fn mmap_get_bench(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStoreOptions::new().open(temp_dir.path()).unwrap();
+    let value = "v".repeat(VALUE_LEN);
+    for chunk in (0..KEYS).collect::<Vec<_>>().chunks(10_000) {
+        let ops = chunk
+            .iter()
+            .map(|i| BatchOp::Set {
+                key: format!("key{}", i),
+                value: value.clone(),
+            })
+            .collect();
+        store.write_batch(ops).unwrap();
+    }
+    drop(store);
+
+    let mut group = c.benchmark_group("mmap_get_bench");
+    for &mmap_reads in &[false, true] {
+        let name = if mmap_reads { "mmap" } else { "buffered" };
+        let store = KvStoreOptions::new()
+            .mmap_reads(mmap_reads)
+            .open(temp_dir.path())
+            .unwrap();
+        let mut rng = SmallRng::from_seed([0; 16]);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                store.get(format!("key{}", rng.gen_range(0, KEYS))).unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, mmap_get_bench);
+criterion_main!(benches);
@@ -0,0 +1,78 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{ConnectOptions, KvStore, KvsClient, KvsServer, Protocol};
+use serde::Serialize;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// same shape as the `Request` sent on the wire, so both encode to the same bytes
+#[derive(Serialize)]
+enum Request {
+    Set { key: String, value: String },
+}
+
+fn payload_size() {
+    let req = Request::Set {
+        key: "key1".to_owned(),
+        value: "value".repeat(20),
+    };
+    for protocol in [Protocol::Json, Protocol::Bincode] {
+        println!(
+            "{:?} set request payload: {} bytes",
+            protocol,
+            protocol.encode(&req).unwrap().len()
+        );
+    }
+}
+
+fn round_trip_bench(c: &mut Criterion) {
+    payload_size();
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4300";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut group = c.benchmark_group("round_trip_bench");
+    for protocol in [Protocol::Json, Protocol::Bincode] {
+        let mut client = KvsClient::connect_with_protocol(addr, protocol).unwrap();
+        client.set("key1".to_owned(), "value".repeat(20)).unwrap();
+        group.bench_function(format!("{:?}", protocol), |b| {
+            b.iter(|| client.get("key1".to_owned()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// small request/response pairs, with TCP_NODELAY set on both ends or on neither
+fn nodelay_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nodelay_bench");
+    for (nodelay, addr) in [(true, "127.0.0.1:4301"), (false, "127.0.0.1:4302")] {
+        let temp_dir = TempDir::new().unwrap();
+        let server = KvsServer::new(
+            KvStore::open(temp_dir.path()).unwrap(),
+            SharedQueueThreadPool::new(2).unwrap(),
+        )
+        .nodelay(nodelay);
+        thread::spawn(move || server.run(addr));
+
+        let options = ConnectOptions {
+            nodelay,
+            ..ConnectOptions::default()
+        };
+        let mut client = KvsClient::connect_with(addr, options).unwrap();
+        client.set("key1".to_owned(), "value".repeat(20)).unwrap();
+        group.bench_function(format!("nodelay_{}", nodelay), |b| {
+            b.iter(|| client.get("key1".to_owned()).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, round_trip_bench, nodelay_bench);
+criterion_main!(benches);
@@ -0,0 +1,127 @@
+use crate::{KvsError, Result};
+
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_MAX_FILES: u32 = 5;
+
+/// A file recording every request served by a `KvsServer`, one JSON object per
+/// line, apart from the diagnostic `log`/`tracing` output.
+///
+/// Each line has the fields `ts_unix_ms`, `peer`, `op`, `key`, `result` (`ok`
+/// or `err`) and `latency_ms`. Before a line would grow the file past
+/// `max_bytes`, the file is rotated: `<path>.1` is renamed to `<path>.2` and so
+/// on, the file to `<path>.1`, and a new file is started. The oldest rotated
+/// file past `max_files` is deleted.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    file: Mutex<ActiveFile>,
+}
+
+/// The file lines are appended to and its length.
+struct ActiveFile {
+    file: File,
+    len: u64,
+}
+
+/// One line of the audit log.
+#[derive(Serialize)]
+pub(crate) struct AuditEntry<'a> {
+    pub(crate) ts_unix_ms: u64,
+    pub(crate) peer: SocketAddr,
+    pub(crate) op: &'a str,
+    pub(crate) key: &'a str,
+    pub(crate) result: &'a str,
+    pub(crate) latency_ms: f64,
+}
+
+impl AuditLog {
+    /// Opens the audit file at `path`, appending to it if it exists, rotated
+    /// once it reaches `max_bytes`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidOption` if `max_bytes` is zero, and
+    /// propagates I/O errors opening the file.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> Result<AuditLog> {
+        if max_bytes == 0 {
+            return Err(KvsError::InvalidOption(
+                "audit log max_bytes must be nonzero".to_owned(),
+            ));
+        }
+        let path = path.into();
+        let file = open_append(&path)?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            max_bytes,
+            max_files: DEFAULT_MAX_FILES,
+            file: Mutex::new(ActiveFile { file, len }),
+        })
+    }
+
+    /// Sets how many rotated files are kept, 5 by default. With zero, the file
+    /// is truncated instead of rotated.
+    pub fn max_files(mut self, files: u32) -> Self {
+        self.max_files = files;
+        self
+    }
+
+    /// Appends `entry` as a line, rotating the file first if the line does not fit.
+    pub(crate) fn record(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut active = self.file.lock().expect("audit log mutex poisoned");
+        // 单独一行超过 max_bytes 时也要写入，只是不和其它行放在同一个文件里
+        if active.len > 0 && active.len + line.len() as u64 > self.max_bytes {
+            self.rotate(&mut active)?;
+        }
+        // 一行一次写入，并发的 request 不会交错
+        active.file.write_all(&line)?;
+        active.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, active: &mut ActiveFile) -> io::Result<()> {
+        if self.max_files == 0 {
+            active.file.set_len(0)?;
+            active.len = 0;
+            return Ok(());
+        }
+        for n in (1..self.max_files).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        active.file = open_append(&self.path)?;
+        active.len = 0;
+        Ok(())
+    }
+
+    /// The path of the `n`th most recent rotated file, `<path>.<n>`.
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+pub(crate) fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,220 @@
+use clap::Parser;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{EngineKind, KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// 每个 job 处理的 key 数，job 太小时测的是 pool 的调度而不是 engine
+const KEYS_PER_JOB: u64 = 100;
+
+/// Measure the write and random-read throughput of every engine under every thread pool.
+#[derive(Parser)]
+#[clap(name = env!("CARGO_PKG_NAME"), about = env!("CARGO_PKG_DESCRIPTION"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
+struct Opts {
+    /// number of keys written, and of random reads after
+    #[clap(long, default_value = "10000")]
+    keys: u64,
+    /// length of every value, in bytes
+    #[clap(long, default_value = "100")]
+    value_size: usize,
+    /// number of threads of every pool
+    #[clap(long, default_value = "4")]
+    threads: u32,
+    /// seed of the random reads, the same seed reads the same keys in the same order
+    #[clap(long, default_value = "0")]
+    seed: u64,
+    /// directory the data directory of every run is created in, the system temp directory by
+    /// default
+    #[clap(long)]
+    dir: Option<PathBuf>,
+}
+
+/// The throughput of one engine under one pool.
+struct Row {
+    engine: EngineKind,
+    pool: &'static str,
+    writes: f64,
+    reads: f64,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    match run(&opts) {
+        Ok(rows) => print_table(&rows),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+fn run(opts: &Opts) -> Result<Vec<Row>> {
+    if opts.keys == 0 || opts.threads == 0 {
+        return Err(KvsError::StringError(
+            "--keys and --threads must be nonzero".to_owned(),
+        ));
+    }
+    let base = opts.dir.clone().unwrap_or_else(env::temp_dir);
+    let mut rows = Vec::new();
+    for engine in [EngineKind::Kvs, EngineKind::Sled] {
+        rows.push(run_pool::<NaiveThreadPool>(opts, &base, engine, "naive")?);
+        rows.push(run_pool::<SharedQueueThreadPool>(
+            opts,
+            &base,
+            engine,
+            "shared_queue",
+        )?);
+        rows.push(run_pool::<RayonThreadPool>(opts, &base, engine, "rayon")?);
+    }
+    Ok(rows)
+}
+
+/// Runs the workload on a fresh data directory, removed afterwards.
+fn run_pool<P: ThreadPool>(
+    opts: &Opts,
+    base: &Path,
+    engine: EngineKind,
+    pool_name: &'static str,
+) -> Result<Row> {
+    let dir = base.join(format!(
+        "kvs-bench-{}-{}-{}",
+        std::process::id(),
+        engine,
+        pool_name
+    ));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    let pool = P::new(opts.threads)?;
+    // sled 要在删除目录前关闭，所以 engine 在 workload 里打开和 drop
+    let res = match engine {
+        EngineKind::Kvs => workload(KvStore::open(&dir)?, &pool, opts),
+        EngineKind::Sled => workload(SledKvsEngine::open(&dir)?, &pool, opts),
+    };
+    fs::remove_dir_all(&dir)?;
+    let (write_time, read_time) = res?;
+    Ok(Row {
+        engine,
+        pool: pool_name,
+        writes: ops_per_sec(opts.keys, write_time),
+        reads: ops_per_sec(opts.keys, read_time),
+    })
+}
+
+/// Writes `opts.keys` keys, then reads as many random ones, from jobs of the pool,
+/// returning the time each phase took.
+fn workload<E: KvsEngine + Clone, P: ThreadPool>(
+    engine: E,
+    pool: &P,
+    opts: &Opts,
+) -> Result<(Duration, Duration)> {
+    let value = "v".repeat(opts.value_size);
+
+    let start = Instant::now();
+    run_jobs(pool, opts.keys, |first, last| {
+        let engine = engine.clone();
+        let value = value.clone();
+        move || {
+            for i in first..last {
+                engine.set(format!("key{}", i), value.clone())?;
+            }
+            Ok(())
+        }
+    })?;
+    let write_time = start.elapsed();
+
+    let keys = opts.keys;
+    let seed = opts.seed;
+    let start = Instant::now();
+    run_jobs(pool, opts.keys, |first, last| {
+        let engine = engine.clone();
+        move || {
+            // 每个 job 的随机序列只取决于 seed 和它的第一个 key
+            let mut rng = XorShift::new(seed ^ first);
+            for _ in first..last {
+                if engine.get(format!("key{}", rng.next() % keys))?.is_none() {
+                    return Err(KvsError::KeyNotFound);
+                }
+            }
+            Ok(())
+        }
+    })?;
+    let read_time = start.elapsed();
+
+    Ok((write_time, read_time))
+}
+
+/// Splits `0..keys` into jobs of `KEYS_PER_JOB` keys, spawns the job `make_job`
+/// returns for each range on `pool` and waits for all of them.
+fn run_jobs<P, M, F>(pool: &P, keys: u64, mut make_job: M) -> Result<()>
+where
+    P: ThreadPool,
+    M: FnMut(u64, u64) -> F,
+    F: FnOnce() -> Result<()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut jobs = 0;
+    let mut first = 0;
+    while first < keys {
+        let last = keys.min(first + KEYS_PER_JOB);
+        let job = make_job(first, last);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            let _ = tx.send(job());
+        });
+        jobs += 1;
+        first = last;
+    }
+    drop(tx);
+    for _ in 0..jobs {
+        // job panic 时不会发送结果，channel 关闭后 recv 返回错误
+        rx.recv()
+            .map_err(|_| KvsError::StringError("a benchmark job panicked".to_owned()))??;
+    }
+    Ok(())
+}
+
+fn ops_per_sec(ops: u64, time: Duration) -> f64 {
+    ops as f64 / time.as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
+fn print_table(rows: &[Row]) {
+    println!(
+        "{:<8}{:<16}{:>16}{:>16}",
+        "engine", "pool", "write ops/s", "read ops/s"
+    );
+    for row in rows {
+        println!(
+            "{:<8}{:<16}{:>16.0}{:>16.0}",
+            row.engine.to_string(),
+            row.pool,
+            row.writes,
+            row.reads
+        );
+    }
+}
+
+/// A xorshift64 generator, enough to pick keys and reproducible across runs.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // 状态不能为 0
+        XorShift(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
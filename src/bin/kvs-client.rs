@@ -1,55 +1,91 @@
-use clap::{AppSettings, Clap};
-use kvs::{KvsClient, Result};
-use std::net::SocketAddr;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use kvs::{resolve_addr, KvsClient, Result};
+use serde_json::json;
 use std::process::exit;
 
-#[derive(Clap)]
+#[derive(Parser)]
 #[clap(name = env!("CARGO_PKG_NAME"), about = env!("CARGO_PKG_DESCRIPTION"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
-#[clap(setting = AppSettings::ColoredHelp)]
 struct Opts {
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
 
-#[derive(Clap)]
+#[derive(Subcommand)]
 enum SubCommand {
     Set(SetParams),
     Get(GetParams),
     Rm(RmParams),
+    Ping(PingParams),
 }
 
 /// Set the value of a string key to a string. Print an error and return a non-zero exit code on failure.
-#[derive(Clap)]
+#[derive(Args)]
 struct SetParams {
     key: String,
     value: String,
 
-    /// accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If
-    /// --addr is not specified then connect on
+    /// accepts an IP address, either v4 or v6, or a host name, and a port number, with the
+    /// format HOST:PORT, such as localhost:4000 or [::1]:4000. If --addr is not specified then
+    /// connect on
     #[clap(long, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    addr: String,
+
+    /// authenticate with this shared secret before sending the request
+    #[clap(long)]
+    auth_token: Option<String>,
 }
 
 /// Get the string value of a given string key. Print an error and return a non-zero exit code on failure.
-#[derive(Clap)]
+#[derive(Args)]
 struct GetParams {
     key: String,
 
-    /// accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If
-    /// --addr is not specified then connect on
+    /// how to print the result: the value or `Key not found` with `plain`, an object
+    /// `{"key":..,"value":..}` with a null value for a missing key with `json`
+    #[clap(long, value_enum, default_value = "plain")]
+    format: Format,
+
+    /// accepts an IP address, either v4 or v6, or a host name, and a port number, with the
+    /// format HOST:PORT, such as localhost:4000 or [::1]:4000. If --addr is not specified then
+    /// connect on
     #[clap(long, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    addr: String,
+
+    /// authenticate with this shared secret before sending the request
+    #[clap(long)]
+    auth_token: Option<String>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Plain,
+    Json,
 }
 
 /// Remove a given key. Print an error and return a non-zero exit code on failure.
-#[derive(Clap)]
+#[derive(Args)]
 struct RmParams {
     key: String,
 
-    /// accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If
-    /// --addr is not specified then connect on
+    /// accepts an IP address, either v4 or v6, or a host name, and a port number, with the
+    /// format HOST:PORT, such as localhost:4000 or [::1]:4000. If --addr is not specified then
+    /// connect on
     #[clap(long, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    addr: String,
+
+    /// authenticate with this shared secret before sending the request
+    #[clap(long)]
+    auth_token: Option<String>,
+}
+
+/// Check that the server is alive and print the round-trip time. Print an error and return a non-zero exit code on failure.
+#[derive(Args)]
+struct PingParams {
+    /// accepts an IP address, either v4 or v6, or a host name, and a port number, with the
+    /// format HOST:PORT, such as localhost:4000 or [::1]:4000. If --addr is not specified then
+    /// connect on
+    #[clap(long, default_value = "127.0.0.1:4000")]
+    addr: String,
 }
 
 fn main() {
@@ -63,23 +99,53 @@ fn main() {
 
 fn run(opts: Opts) -> Result<()> {
     match opts.subcmd {
-        SubCommand::Set(SetParams { key, value, addr }) => {
-            let mut client = KvsClient::connect(addr)?;
+        SubCommand::Set(SetParams {
+            key,
+            value,
+            addr,
+            auth_token,
+        }) => {
+            let mut client = connect(addr, auth_token)?;
             client.set(key, value)?;
         }
-        SubCommand::Get(GetParams { key, addr }) => {
-            let mut client = KvsClient::connect(addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                print!("Key not found");
+        SubCommand::Get(GetParams {
+            key,
+            format,
+            addr,
+            auth_token,
+        }) => {
+            let mut client = connect(addr, auth_token)?;
+            let value = client.get(key.clone())?;
+            match (format, value) {
+                (Format::Plain, Some(value)) => println!("{}", value),
+                (Format::Plain, None) => println!("Key not found"),
+                (Format::Json, value) => {
+                    println!("{}", json!({ "key": key, "value": value }))
+                }
             }
         }
-        SubCommand::Rm(RmParams { key, addr }) => {
-            let mut client = KvsClient::connect(addr)?;
+        SubCommand::Rm(RmParams {
+            key,
+            addr,
+            auth_token,
+        }) => {
+            let mut client = connect(addr, auth_token)?;
             client.remove(key)?;
         }
+        SubCommand::Ping(PingParams { addr }) => {
+            let mut client = connect(addr, None)?;
+            let rtt = client.ping()?;
+            println!("pong in {:.3} ms", rtt.as_secs_f64() * 1000.0);
+        }
     }
 
     Ok(())
 }
+
+fn connect(addr: String, auth_token: Option<String>) -> Result<KvsClient> {
+    let mut client = KvsClient::connect(&resolve_addr(&addr)?[..])?;
+    if let Some(token) = auth_token {
+        client.auth(token)?;
+    }
+    Ok(client)
+}
@@ -1,67 +1,135 @@
-use clap::{AppSettings, Clap};
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use log::{error, info, warn, LevelFilter};
+use clap::Parser;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    detect_engine, resolve_addr, serve_metrics, write_engine_marker, AuditLog, EngineKind,
+    KvStoreOptions, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine, StoreStats,
+};
+use log::{error, info, LevelFilter};
+use serde::Deserialize;
 use std::env::current_dir;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
 use std::process::exit;
-use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
 
-const DEFAULT_ENGINE: Engine = Engine::kvs;
-const ENGINE_FILE: &str = "engine";
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: EngineKind = EngineKind::Kvs;
+const DEFAULT_CONFIG_FILE: &str = "kvs-server.toml";
+const DEFAULT_THREADS: u32 = 4;
+const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+const LOG_LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
 
-#[derive(Clap)]
+/// The statistics of the engine served, `None` for an engine without any.
+type StatsFn = Box<dyn Fn() -> Result<Option<StoreStats>> + Send>;
+
+#[derive(Parser)]
 #[clap(name = env!("CARGO_PKG_NAME"), about = env!("CARGO_PKG_DESCRIPTION"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
-#[clap(setting = AppSettings::ColoredHelp)]
 struct Opts {
-    /// accepts an IP address, either v4 or v6, and a port number, with the format IP:PORT. If
-    /// --addr is not specified then listen on
-    #[clap(long, default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    /// accepts an IP address, either v4 or v6, or a host name, and a port number, with the
+    /// format HOST:PORT, such as localhost:4000 or [::1]:4000. If --addr is not specified then
+    /// listen on 127.0.0.1:4000
+    #[clap(long)]
+    addr: Option<String>,
     /// engine name
     #[clap(long)]
-    engine: Option<Engine>,
+    engine: Option<EngineKind>,
+    /// log level: off, error, warn, info, debug or trace, info by default. `RUST_LOG` takes
+    /// precedence if set
+    #[clap(long)]
+    log_level: Option<LevelFilter>,
+    /// more verbose logging than --log-level, repeatable (-v for debug, -vv for trace)
+    #[clap(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// compaction threshold of the kvs engine, in bytes
+    #[clap(long)]
+    compaction_threshold: Option<u64>,
+    /// number of threads serving connections, the number of CPUs by default
+    #[clap(long)]
+    threads: Option<u32>,
+    /// serve Prometheus metrics over HTTP on this address, at /metrics
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// shared secret clients must authenticate with before any other request
+    #[clap(long)]
+    auth_token: Option<String>,
+    /// close connections that send nothing for this many seconds
+    #[clap(long)]
+    client_timeout: Option<u64>,
+    /// reject new connections while this many are being served, unlimited by default
+    #[clap(long)]
+    max_connections: Option<u32>,
+    /// record every request, one JSON line each, in this file
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    /// rotate the audit file once it reaches this many bytes, 64 MiB by default
+    #[clap(long)]
+    audit_log_max_bytes: Option<u64>,
+    /// directory of the data files and the engine marker, the current directory by default
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// config file, kvs-server.toml in the current directory by default
+    #[clap(long)]
+    config: Option<PathBuf>,
 }
 
-#[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum Engine {
-    kvs,
-    sled,
+/// The settings of `kvs-server.toml`, overridden by the command line flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    addr: Option<String>,
+    engine: Option<EngineKind>,
+    log_level: Option<String>,
+    compaction_threshold: Option<u64>,
+    threads: Option<u32>,
+    metrics_addr: Option<SocketAddr>,
+    auth_token: Option<String>,
+    client_timeout: Option<u64>,
+    max_connections: Option<u32>,
+    audit_log: Option<PathBuf>,
+    audit_log_max_bytes: Option<u64>,
+    data_dir: Option<PathBuf>,
 }
 
-// imple FromStr trait
-impl FromStr for Engine {
-    type Err = &'static str;
-
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        match s {
-            "kvs" => Ok(Engine::kvs),
-            "sled" => Ok(Engine::sled),
-            _ => Err("no match engine"),
-        }
+fn main() {
+    let mut opts: Opts = Opts::parse();
+    // 日志级别也可以来自配置文件，所以要先读配置文件再初始化日志
+    if let Err(e) = apply_config(&mut opts) {
+        eprintln!("{}", e);
+        exit(1);
     }
-}
 
-fn main() {
-    // init logging
+    // init logging, RUST_LOG 中的设置会覆盖命令行参数
     env_logger::Builder::new()
-        .filter_level(LevelFilter::max())
+        .filter_level(log_level(
+            opts.log_level.unwrap_or(DEFAULT_LOG_LEVEL),
+            opts.verbose,
+        ))
+        .parse_default_env()
         .init();
 
-    let mut opts: Opts = Opts::parse();
-
-    let res = current_engine().and_then(move |curr_engine| {
-        info!("curr engine: {:?}", curr_engine);
-        if opts.engine.is_none() {
-            opts.engine = curr_engine;
-        }
-        if curr_engine.is_some() && opts.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
-        }
-        run(opts)
-    });
+    let res = data_dir(&opts)
+        .and_then(detect_engine)
+        .and_then(move |curr_engine| {
+            info!("curr engine: {:?}", curr_engine);
+            if opts.engine.is_none() {
+                opts.engine = curr_engine;
+            }
+            if curr_engine.is_some() && opts.engine != curr_engine {
+                error!("Wrong engine!");
+                exit(1);
+            }
+            run(opts)
+        });
 
     if let Err(e) = res {
         error!("{:?}", e);
@@ -69,37 +137,119 @@ fn main() {
     }
 }
 
+// fill the flags missing from the command line with the config file
+fn apply_config(opts: &mut Opts) -> Result<()> {
+    let path = match &opts.config {
+        Some(path) => path.clone(),
+        None => {
+            let path = current_dir()?.join(DEFAULT_CONFIG_FILE);
+            // 默认的配置文件可以不存在
+            if !path.exists() {
+                return Ok(());
+            }
+            path
+        }
+    };
+    let invalid = |e: &dyn std::fmt::Display| {
+        KvsError::StringError(format!("invalid config file {}: {}", path.display(), e))
+    };
+    let config: Config = toml::from_str(&fs::read_to_string(&path)?).map_err(|e| invalid(&e))?;
+
+    opts.addr = opts.addr.take().or(config.addr);
+    opts.engine = opts.engine.or(config.engine);
+    if opts.log_level.is_none() {
+        opts.log_level = match config.log_level {
+            Some(level) => Some(level.parse().map_err(|e| invalid(&e))?),
+            None => None,
+        };
+    }
+    opts.compaction_threshold = opts.compaction_threshold.or(config.compaction_threshold);
+    opts.threads = opts.threads.or(config.threads);
+    opts.metrics_addr = opts.metrics_addr.or(config.metrics_addr);
+    opts.auth_token = opts.auth_token.take().or(config.auth_token);
+    opts.client_timeout = opts.client_timeout.or(config.client_timeout);
+    opts.max_connections = opts.max_connections.or(config.max_connections);
+    opts.audit_log = opts.audit_log.take().or(config.audit_log);
+    opts.audit_log_max_bytes = opts.audit_log_max_bytes.or(config.audit_log_max_bytes);
+    opts.data_dir = opts.data_dir.take().or(config.data_dir);
+    Ok(())
+}
+
+// raise `level` by one step for each `-v`
+fn log_level(level: LevelFilter, verbose: u8) -> LevelFilter {
+    let index = (level as usize + verbose as usize).min(LOG_LEVELS.len() - 1);
+    LOG_LEVELS[index]
+}
+
 fn run(opts: Opts) -> Result<()> {
     let engine = opts.engine.unwrap_or(DEFAULT_ENGINE);
+    let addr = opts.addr.clone().unwrap_or_else(|| DEFAULT_ADDR.to_owned());
+    let addrs = resolve_addr(&addr)?;
+    let threads = opts.threads.unwrap_or_else(num_threads);
+    let data_dir = data_dir(&opts)?;
     info!("kvs-server {:?}", env!("CARGO_PKG_VERSION"));
-    info!("Storage engine: {:?}", engine);
-    info!("Listening on {:?}", opts.addr);
+    info!("Storage engine: {}", engine);
+    info!("Data directory: {:?}", data_dir);
+    info!("Listening on {:?}", addr);
 
     // 写 engine 文件
-    fs::write(current_dir()?.join(ENGINE_FILE), format!("{:?}", engine))?;
+    fs::create_dir_all(&data_dir)?;
+    write_engine_marker(&data_dir, engine)?;
+
+    // 只有 kvs engine 有 stats
+    let (engine, stats): (Box<dyn KvsEngine>, StatsFn) = match engine {
+        EngineKind::Kvs => {
+            let mut options = KvStoreOptions::new();
+            if let Some(compaction_threshold) = opts.compaction_threshold {
+                options.compaction_threshold(compaction_threshold);
+            }
+            let store = options.open(data_dir)?;
+            let stats_store = store.clone();
+            (
+                Box::new(store),
+                Box::new(move || stats_store.stats().map(Some)),
+            )
+        }
+        EngineKind::Sled => (
+            Box::new(SledKvsEngine::open(data_dir)?),
+            Box::new(|| Ok(None)),
+        ),
+    };
 
-    match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, opts.addr),
-        Engine::sled => run_with_engine(SledKvsEngine::open(current_dir()?)?, opts.addr),
+    let pool = SharedQueueThreadPool::new(threads)?;
+    let mut server = KvsServer::new_boxed(engine, pool);
+    if let Some(token) = &opts.auth_token {
+        server = server.auth_token(token.clone());
+    }
+    if let Some(secs) = opts.client_timeout {
+        server = server.client_timeout(Duration::from_secs(secs));
+    }
+    if let Some(limit) = opts.max_connections {
+        server = server.max_connections(limit);
     }
+    if let Some(path) = &opts.audit_log {
+        let max_bytes = opts
+            .audit_log_max_bytes
+            .unwrap_or(DEFAULT_AUDIT_LOG_MAX_BYTES);
+        server = server.audit_log(AuditLog::open(path, max_bytes)?);
+    }
+    if let Some(metrics_addr) = opts.metrics_addr {
+        let listener = TcpListener::bind(metrics_addr)?;
+        let metrics = server.metrics();
+        thread::spawn(move || serve_metrics(listener, metrics, stats));
+    }
+    server.run(&addrs[..])
 }
 
-fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let mut server = KvsServer::new(engine);
-    server.run(addr)
+fn num_threads() -> u32 {
+    thread::available_parallelism()
+        .map(|n| n.get() as u32)
+        .unwrap_or(DEFAULT_THREADS)
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join(ENGINE_FILE);
-    if !engine.exists() {
-        return Ok(None);
-    }
-
-    match fs::read_to_string(engine)?.parse::<Engine>() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {:?}", e);
-            Ok(None)
-        }
+fn data_dir(opts: &Opts) -> Result<PathBuf> {
+    match &opts.data_dir {
+        Some(dir) => Ok(dir.clone()),
+        None => Ok(current_dir()?),
     }
 }
@@ -0,0 +1,113 @@
+use clap::{Args, Parser, Subcommand};
+use kvs::{CompactionStats, KvStore, KvsError, Result};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+
+#[derive(Parser)]
+#[clap(name = env!("CARGO_PKG_NAME"), about = env!("CARGO_PKG_DESCRIPTION"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"))]
+struct Opts {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+}
+
+#[derive(Subcommand)]
+enum SubCommand {
+    Dump(DumpParams),
+    Compact(CompactParams),
+    Repair(RepairParams),
+}
+
+/// Print every record of the log files with its offset and length, for debugging.
+#[derive(Args)]
+struct DumpParams {
+    /// only dump the log of this generation, all logs by default
+    #[clap(long)]
+    gen: Option<u64>,
+
+    /// directory of the store, the current directory by default
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+}
+
+/// Compact the log files of the store, dropping stale records.
+#[derive(Args)]
+struct CompactParams {
+    /// directory of the store, the current directory by default
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+}
+
+/// Cut the log files at corrupt records, drop broken index entries and compact the rest.
+#[derive(Args)]
+struct RepairParams {
+    /// directory of the store, the current directory by default
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+}
+
+fn main() {
+    let opts: Opts = Opts::parse();
+
+    if let Err(e) = run(opts) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opts: Opts) -> Result<()> {
+    match opts.subcmd {
+        SubCommand::Dump(DumpParams { gen, data_dir }) => {
+            KvStore::dump_log(resolve_dir(data_dir)?, gen, |record| {
+                println!(
+                    "gen={} offset={} len={} {} {}",
+                    record.gen, record.offset, record.len, record.op, record.key
+                );
+            })?;
+        }
+        SubCommand::Compact(CompactParams { data_dir }) => {
+            let dir = resolve_dir(data_dir)?;
+            let store = KvStore::open(&dir).map_err(|e| {
+                KvsError::StringError(format!("cannot open the store at {}: {}", dir.display(), e))
+            })?;
+            print_compaction(&store.compact()?);
+            store.close()?;
+        }
+        SubCommand::Repair(RepairParams { data_dir }) => {
+            let dir = resolve_dir(data_dir)?;
+            let report = KvStore::repair(&dir).map_err(|e| {
+                KvsError::StringError(format!(
+                    "cannot repair the store at {}: {}",
+                    dir.display(),
+                    e
+                ))
+            })?;
+            for (gen, offset) in report.truncated {
+                println!("truncated gen={} at offset={}", gen, offset);
+            }
+            for problem in report.dropped {
+                println!(
+                    "dropped {} at gen={} offset={}: {:?}",
+                    problem.key, problem.gen, problem.offset, problem.kind
+                );
+            }
+            print_compaction(&report.compaction);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_compaction(stats: &CompactionStats) {
+    println!(
+        "compacted {} bytes into {} bytes, {} entries retained, {} files removed",
+        stats.bytes_before, stats.bytes_after, stats.entries_retained, stats.files_removed
+    );
+}
+
+fn resolve_dir(data_dir: Option<PathBuf>) -> Result<PathBuf> {
+    match data_dir {
+        Some(dir) => Ok(dir),
+        None => Ok(current_dir()?),
+    }
+}
@@ -1,67 +1,379 @@
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
+use crate::common::{
+    check_server_handshake, encode_handshake, read_frame, remote_error, set_tcp_options,
+    write_frame, AuthResponse, ClearResponse, ContainsResponse, GetManyResponse,
+    GetOrErrorResponse, GetResponse, GetSetResponse, PingResponse, Protocol, RemoveResponse,
+    RenameResponse, Request, SetIfAbsentResponse, SetResponse, StatsResponse, SubscribeResponse,
+    HANDSHAKE_LEN,
+};
+use crate::{KvsError, Result, ServerStats};
 
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
-use std::io::{BufReader, BufWriter, Write};
+use serde::de::DeserializeOwned;
+use std::io::{self, BufReader, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// KvsClent
 pub struct KvsClient {
-    writer: BufWriter<TcpStream>,
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    stream: BufReader<Transport>,
+    protocol: Protocol,
 }
 
 impl KvsClient {
-    /// connect to a remote hosts
+    /// connect to a remote hosts, speaking `Protocol::Json`
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        let tcp_writer = TcpStream::connect(addr)?;
-        let tcp_reader = tcp_writer.try_clone()?;
-        // println!("client local addr: {:?}", tcp_writer.local_addr()?);
-        // println!("server addr: {:?}", tcp_writer.peer_addr()?);
-
-        Ok(KvsClient {
-            writer: BufWriter::new(tcp_writer),
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
-        })
+        KvsClient::connect_with_protocol(addr, Protocol::default())
+    }
+
+    /// connect to a remote hosts, speaking the given `protocol`
+    pub fn connect_with_protocol<A: ToSocketAddrs>(addr: A, protocol: Protocol) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        // println!("client local addr: {:?}", stream.local_addr()?);
+        // println!("server addr: {:?}", stream.peer_addr()?);
+        KvsClient::handshake(Transport::Tcp(stream), protocol)
+    }
+
+    /// connect to a remote hosts, speaking `Protocol::Json`, with a timeout on
+    /// each attempt and retries, see `ConnectOptions`
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ConnectFailed` with the error of the last attempt once
+    /// every attempt failed, and propagates errors of the handshake.
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, options: ConnectOptions) -> Result<Self> {
+        let mut backoff = options.backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match connect_timeout(&addr, options.timeout) {
+                Ok(stream) => {
+                    set_tcp_options(&stream, options.nodelay, options.keepalive)?;
+                    return KvsClient::handshake(Transport::Tcp(stream), Protocol::default());
+                }
+                Err(source) if attempts > options.retries => {
+                    return Err(KvsError::ConnectFailed { attempts, source })
+                }
+                Err(_) => {
+                    // server 可能还在启动，等待的时间每次加倍
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+            }
+        }
+    }
+
+    /// connect to a server started by `KvsServer::run_tls`, speaking `Protocol::Json`
+    ///
+    /// `addr` is `HOST:PORT`. The certificate of the server must be issued for
+    /// `HOST` and signed by one of the certificates of the PEM file `ca`.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(addr: &str, ca: impl AsRef<Path>) -> Result<Self> {
+        let config = crate::tls::client_config(ca.as_ref())?;
+        let stream = TcpStream::connect(&crate::common::resolve_addr(addr)?[..])?;
+        stream.set_nodelay(true)?;
+        let stream = crate::tls::connect(config, addr, stream)?;
+        KvsClient::handshake(Transport::Tls(Box::new(stream)), Protocol::default())
+    }
+
+    fn handshake(stream: Transport, protocol: Protocol) -> Result<Self> {
+        // 握手：发送 protocol 和协议版本，server 返回它的 protocol 和协议版本
+        let mut stream = BufReader::new(stream);
+        stream.get_mut().write_all(&encode_handshake(protocol))?;
+        stream.get_mut().flush()?;
+        let mut answer = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut answer)?;
+        check_server_handshake(protocol, &answer)?;
+
+        Ok(KvsClient { stream, protocol })
     }
 
     /// set
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
+        self.send(&Request::Set { key, value })?;
 
-        let resp = SetResponse::deserialize(&mut self.reader)?;
+        let resp: SetResponse = self.read_response()?;
         // println!("set response: {:?}", resp);
         match resp {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// set_if_absent, returning whether the value was written
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.send(&Request::SetIfAbsent { key, value })?;
+
+        let resp: SetIfAbsentResponse = self.read_response()?;
+        match resp {
+            SetIfAbsentResponse::Ok(written) => Ok(written),
+            SetIfAbsentResponse::Err(msg) => Err(remote_error(msg)),
         }
     }
 
     /// get
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
+        self.send(&Request::Get { key })?;
 
-        let resp = GetResponse::deserialize(&mut self.reader)?;
+        let resp: GetResponse = self.read_response()?;
         // println!("get response: {:?}", resp);
         match resp {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// get, with `KvsError::KeyNotFound` if the key does not exist
+    pub fn get_or_error(&mut self, key: String) -> Result<String> {
+        self.send(&Request::GetOrError { key })?;
+
+        let resp: GetOrErrorResponse = self.read_response()?;
+        match resp {
+            GetOrErrorResponse::Ok(value) => Ok(value),
+            GetOrErrorResponse::KeyNotFound => Err(KvsError::KeyNotFound),
+            GetOrErrorResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// get_set, returning the previous value
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        self.send(&Request::GetSet { key, value })?;
+
+        let resp: GetSetResponse = self.read_response()?;
+        match resp {
+            GetSetResponse::Ok(old) => Ok(old),
+            GetSetResponse::Err(msg) => Err(remote_error(msg)),
         }
     }
 
-    /// remove
+    /// remove, with `KvsError::KeyNotFound` if the key does not exist
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
+        self.send(&Request::Remove { key })?;
 
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
+        let resp: RemoveResponse = self.read_response()?;
         // println!("remove response: {:?}", resp);
         match resp {
             RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// rename
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        self.send(&Request::Rename { from, to })?;
+
+        let resp: RenameResponse = self.read_response()?;
+        match resp {
+            RenameResponse::Ok(_) => Ok(()),
+            RenameResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// ping, returning the round-trip time
+    ///
+    /// The server answers without touching the engine, and without requiring
+    /// authentication, so it works as a liveness probe.
+    pub fn ping(&mut self) -> Result<Duration> {
+        let start = Instant::now();
+        self.send(&Request::Ping)?;
+
+        let resp: PingResponse = self.read_response()?;
+        match resp {
+            PingResponse::Pong => Ok(start.elapsed()),
+            PingResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// stats, the request counters of the server
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        self.send(&Request::Stats)?;
+
+        let resp: StatsResponse = self.read_response()?;
+        match resp {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// clear, removing all keys
+    pub fn clear(&mut self) -> Result<()> {
+        self.send(&Request::Clear)?;
+
+        let resp: ClearResponse = self.read_response()?;
+        match resp {
+            ClearResponse::Ok(_) => Ok(()),
+            ClearResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// subscribe to the log records the server writes from now on, turning the
+    /// connection into a `Subscription`
+    ///
+    /// This is how a replica follows its primary, see `KvStore::follow`.
+    pub fn subscribe(mut self) -> Result<Subscription> {
+        self.send(&Request::Subscribe)?;
+
+        let resp: SubscribeResponse = self.read_response()?;
+        match resp {
+            SubscribeResponse::Ok(_) => Ok(Subscription {
+                stream: self.stream,
+                protocol: self.protocol,
+            }),
+            SubscribeResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// contains_key
+    pub fn contains_key(&mut self, key: String) -> Result<bool> {
+        self.send(&Request::Contains { key })?;
+
+        let resp: ContainsResponse = self.read_response()?;
+        match resp {
+            ContainsResponse::Ok(exists) => Ok(exists),
+            ContainsResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// get_many, the values in the order of `keys`
+    pub fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.send(&Request::GetMany { keys })?;
+
+        let resp: GetManyResponse = self.read_response()?;
+        match resp {
+            GetManyResponse::Ok(values) => Ok(values),
+            GetManyResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// authenticate with the shared `token` of a server started with an auth token
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unauthorized` if the token does not match.
+    pub fn auth(&mut self, token: String) -> Result<()> {
+        self.send(&Request::Auth { token })?;
+
+        let resp: AuthResponse = self.read_response()?;
+        match resp {
+            AuthResponse::Ok(_) => Ok(()),
+            AuthResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// write `req` as one frame and flush it
+    fn send(&mut self, req: &Request) -> Result<()> {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, self.protocol, req)?;
+        let stream = self.stream.get_mut();
+        stream.write_all(&buf)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// read the response of a request sent before
+    fn read_response<R: DeserializeOwned>(&mut self) -> Result<R> {
+        read_frame(&mut self.stream, self.protocol)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the response",
+            )
+            .into()
+        })
+    }
+}
+
+/// How `KvsClient::connect_with` connects.
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// the timeout of each attempt, nonzero
+    pub timeout: Duration,
+    /// the number of attempts after the first one fails
+    pub retries: u32,
+    /// the wait before the first retry, doubled before each next one
+    pub backoff: Duration,
+    /// whether to set `TCP_NODELAY`, so a request is sent at once
+    pub nodelay: bool,
+    /// send TCP keepalive probes once the connection is idle for this long
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for ConnectOptions {
+    /// A timeout of 5s, 3 retries, a backoff of 100ms, `TCP_NODELAY` and no keepalive.
+    fn default() -> Self {
+        ConnectOptions {
+            timeout: Duration::from_secs(5),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+/// Connects to the first address `addr` resolves to that accepts within `timeout`.
+fn connect_timeout<A: ToSocketAddrs>(addr: &A, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolves to nothing")
+    }))
+}
+
+/// The log records shipped by a server to a `KvsClient::subscribe`, in the
+/// order they were written.
+///
+/// The iterator ends when the server closes the connection.
+pub struct Subscription {
+    stream: BufReader<Transport>,
+    protocol: Protocol,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_frame(&mut self.stream, self.protocol).transpose()
+    }
+}
+
+/// The connection of a `KvsClient`, plain or encrypted.
+enum Transport {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.flush(),
         }
     }
 }
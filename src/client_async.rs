@@ -0,0 +1,99 @@
+use crate::common::{
+    check_server_handshake, encode_handshake, frame_codec, remote_error, ContainsResponse,
+    GetManyResponse, GetResponse, Protocol, RemoveResponse, Request, SetResponse, HANDSHAKE_LEN,
+};
+use crate::Result;
+
+use futures::{SinkExt, StreamExt};
+use serde::de::DeserializeOwned;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// KvsClientAsync, an async client running on the tokio runtime
+pub struct KvsClientAsync {
+    framed: Framed<TcpStream, LengthDelimitedCodec>,
+    protocol: Protocol,
+}
+
+impl KvsClientAsync {
+    /// connect to a remote hosts, speaking `Protocol::Json`
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        KvsClientAsync::connect_with_protocol(addr, Protocol::default()).await
+    }
+
+    /// connect to a remote hosts, speaking the given `protocol`
+    pub async fn connect_with_protocol<A: ToSocketAddrs>(
+        addr: A,
+        protocol: Protocol,
+    ) -> Result<Self> {
+        let mut tcp_stream = TcpStream::connect(addr).await?;
+        tcp_stream.set_nodelay(true)?;
+        // 握手：发送 protocol 和协议版本，server 返回它的 protocol 和协议版本
+        tcp_stream.write_all(&encode_handshake(protocol)).await?;
+        let mut answer = [0u8; HANDSHAKE_LEN];
+        tcp_stream.read_exact(&mut answer).await?;
+        check_server_handshake(protocol, &answer)?;
+        Ok(KvsClientAsync {
+            framed: Framed::new(tcp_stream, frame_codec()),
+            protocol,
+        })
+    }
+
+    /// set
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(&Request::Set { key, value }).await? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// get
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get { key }).await? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// remove
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Remove { key }).await? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// contains_key
+    pub async fn contains_key(&mut self, key: String) -> Result<bool> {
+        match self.request(&Request::Contains { key }).await? {
+            ContainsResponse::Ok(exists) => Ok(exists),
+            ContainsResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// get_many, the values in the order of `keys`
+    pub async fn get_many(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        match self.request(&Request::GetMany { keys }).await? {
+            GetManyResponse::Ok(values) => Ok(values),
+            GetManyResponse::Err(msg) => Err(remote_error(msg)),
+        }
+    }
+
+    /// send `req` as one frame and wait for the frame of its response
+    async fn request<R: DeserializeOwned>(&mut self, req: &Request) -> Result<R> {
+        self.framed
+            .send(Bytes::from(self.protocol.encode(req)?))
+            .await?;
+        match self.framed.next().await {
+            Some(frame) => self.protocol.decode(&frame?),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the response",
+            )
+            .into()),
+        }
+    }
+}
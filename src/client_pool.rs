@@ -0,0 +1,120 @@
+use crate::common::Protocol;
+use crate::{KvsClient, KvsError, Result};
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// KvsClientPool, a fixed number of `KvsClient` connections shared by threads
+///
+/// A connection that failed with an I/O error is dropped and reconnected the
+/// next time it is handed out.
+pub struct KvsClientPool {
+    addrs: Vec<SocketAddr>,
+    protocol: Protocol,
+    // `None` 表示连接已经断开，下次取出时重新连接
+    clients: Mutex<Vec<Option<KvsClient>>>,
+    available: Condvar,
+}
+
+impl KvsClientPool {
+    /// connect `size` clients to a remote hosts, speaking `Protocol::Json`
+    pub fn connect<A: ToSocketAddrs>(addr: A, size: usize) -> Result<Self> {
+        KvsClientPool::connect_with_protocol(addr, size, Protocol::default())
+    }
+
+    /// connect `size` clients to a remote hosts, speaking the given `protocol`
+    pub fn connect_with_protocol<A: ToSocketAddrs>(
+        addr: A,
+        size: usize,
+        protocol: Protocol,
+    ) -> Result<Self> {
+        if size == 0 {
+            return Err(KvsError::StringError(
+                "client pool size must be nonzero".to_owned(),
+            ));
+        }
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let clients = (0..size)
+            .map(|_| KvsClient::connect_with_protocol(&addrs[..], protocol).map(Some))
+            .collect::<Result<_>>()?;
+        Ok(KvsClientPool {
+            addrs,
+            protocol,
+            clients: Mutex::new(clients),
+            available: Condvar::new(),
+        })
+    }
+
+    /// run `f` with a client of the pool, waiting until one is free
+    ///
+    /// The client goes back to the pool afterwards, unless `f` failed with a local
+    /// I/O error: then the connection is considered dead and replaced by a new
+    /// one. An error the server answered with, `KvsError::Remote` included,
+    /// keeps the connection. If `f` panics, the connection is dropped and
+    /// replaced the same way.
+    pub fn with_client<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvsClient) -> Result<T>,
+    {
+        let slot = {
+            let mut clients = self.clients.lock().expect("client pool mutex poisoned");
+            loop {
+                match clients.pop() {
+                    Some(slot) => break slot,
+                    None => {
+                        clients = self
+                            .available
+                            .wait(clients)
+                            .expect("client pool mutex poisoned")
+                    }
+                }
+            }
+        };
+
+        // slot 由 guard 放回池中，`f` panic 时也不会丢失
+        let mut guard = SlotGuard {
+            pool: self,
+            client: None,
+        };
+        let client = match slot {
+            Some(client) => guard.client.insert(client),
+            None => guard.client.insert(KvsClient::connect_with_protocol(
+                &self.addrs[..],
+                self.protocol,
+            )?),
+        };
+        let res = f(client);
+        if let Err(KvsError::Io(_)) = res {
+            guard.client = None;
+        }
+        res
+    }
+
+    fn put_back(&self, slot: Option<KvsClient>) {
+        self.clients
+            .lock()
+            .expect("client pool mutex poisoned")
+            .push(slot);
+        self.available.notify_one();
+    }
+}
+
+/// Puts a slot back into the pool when dropped, `None` if the client is
+/// missing or the thread is unwinding from a panic of the closure.
+struct SlotGuard<'a> {
+    pool: &'a KvsClientPool,
+    client: Option<KvsClient>,
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        // panic 时连接可能停在一个请求的中间，不能再用
+        let client = if thread::panicking() {
+            None
+        } else {
+            self.client.take()
+        };
+        self.pool.put_back(client);
+    }
+}
@@ -1,4 +1,81 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::{KvsError, Result, ServerStats};
+
+/// Version of the wire protocol, bump it on every incompatible change of the
+/// messages below.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Length of a handshake message: the protocol byte and the big-endian version.
+pub const HANDSHAKE_LEN: usize = 5;
+
+/// Largest payload of a frame, in bytes. A frame announcing a longer payload is
+/// rejected before its payload is read, so a peer cannot make the other side
+/// allocate an arbitrary buffer with a 4-byte length prefix.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+// 代替 protocol byte 的 handshake 回复，表示 server 的连接数已满，后面是连接数上限
+const BUSY_BYTE: u8 = b'!';
+
+/// Serialization format of the requests and responses on a connection.
+///
+/// The client picks one when connecting and sends it in the handshake, the
+/// server echoes the byte back to accept it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// serde_json, readable and the default
+    #[default]
+    Json,
+    /// bincode, smaller and cheaper to (de)serialize
+    Bincode,
+}
+
+impl Protocol {
+    /// Returns the handshake byte of the protocol.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Protocol::Json => b'J',
+            Protocol::Bincode => b'B',
+        }
+    }
+
+    /// Parses a handshake byte.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` for an unknown byte.
+    pub fn from_byte(byte: u8) -> Result<Protocol> {
+        match byte {
+            b'J' => Ok(Protocol::Json),
+            b'B' => Ok(Protocol::Bincode),
+            _ => Err(KvsError::StringError(format!(
+                "unknown protocol byte {:#04x}",
+                byte
+            ))),
+        }
+    }
+
+    /// Serializes `msg` with the protocol.
+    pub fn encode<T: Serialize>(self, msg: &T) -> Result<Vec<u8>> {
+        match self {
+            Protocol::Json => Ok(serde_json::to_vec(msg)?),
+            Protocol::Bincode => Ok(bincode::serialize(msg)?),
+        }
+    }
+
+    /// Deserializes a message serialized by `encode`.
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Protocol::Json => Ok(serde_json::from_slice(payload)?),
+            Protocol::Bincode => Ok(bincode::deserialize(payload)?),
+        }
+    }
+}
 
 /// Request
 #[derive(Debug, Serialize, Deserialize)]
@@ -6,25 +83,394 @@ pub enum Request {
     Set { key: String, value: String },
     Get { key: String },
     Remove { key: String },
+    Contains { key: String },
+    GetMany { keys: Vec<String> },
+    Auth { token: String },
+    GetOrError { key: String },
+    Rename { from: String, to: String },
+    GetSet { key: String, value: String },
+    SetIfAbsent { key: String, value: String },
+    Clear,
+    Ping,
+    Stats,
+    Subscribe,
+}
+
+impl Request {
+    /// the name of the operation, for logging
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Remove { .. } => "remove",
+            Request::Contains { .. } => "contains",
+            Request::GetMany { .. } => "get_many",
+            Request::Auth { .. } => "auth",
+            Request::GetOrError { .. } => "get_or_error",
+            Request::Rename { .. } => "rename",
+            Request::GetSet { .. } => "get_set",
+            Request::SetIfAbsent { .. } => "set_if_absent",
+            Request::Clear => "clear",
+            Request::Ping => "ping",
+            Request::Stats => "stats",
+            Request::Subscribe => "subscribe",
+        }
+    }
+
+    /// the key(s) the request is about, for logging
+    pub fn key(&self) -> String {
+        match self {
+            Request::Set { key, .. }
+            | Request::Get { key }
+            | Request::Remove { key }
+            | Request::Contains { key }
+            | Request::GetOrError { key }
+            | Request::Rename { from: key, .. }
+            | Request::GetSet { key, .. }
+            | Request::SetIfAbsent { key, .. } => key.clone(),
+            Request::GetMany { keys } => keys.join(","),
+            // token 不能出现在日志中
+            Request::Auth { .. }
+            | Request::Clear
+            | Request::Ping
+            | Request::Stats
+            | Request::Subscribe => String::new(),
+        }
+    }
 }
 
 /// SetResponse
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
 }
 
 /// GetResponse
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(RemoteError),
 }
 
 /// RemoveResponse
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
+}
+
+/// ContainsResponse
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ContainsResponse {
+    Ok(bool),
+    Err(RemoteError),
+}
+
+/// GetManyResponse, the values in the order of the requested keys
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetManyResponse {
+    Ok(Vec<Option<String>>),
+    Err(RemoteError),
+}
+
+/// GetOrErrorResponse, with the absence of the key as its own variant
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetOrErrorResponse {
+    Ok(String),
+    KeyNotFound,
+    Err(RemoteError),
+}
+
+/// RenameResponse
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RenameResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+/// GetSetResponse, with the previous value
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetSetResponse {
+    Ok(Option<String>),
+    Err(RemoteError),
+}
+
+/// SetIfAbsentResponse, whether the value was written
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetIfAbsentResponse {
+    Ok(bool),
+    Err(RemoteError),
+}
+
+/// ClearResponse
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClearResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+/// PingResponse, answered without touching the engine
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PingResponse {
+    Pong,
+    Err(RemoteError),
+}
+
+/// StatsResponse, the counters of the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(ServerStats),
+    Err(RemoteError),
+}
+
+/// SubscribeResponse, followed by one frame per log record shipped by the server
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SubscribeResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+/// AuthResponse
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AuthResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+/// The kind of a `KvsError` sent over the wire, so the client can rebuild the
+/// variant the server failed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// `KvsError::KeyNotFound`
+    KeyNotFound,
+    /// `KvsError::Unauthorized`
+    Unauthorized,
+    /// `KvsError::ReadOnly`
+    ReadOnly,
+    /// `KvsError::Io`, rebuilt as `KvsError::Remote` so it is not taken for
+    /// an error of the connection
+    Io,
+    /// `KvsError::Serde` and `KvsError::Bincode`
+    Serde,
+    /// any other error, rebuilt as `KvsError::StringError`
+    Internal,
+}
+
+/// The error of a failed response: its kind and its message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteError {
+    /// the kind of the error
+    pub code: ErrorCode,
+    /// the message of the error on the server
+    pub message: String,
+}
+
+impl From<&KvsError> for RemoteError {
+    fn from(err: &KvsError) -> Self {
+        let code = match err {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Unauthorized => ErrorCode::Unauthorized,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Remote { code, .. } => *code,
+            KvsError::Serde(_) | KvsError::Bincode(_) => ErrorCode::Serde,
+            _ => ErrorCode::Internal,
+        };
+        RemoteError {
+            code,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Turns the error of a response back into a `KvsError`.
+pub fn remote_error(err: RemoteError) -> KvsError {
+    match err.code {
+        ErrorCode::KeyNotFound => KvsError::KeyNotFound,
+        ErrorCode::Unauthorized => KvsError::Unauthorized,
+        ErrorCode::ReadOnly => KvsError::ReadOnly,
+        code @ ErrorCode::Io => KvsError::Remote {
+            code,
+            message: err.message,
+        },
+        ErrorCode::Serde => KvsError::Serde(serde::de::Error::custom(err.message)),
+        ErrorCode::Internal => KvsError::StringError(err.message),
+    }
+}
+
+/// Sets `TCP_NODELAY` on `stream` to `nodelay` and, with `keepalive`, sends TCP
+/// keepalive probes once the connection is idle for that long.
+pub(crate) fn set_tcp_options(
+    stream: &TcpStream,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+) -> io::Result<()> {
+    stream.set_nodelay(nodelay)?;
+    if let Some(time) = keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    Ok(())
+}
+
+/// Resolves `addr`, an IP address or a host name with a port such as
+/// `localhost:4000` or `[::1]:4000`, to its socket addresses.
+///
+/// Connecting or binding to the returned addresses tries each in turn until
+/// one succeeds.
+///
+/// # Errors
+///
+/// It returns `KvsError::AddrResolution` if `addr` is malformed or resolves to
+/// no address.
+pub fn resolve_addr(addr: &str) -> Result<Vec<SocketAddr>> {
+    let error = |reason: String| KvsError::AddrResolution {
+        addr: addr.to_owned(),
+        reason,
+    };
+    let addrs: Vec<_> = addr
+        .to_socket_addrs()
+        .map_err(|e| error(e.to_string()))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(error("no address found".to_owned()));
+    }
+    Ok(addrs)
+}
+
+/// Encodes the handshake message for `protocol` at `PROTOCOL_VERSION`.
+///
+/// Both sides send one right after connecting, the client first.
+pub fn encode_handshake(protocol: Protocol) -> [u8; HANDSHAKE_LEN] {
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    buf[0] = protocol.to_byte();
+    buf[1..].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    buf
+}
+
+/// Encodes the handshake answer of a server rejecting a connection because it
+/// serves `limit` connections already.
+pub fn encode_busy_handshake(limit: u32) -> [u8; HANDSHAKE_LEN] {
+    let mut buf = [0u8; HANDSHAKE_LEN];
+    buf[0] = BUSY_BYTE;
+    buf[1..].copy_from_slice(&limit.to_be_bytes());
+    buf
+}
+
+/// Decodes a handshake message into its protocol and version.
+pub fn decode_handshake(buf: &[u8; HANDSHAKE_LEN]) -> Result<(Protocol, u32)> {
+    let version = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+    Ok((Protocol::from_byte(buf[0])?, version))
+}
+
+/// Checks the handshake answer of the server to a client speaking `protocol`.
+///
+/// # Errors
+///
+/// It returns `KvsError::TooManyConnections` if the server rejected the
+/// connection, and `KvsError::ProtocolVersionMismatch` if it speaks another
+/// version.
+pub fn check_server_handshake(protocol: Protocol, buf: &[u8; HANDSHAKE_LEN]) -> Result<()> {
+    if buf[0] == BUSY_BYTE {
+        let limit = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+        return Err(KvsError::TooManyConnections { limit });
+    }
+    let (server_protocol, server_version) = decode_handshake(buf)?;
+    if server_version != PROTOCOL_VERSION {
+        return Err(KvsError::ProtocolVersionMismatch {
+            client: PROTOCOL_VERSION,
+            server: server_version,
+        });
+    }
+    if server_protocol != protocol {
+        return Err(KvsError::StringError(format!(
+            "server rejected protocol {:?}",
+            protocol
+        )));
+    }
+    Ok(())
+}
+
+/// Answers the handshake `buf` of a client, returning the answer to send back
+/// and the protocol the client picked.
+///
+/// The answer is also sent on a version mismatch, so the client can report it,
+/// and the connection should be closed after sending it.
+pub fn answer_client_handshake(
+    buf: &[u8; HANDSHAKE_LEN],
+) -> ([u8; HANDSHAKE_LEN], Result<Protocol>) {
+    match decode_handshake(buf) {
+        Ok((protocol, version)) => {
+            let answer = encode_handshake(protocol);
+            if version != PROTOCOL_VERSION {
+                let err = KvsError::ProtocolVersionMismatch {
+                    client: version,
+                    server: PROTOCOL_VERSION,
+                };
+                return (answer, Err(err));
+            }
+            (answer, Ok(protocol))
+        }
+        Err(e) => (encode_handshake(Protocol::default()), Err(e)),
+    }
+}
+
+/// Writes `msg` as one frame: a big-endian u32 length prefix followed by `msg`
+/// serialized with `protocol`.
+///
+/// The frame layout matches tokio's `LengthDelimitedCodec` defaults, so the sync
+/// and async servers speak the same protocol.
+pub fn write_frame<W: Write, T: Serialize>(
+    writer: &mut W,
+    protocol: Protocol,
+    msg: &T,
+) -> Result<()> {
+    write_payload(writer, &protocol.encode(msg)?)
+}
+
+/// Writes an already serialized message as one frame.
+pub fn write_payload<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(KvsError::StringError("message too large".to_owned()));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// The codec of the async client and server, framing messages like `write_frame`
+/// with the same `MAX_FRAME_LEN`.
+#[cfg(feature = "async")]
+pub(crate) fn frame_codec() -> tokio_util::codec::LengthDelimitedCodec {
+    tokio_util::codec::LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LEN as usize)
+        .new_codec()
+}
+
+/// Reads one frame written by `write_frame`.
+///
+/// Returns `None` if the peer closed the connection before a new frame, and an
+/// `io::ErrorKind::InvalidData` error if the frame is longer than `MAX_FRAME_LEN`.
+pub fn read_frame<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    protocol: Protocol,
+) -> Result<Option<T>> {
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len_buf);
+    // 在分配 buffer 之前检查，认证之前的连接也会走到这里
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds {} bytes", len, MAX_FRAME_LEN),
+        )
+        .into());
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(protocol.decode(&payload)?))
 }
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::Result;
+
+// 第一层的容量，之后每一层翻倍
+const INITIAL_CAPACITY: u64 = 1024;
+
+/// A scalable bloom filter of the keys recorded in one log generation.
+///
+/// The filter is made of layers: when the last one is full, a new layer twice as
+/// large is added with half its false positive rate, so the overall rate stays
+/// below the configured one however many keys are inserted.
+#[derive(Debug, Clone)]
+pub(super) struct BloomFilter {
+    false_positive_rate: f64,
+    layers: Vec<Layer>,
+}
+
+#[derive(Debug, Clone)]
+struct Layer {
+    hashes: u32,
+    capacity: u64,
+    len: u64,
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter, with a first layer sized for `capacity` keys.
+    pub(super) fn with_capacity(capacity: u64, false_positive_rate: f64) -> Self {
+        BloomFilter {
+            false_positive_rate,
+            // 各层的 rate 是 p/2, p/4, ...，加起来不超过 p
+            layers: vec![Layer::new(capacity.max(1), false_positive_rate / 2.0)],
+        }
+    }
+
+    /// Creates an empty filter that grows as keys are inserted.
+    pub(super) fn new(false_positive_rate: f64) -> Self {
+        BloomFilter::with_capacity(INITIAL_CAPACITY, false_positive_rate)
+    }
+
+    pub(super) fn insert(&mut self, key: &str) {
+        let (h1, h2) = hash_pair(key);
+        if self.layers.iter().any(|layer| layer.contains(h1, h2)) {
+            return;
+        }
+        let last = self.layers.last().expect("a filter has a layer");
+        if last.len >= last.capacity {
+            let rate = self.false_positive_rate / 2f64.powi(self.layers.len() as i32 + 1);
+            let layer = Layer::new(last.capacity * 2, rate);
+            self.layers.push(layer);
+        }
+        self.layers
+            .last_mut()
+            .expect("a filter has a layer")
+            .insert(h1, h2);
+    }
+
+    /// Returns `false` if `key` was certainly never inserted.
+    pub(super) fn may_contain(&self, key: &str) -> bool {
+        let (h1, h2) = hash_pair(key);
+        self.layers.iter().any(|layer| layer.contains(h1, h2))
+    }
+
+    /// Writes the filter to `path`.
+    pub(super) fn save(&self, path: &Path) -> Result<()> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&self.false_positive_rate.to_le_bytes());
+        buf.extend_from_slice(&(self.layers.len() as u32).to_le_bytes());
+        for layer in &self.layers {
+            buf.extend_from_slice(&layer.hashes.to_le_bytes());
+            buf.extend_from_slice(&layer.capacity.to_le_bytes());
+            buf.extend_from_slice(&layer.len.to_le_bytes());
+            buf.extend_from_slice(&(layer.bits.len() as u64).to_le_bytes());
+            for word in &layer.bits {
+                buf.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        fs::write(path, buf)?;
+        Ok(())
+    }
+
+    /// Reads the filter written to `path` by `save`.
+    ///
+    /// Returns `None` if there is no such file or it is not a valid filter.
+    pub(super) fn load(path: &Path) -> Result<Option<BloomFilter>> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(BloomFilter::decode(&buf))
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<BloomFilter> {
+        let false_positive_rate = f64::from_bits(take_u64(&mut buf)?);
+        let layer_count = take_u32(&mut buf)?;
+        let mut layers = Vec::new();
+        for _ in 0..layer_count {
+            let hashes = take_u32(&mut buf)?;
+            let capacity = take_u64(&mut buf)?;
+            let len = take_u64(&mut buf)?;
+            let words = take_u64(&mut buf)?;
+            // 长度不对的文件直接丢弃，重新构建
+            if words == 0 || words > buf.len() as u64 / 8 {
+                return None;
+            }
+            let bits = (0..words)
+                .map(|_| take_u64(&mut buf))
+                .collect::<Option<_>>()?;
+            layers.push(Layer {
+                hashes,
+                capacity,
+                len,
+                bits,
+            });
+        }
+        if layers.is_empty() || !buf.is_empty() {
+            return None;
+        }
+        Some(BloomFilter {
+            false_positive_rate,
+            layers,
+        })
+    }
+}
+
+impl Layer {
+    /// Sizes a layer for `capacity` keys at `false_positive_rate`.
+    fn new(capacity: u64, false_positive_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let words = bits.max(64).div_ceil(64);
+        let hashes = ((words * 64) as f64 / capacity as f64 * ln2)
+            .round()
+            .max(1.0) as u32;
+        Layer {
+            hashes,
+            capacity,
+            len: 0,
+            bits: vec![0; words as usize],
+        }
+    }
+
+    fn insert(&mut self, h1: u64, h2: u64) {
+        for bit in self.bit_positions(h1, h2) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn contains(&self, h1: u64, h2: u64) -> bool {
+        self.bit_positions(h1, h2)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    // double hashing: 第 i 个 hash 是 h1 + i * h2
+    fn bit_positions(&self, h1: u64, h2: u64) -> impl Iterator<Item = u64> {
+        let len = self.bits.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
+
+/// Hashes `key` twice. The hashes are stable across builds, as filters are
+/// stored on disk.
+fn hash_pair(key: &str) -> (u64, u64) {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key.as_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    let h1 = mix(hash);
+    (h1, mix(h1) | 1)
+}
+
+// splitmix64 的 finalizer，打散 FNV 的低位
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn take_u32(buf: &mut &[u8]) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(take(buf, 4)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn take_u64(buf: &mut &[u8]) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(take(buf, 8)?);
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: u64 = 100_000;
+    const RATE: f64 = 0.01;
+
+    fn false_positive_rate(filter: &BloomFilter) -> f64 {
+        let false_positives = (0..KEYS)
+            .filter(|i| filter.may_contain(&format!("missing{}", i)))
+            .count();
+        false_positives as f64 / KEYS as f64
+    }
+
+    #[test]
+    fn false_positive_rate_within_bound() {
+        let mut sized = BloomFilter::with_capacity(KEYS, RATE);
+        let mut grown = BloomFilter::new(RATE);
+        for i in 0..KEYS {
+            sized.insert(&format!("key{}", i));
+            grown.insert(&format!("key{}", i));
+        }
+        assert_eq!(sized.layers.len(), 1);
+        assert!(grown.layers.len() > 1);
+        for filter in &[&sized, &grown] {
+            assert!((0..KEYS).all(|i| filter.may_contain(&format!("key{}", i))));
+            let rate = false_positive_rate(filter);
+            assert!(rate <= RATE, "false positive rate {} over {}", rate, RATE);
+        }
+    }
+
+    #[test]
+    fn save_and_load() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new().expect("unable to create temporary directory");
+        let path = temp_dir.path().join("1.bloom");
+        assert!(BloomFilter::load(&path)?.is_none());
+
+        let mut filter = BloomFilter::new(RATE);
+        (0..5000).for_each(|i| filter.insert(&format!("key{}", i)));
+        filter.save(&path)?;
+        let loaded = BloomFilter::load(&path)?.expect("filter not saved");
+        assert!((0..5000).all(|i| loaded.may_contain(&format!("key{}", i))));
+        assert_eq!(false_positive_rate(&loaded), false_positive_rate(&filter));
+
+        // 截断的文件不是合法的 filter
+        let buf = fs::read(&path)?;
+        fs::write(&path, &buf[..buf.len() - 1])?;
+        assert!(BloomFilter::load(&path)?.is_none());
+        Ok(())
+    }
+}
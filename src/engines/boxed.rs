@@ -0,0 +1,71 @@
+use super::KvsEngine;
+use crate::Result;
+
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+/// An engine picked at runtime, behind a trait object.
+///
+/// Unlike `Box<dyn KvsEngine>`, it is `Clone`: the clones share the same
+/// engine, so a `KvsServer` can serve it, see `KvsServer::new_boxed`.
+#[derive(Clone)]
+pub struct BoxedEngine {
+    engine: Arc<dyn KvsEngine>,
+}
+
+impl BoxedEngine {
+    /// Wraps `engine`, such as the one returned by `open_engine`.
+    pub fn new(engine: Box<dyn KvsEngine>) -> Self {
+        BoxedEngine {
+            engine: Arc::from(engine),
+        }
+    }
+}
+
+impl From<Box<dyn KvsEngine>> for BoxedEngine {
+    fn from(engine: Box<dyn KvsEngine>) -> Self {
+        BoxedEngine::new(engine)
+    }
+}
+
+impl KvsEngine for BoxedEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.engine.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn get_or_error(&self, key: String) -> Result<String> {
+        self.engine.get_or_error(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.engine.rename(from, to)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.engine.get_set(key, value)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.engine.set_if_absent(key, value)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.engine.clear()
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.engine.contains_key(key)
+    }
+
+    fn subscribe(&self) -> Result<Receiver<Vec<u8>>> {
+        self.engine.subscribe()
+    }
+}
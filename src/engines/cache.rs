@@ -0,0 +1,173 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// An LRU cache of the values recently read from the log, keyed by key.
+///
+/// Each value remembers the position of the record it was read from, and a
+/// lookup only hits if the index still points at that position, so a value can
+/// never outlive the record it came from even if an invalidation is missed.
+#[derive(Debug)]
+pub(super) struct ValueCache {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    // map the last use of each key to the key, the first one is evicted first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    gen: u64,
+    start: u64,
+    value: String,
+    last_used: u64,
+}
+
+impl ValueCache {
+    /// Creates an empty cache holding at most `capacity` values.
+    pub(super) fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the value of `key` if it was read from the record at `(gen, start)`,
+    /// marking it as the most recently used.
+    pub(super) fn get(&mut self, key: &str, gen: u64, start: u64) -> Option<String> {
+        let entry = self.entries.get_mut(key)?;
+        if (entry.gen, entry.start) != (gen, start) {
+            return None;
+        }
+        self.tick += 1;
+        self.recency.remove(&entry.last_used);
+        self.recency.insert(self.tick, key.to_owned());
+        entry.last_used = self.tick;
+        Some(entry.value.clone())
+    }
+
+    /// Caches `value`, read from the record at `(gen, start)`, evicting the least
+    /// recently used value if the cache is full.
+    pub(super) fn insert(&mut self, key: String, gen: u64, start: u64, value: String) {
+        self.remove(&key);
+        if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        let entry = Entry {
+            gen,
+            start,
+            value,
+            last_used: self.tick,
+        };
+        self.entries.insert(key, entry);
+    }
+
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+/// The order in which the keys of the store were last read or written, to evict
+/// the least recently used one under `KvStoreOptions::max_keys`.
+///
+/// A key dropped from the index without `forget`, like an expired one, stays
+/// here until it comes up in `pop_oldest`, so the caller has to skip the keys
+/// it no longer holds.
+#[derive(Debug, Default)]
+pub(super) struct KeyRecency {
+    last_used: HashMap<String, u64>,
+    // map the last use of each key to the key, the first one is evicted first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl KeyRecency {
+    /// Marks `key` as the most recently used.
+    pub(super) fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        match self.last_used.get_mut(key) {
+            Some(last_used) => {
+                self.order.remove(last_used);
+                *last_used = self.tick;
+            }
+            None => {
+                self.last_used.insert(key.to_owned(), self.tick);
+            }
+        }
+        self.order.insert(self.tick, key.to_owned());
+    }
+
+    pub(super) fn forget(&mut self, key: &str) {
+        if let Some(last_used) = self.last_used.remove(key) {
+            self.order.remove(&last_used);
+        }
+    }
+
+    /// Removes and returns the least recently used key.
+    pub(super) fn pop_oldest(&mut self) -> Option<String> {
+        let (_, key) = self.order.pop_first()?;
+        self.last_used.remove(&key);
+        Some(key)
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.last_used.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ValueCache::new(2);
+        cache.insert("key1".to_owned(), 1, 0, "value1".to_owned());
+        cache.insert("key2".to_owned(), 1, 10, "value2".to_owned());
+        // key1 被访问过，满了之后淘汰的是 key2
+        assert_eq!(cache.get("key1", 1, 0), Some("value1".to_owned()));
+        cache.insert("key3".to_owned(), 1, 20, "value3".to_owned());
+        assert_eq!(cache.get("key2", 1, 10), None);
+        assert_eq!(cache.get("key1", 1, 0), Some("value1".to_owned()));
+        assert_eq!(cache.get("key3", 1, 20), Some("value3".to_owned()));
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.recency.len(), 2);
+    }
+
+    #[test]
+    fn misses_on_a_moved_record() {
+        let mut cache = ValueCache::new(2);
+        cache.insert("key1".to_owned(), 1, 0, "value1".to_owned());
+        assert_eq!(cache.get("key1", 1, 5), None);
+        assert_eq!(cache.get("key1", 2, 0), None);
+        cache.remove("key1");
+        assert_eq!(cache.get("key1", 1, 0), None);
+    }
+
+    #[test]
+    fn pops_least_recently_used_key() {
+        let mut recency = KeyRecency::default();
+        for key in ["key1", "key2", "key3"] {
+            recency.touch(key);
+        }
+        recency.touch("key1");
+        recency.forget("key3");
+        assert_eq!(recency.pop_oldest(), Some("key2".to_owned()));
+        assert_eq!(recency.pop_oldest(), Some("key1".to_owned()));
+        assert_eq!(recency.pop_oldest(), None);
+        assert!(recency.last_used.is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use std::io::Write;
+
+use super::kvs::Command;
+use crate::{KvsError, Result};
+
+/// Serializes the commands of the log.
+///
+/// Records are framed with their length, so decoding works on the payload of one
+/// record at a time.
+pub trait LogCodec {
+    /// Serializes `cmd` into `writer`.
+    fn encode(&self, cmd: &Command, writer: &mut impl Write) -> Result<()>;
+
+    /// Deserializes a command from the payload of one record.
+    fn decode(&self, payload: &[u8]) -> Result<Command>;
+}
+
+/// Commands as json, readable with any text tool.
+pub struct JsonCodec;
+
+impl LogCodec for JsonCodec {
+    fn encode(&self, cmd: &Command, writer: &mut impl Write) -> Result<()> {
+        Ok(serde_json::to_writer(writer, cmd)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Command> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+/// Commands as bincode, smaller and faster to (de)serialize than json.
+pub struct BincodeCodec;
+
+impl LogCodec for BincodeCodec {
+    fn encode(&self, cmd: &Command, writer: &mut impl Write) -> Result<()> {
+        Ok(bincode::serialize_into(writer, cmd)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Command> {
+        Ok(bincode::deserialize(payload)?)
+    }
+}
+
+/// The serialization of the records in new log files.
+///
+/// The choice is stored in the header byte of each log file, so a store can be
+/// reopened with another encoding and still read its older files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogEncoding {
+    /// `JsonCodec`, the default
+    #[default]
+    Json,
+    /// `BincodeCodec`
+    Bincode,
+    /// `JsonCodec`, with each record on a line of its own instead of a binary
+    /// frame, so the log can be read with `grep` or `jq`
+    ///
+    /// A line is `{"lsn":..,"cmd":..}`, without a checksum: a damaged line is
+    /// only detected if it no longer parses. Records are never compressed.
+    JsonLines,
+}
+
+impl LogEncoding {
+    /// Returns the header byte written at the start of a log file.
+    pub(super) fn header(self) -> u8 {
+        match self {
+            LogEncoding::Json => 1,
+            LogEncoding::Bincode => 2,
+            LogEncoding::JsonLines => 3,
+        }
+    }
+
+    /// Parses the header byte of a log file, `None` if it is not a known header.
+    pub(super) fn from_header(header: u8) -> Option<LogEncoding> {
+        match header {
+            1 => Some(LogEncoding::Json),
+            2 => Some(LogEncoding::Bincode),
+            3 => Some(LogEncoding::JsonLines),
+            _ => None,
+        }
+    }
+
+    pub(super) fn encode(self, cmd: &Command, writer: &mut impl Write) -> Result<()> {
+        match self {
+            LogEncoding::Json | LogEncoding::JsonLines => JsonCodec.encode(cmd, writer),
+            LogEncoding::Bincode => BincodeCodec.encode(cmd, writer),
+        }
+    }
+
+    pub(super) fn decode(self, payload: &[u8]) -> Result<Command> {
+        match self {
+            LogEncoding::Json | LogEncoding::JsonLines => JsonCodec.decode(payload),
+            LogEncoding::Bincode => BincodeCodec.decode(payload),
+        }
+    }
+
+    /// Returns the `(offset, length)` of each command of `cmds` in the payload of
+    /// the `Command::Batch` of them, so each can be decoded on its own.
+    pub(super) fn batch_offsets(self, cmds: &[Command]) -> Result<Vec<(u64, u64)>> {
+        // json: {"Batch":[cmd,cmd]}，bincode: variant 的 u32 + Vec 长度的 u64，之后依次是每个 cmd
+        let (prefix, separator) = match self {
+            LogEncoding::Json | LogEncoding::JsonLines => (br#"{"Batch":["#.len() as u64, 1),
+            LogEncoding::Bincode => (4 + 8, 0),
+        };
+        let mut offset = prefix;
+        let mut offsets = Vec::with_capacity(cmds.len());
+        let mut buf = Vec::new();
+        for cmd in cmds {
+            buf.clear();
+            self.encode(cmd, &mut buf)?;
+            offsets.push((offset, buf.len() as u64));
+            offset += buf.len() as u64 + separator;
+        }
+        Ok(offsets)
+    }
+}
+
+/// Compression type byte of a record compressed with lz4.
+#[cfg(feature = "compression")]
+const LZ4: u8 = 1;
+
+/// Compresses the payload of a record into `[compression type][compressed
+/// payload]`, `None` if that is not smaller than `payload`.
+#[cfg(feature = "compression")]
+pub(super) fn compress(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut compressed = vec![LZ4];
+    compressed.extend_from_slice(&lz4_flex::compress_prepend_size(payload));
+    (compressed.len() < payload.len()).then_some(compressed)
+}
+
+/// Records are never compressed without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+pub(super) fn compress(_payload: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Decompresses a payload made by `compress`, `None` if it is corrupt.
+///
+/// # Errors
+///
+/// It returns `KvsError::UnsupportedCompression` if the compression type is
+/// unknown to this build.
+pub(super) fn decompress(compressed: &[u8]) -> Result<Option<Vec<u8>>> {
+    match compressed.split_first() {
+        #[cfg(feature = "compression")]
+        Some((&LZ4, data)) => Ok(lz4_flex::decompress_size_prepended(data).ok()),
+        Some((&kind, _)) => Err(KvsError::UnsupportedCompression(kind)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_offsets_locate_each_command() -> Result<()> {
+        let cmds = vec![
+            Command::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            Command::Remove {
+                key: "key\"2".to_owned(),
+            },
+            Command::SetBytes {
+                key: "键3".to_owned(),
+                value: vec![0, 1, 2],
+            },
+        ];
+        for encoding in [
+            LogEncoding::Json,
+            LogEncoding::Bincode,
+            LogEncoding::JsonLines,
+        ] {
+            let offsets = encoding.batch_offsets(&cmds)?;
+            let mut payload = Vec::new();
+            encoding.encode(&Command::Batch(cmds.clone()), &mut payload)?;
+            for (cmd, (offset, len)) in cmds.iter().zip(offsets) {
+                let mut expected = Vec::new();
+                encoding.encode(cmd, &mut expected)?;
+                let range = offset as usize..(offset + len) as usize;
+                assert_eq!(&payload[range.clone()], &expected[..], "{:?}", encoding);
+                assert_eq!(encoding.decode(&payload[range])?.key(), cmd.key());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_unknown_type() {
+        assert!(matches!(
+            decompress(&[9, 0, 0]),
+            Err(KvsError::UnsupportedCompression(9))
+        ));
+        assert!(matches!(decompress(&[]), Ok(None)));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compress_round_trip() -> Result<()> {
+        let payload = b"value ".repeat(100);
+        let compressed = compress(&payload).expect("repeated text should shrink");
+        assert_eq!(decompress(&compressed)?, Some(payload));
+        assert_eq!(compress(b"v"), None);
+        Ok(())
+    }
+}
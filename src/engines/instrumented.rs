@@ -0,0 +1,181 @@
+use super::KvsEngine;
+use crate::Result;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// 第 i 个 bucket 记录 [2^(i-1), 2^i) 微秒的延迟，最后一个 bucket 记录更大的延迟
+const BUCKETS: usize = 32;
+
+/// A wrapper engine recording the latency of every operation of `E`.
+///
+/// Clones share the same histograms, so the latencies of all the connections of
+/// a server add up.
+#[derive(Clone)]
+pub struct InstrumentedEngine<E: KvsEngine> {
+    engine: E,
+    latencies: Arc<Latencies>,
+}
+
+#[derive(Default)]
+struct Latencies {
+    set: Histogram,
+    get: Histogram,
+    remove: Histogram,
+    contains_key: Histogram,
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// The latencies of one kind of operation, grouped in power of two buckets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// the number of operations per bucket: bucket 0 counts the operations under
+    /// one microsecond, bucket `i` those under `2^i` microseconds
+    pub buckets: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    /// Returns the number of recorded operations.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Returns the upper bound of the bucket holding the given percentile, `None`
+    /// if nothing was recorded.
+    ///
+    /// `percentile` is between 0 and 100.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * count as f64).ceil() as u64).clamp(1, count);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << i));
+            }
+        }
+        None
+    }
+}
+
+/// The latencies recorded by an `InstrumentedEngine`, per operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// `KvsEngine::set`
+    pub set: LatencyHistogram,
+    /// `KvsEngine::get`
+    pub get: LatencyHistogram,
+    /// `KvsEngine::remove`
+    pub remove: LatencyHistogram,
+    /// `KvsEngine::contains_key`
+    pub contains_key: LatencyHistogram,
+}
+
+impl<E: KvsEngine> InstrumentedEngine<E> {
+    /// Wraps `engine`, with empty histograms.
+    pub fn new(engine: E) -> Self {
+        InstrumentedEngine {
+            engine,
+            latencies: Arc::default(),
+        }
+    }
+
+    /// Returns the wrapped engine.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    /// Returns the latencies recorded so far.
+    ///
+    /// Failed operations are recorded too.
+    pub fn snapshot_latencies(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            set: self.latencies.set.snapshot(),
+            get: self.latencies.get.snapshot(),
+            remove: self.latencies.remove.snapshot(),
+            contains_key: self.latencies.contains_key.snapshot(),
+        }
+    }
+}
+
+// 执行 `f`，并把耗时记录到 `histogram`
+fn timed<T>(histogram: &Histogram, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = f();
+    histogram.record(start.elapsed());
+    res
+}
+
+impl<E: KvsEngine> KvsEngine for InstrumentedEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        timed(&self.latencies.set, || self.engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        timed(&self.latencies.get, || self.engine.get(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        timed(&self.latencies.remove, || self.engine.remove(key))
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.engine.rename(from, to)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.engine.get_set(key, value)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.engine.set_if_absent(key, value)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.engine.clear()
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        timed(&self.latencies.contains_key, || {
+            self.engine.contains_key(key)
+        })
+    }
+
+    fn subscribe(&self) -> Result<Receiver<Vec<u8>>> {
+        self.engine.subscribe()
+    }
+}
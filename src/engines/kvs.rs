@@ -1,22 +1,95 @@
+use fs2::FileExt;
+use log::{error, warn};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use super::KvsEngine;
-use crate::{KvsError, Result};
+use super::bloom::BloomFilter;
+use super::cache::{KeyRecency, ValueCache};
+use super::codec::{self, LogEncoding};
+use super::sled::is_sled_dir;
+use super::{validate_key, KvsEngine};
+use crate::{KvsClient, KvsError, Result};
 
 // 1MB
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// 每一步 background compaction 最多 copy 64KB，之间释放锁
+const COMPACTION_STEP_BYTES: u64 = 64 * 1024;
+// 1%
+const DEFAULT_BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+// 8KB，和 std 的 BufReader/BufWriter 一样
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+// Every log file starts with a header byte naming its `LogEncoding`. Each command
+// is framed as `[length: u32][crc32: u32][lsn: u64][payload]`, all integers
+// little-endian, the lsn only if the record was written with one. The length and
+// the crc32 cover the lsn and the payload.
+// Log files written before the header byte existed have no frames, the
+// json-serialized commands are simply concatenated.
+
+// length + crc32
+const FRAME_HEADER_LEN: u64 = 8;
+// length 的最高位表示 payload 是压缩过的，payload 的第一个 byte 是压缩类型
+const COMPRESSED_FLAG: u32 = 1 << 31;
+// length 的次高位表示 payload 之前有 8 byte 的 LSN
+const LSN_FLAG: u32 = 1 << 30;
+const LSN_LEN: u64 = 8;
+// advisory lock file held by the writing instance
+const LOCK_FILE: &str = "LOCK";
+// namespace 和 key 之间的分隔符，namespace 中不能出现
+const NAMESPACE_SEPARATOR: char = '\u{0}';
 
 /// value representing set/rm command
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    // 新增的 variant 放在最后，已有的 log 仍然可以正常解析
+    SetBytes {
+        key: String,
+        value: Vec<u8>,
+    },
+    SetEx {
+        key: String,
+        value: String,
+        expire_at_unix_ms: u64,
+    },
+    // 清空之前所有的 key
+    Clear,
+    // 多个 command 写成一条 record，load 时展开，每个 command 在 index 中有自己的位置
+    Batch(Vec<Command>),
+    // value 在 blob 文件 `<blob>.blob` 中，record 只保存它的位置
+    BlobRef {
+        key: String,
+        blob: u64,
+        offset: u64,
+        length: u64,
+    },
+    // 同一个 generation 中 key 和 token 都相同的 record 只有第一条生效，
+    // compaction log 中每个 key 只有一条 record，不会误删
+    SetIdempotent {
+        key: String,
+        value: String,
+        token: String,
+    },
 }
 
 impl Command {
@@ -24,266 +97,4176 @@ impl Command {
         Command::Set { key, value }
     }
 
+    fn set_bytes(key: String, value: Vec<u8>) -> Self {
+        Command::SetBytes { key, value }
+    }
+
+    fn set_ex(key: String, value: String, expire_at_unix_ms: u64) -> Self {
+        Command::SetEx {
+            key,
+            value,
+            expire_at_unix_ms,
+        }
+    }
+
     fn remove(key: String) -> Self {
         Command::Remove { key }
     }
+
+    /// The name of the variant, for `KvStore::dump_log`.
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "Set",
+            Command::Remove { .. } => "Remove",
+            Command::SetBytes { .. } => "SetBytes",
+            Command::SetEx { .. } => "SetEx",
+            Command::Clear => "Clear",
+            Command::Batch(_) => "Batch",
+            Command::BlobRef { .. } => "BlobRef",
+            Command::SetIdempotent { .. } => "SetIdempotent",
+        }
+    }
+
+    /// The unix timestamp in milliseconds after which the value is dead, if any.
+    fn expire_at(&self) -> Option<u64> {
+        match self {
+            Command::SetEx {
+                expire_at_unix_ms, ..
+            } => Some(*expire_at_unix_ms),
+            _ => None,
+        }
+    }
+
+    /// The key of the command, empty for `Clear` and `Batch`.
+    pub(super) fn key(&self) -> &str {
+        match self {
+            Command::Set { key, .. }
+            | Command::Remove { key }
+            | Command::SetBytes { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::SetIdempotent { key, .. }
+            | Command::BlobRef { key, .. } => key,
+            Command::Clear | Command::Batch(_) => "",
+        }
+    }
+
+    /// The size of the value in bytes, `None` for a removal.
+    fn value_len(&self) -> Option<usize> {
+        match self {
+            Command::Set { value, .. }
+            | Command::SetEx { value, .. }
+            | Command::SetIdempotent { value, .. } => Some(value.len()),
+            Command::SetBytes { value, .. } => Some(value.len()),
+            Command::BlobRef { length, .. } => Some(*length as usize),
+            Command::Remove { .. } | Command::Clear | Command::Batch(_) => None,
+        }
+    }
+
+    /// The same command for another key.
+    fn with_key(self, key: String) -> Self {
+        match self {
+            Command::Set { value, .. } => Command::Set { key, value },
+            Command::Remove { .. } => Command::Remove { key },
+            Command::Clear => Command::Clear,
+            Command::Batch(cmds) => Command::Batch(cmds),
+            Command::SetBytes { value, .. } => Command::SetBytes { key, value },
+            Command::BlobRef {
+                blob,
+                offset,
+                length,
+                ..
+            } => Command::BlobRef {
+                key,
+                blob,
+                offset,
+                length,
+            },
+            Command::SetEx {
+                value,
+                expire_at_unix_ms,
+                ..
+            } => Command::SetEx {
+                key,
+                value,
+                expire_at_unix_ms,
+            },
+            Command::SetIdempotent { value, token, .. } => {
+                Command::SetIdempotent { key, value, token }
+            }
+        }
+    }
+
+    /// The value of a `Set` or `SetBytes`, the commands whose value can be
+    /// stored in a blob file.
+    fn blob_value(&self) -> Option<&[u8]> {
+        match self {
+            Command::Set { value, .. } => Some(value.as_bytes()),
+            Command::SetBytes { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    fn into_key(self) -> String {
+        match self {
+            Command::Set { key, .. }
+            | Command::Remove { key }
+            | Command::SetBytes { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::SetIdempotent { key, .. }
+            | Command::BlobRef { key, .. } => key,
+            Command::Clear | Command::Batch(_) => String::new(),
+        }
+    }
 }
 
-/// The `KvStore` used HashMap, storing in memroy, not on a disk
+/// A single operation of a [`KvStore::write_batch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set the value of a key.
+    Set {
+        /// the key
+        key: String,
+        /// the value
+        value: String,
+    },
+    /// Remove a key.
+    Remove {
+        /// the key
+        key: String,
+    },
+}
+
+/// When `KvStore` forces written log records from the OS page cache to disk.
 ///
-/// Example:
+/// Unless `KvStoreOptions::buffered_writes` is set, every write is flushed from
+/// the in-process buffer to the OS, which survives a crash of the process.
+/// Surviving a power loss or kernel crash needs the file to be synced as well,
+/// which costs one `fsync` per sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Never call `fsync`, leave it to the OS. Fastest, but acknowledged writes
+    /// may be lost on power loss.
+    #[default]
+    Never,
+    /// Call `fsync` after every write. Slowest, no acknowledged write is lost.
+    EveryWrite,
+    /// Call `fsync` on a write if the last sync is older than the interval, so at
+    /// most about one interval of writes is lost on power loss.
+    Interval(Duration),
+}
+
+/// What a `KvStore` holding `KvStoreOptions::max_keys` keys does on a write of
+/// a new key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Fail the write with `KvsError::Capacity`, nothing is written.
+    #[default]
+    RejectNew,
+    /// Remove the least recently read or written keys first, writing their
+    /// removes to the log, like a bounded cache.
+    EvictLru,
+}
+
+/// Which generations a compaction triggered by a write merges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactionStrategy {
+    /// Merge every generation into one, once the stale records exceed the
+    /// compaction threshold.
+    #[default]
+    Full,
+    /// Merge only the newest generations, each smaller than half `threshold`
+    /// bytes, once they add up to `threshold` bytes.
+    ///
+    /// A generation that reached half `threshold`, such as a merge that kept
+    /// that much live data, is left alone, so a large store is not rewritten
+    /// for every few writes. Its stale records are only reclaimed by a full
+    /// compaction, which still runs past the compaction threshold or
+    /// `max_generations`, and on `KvStore::compact`.
+    SizeTiered {
+        /// the size in bytes the merged generations add up to
+        threshold: u64,
+    },
+}
+
+/// Options and flags which can be used to configure how a `KvStore` is opened.
+///
+/// Works like [`std::fs::OpenOptions`]:
 ///
 /// ```rust
-/// # use kvs::{KvStore, Result};
+/// # use kvs::{KvStoreOptions, Result, SyncPolicy};
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
-/// use kvs::KvsEngine;
-///
-/// let mut store = KvStore::open(current_dir()?)?;
-///
-/// store.set("key1".to_owned(), "value1".to_owned());
-/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-/// assert_eq!(store.get("key2".to_owned())?, None);
 ///
-/// store.remove("key1".to_owned());
-/// assert_eq!(store.get("key1".to_owned())?, None);
+/// let store = KvStoreOptions::new()
+///     .sync_policy(SyncPolicy::EveryWrite)
+///     .open(current_dir()?)?;
 /// # Ok(())
 /// # }
 /// ```
-pub struct KvStore {
-    // directory for the log and other data.
-    path: PathBuf,
-    current_gen: u64,
-    // map generation number to the file reader.
-    readers: HashMap<u64, BufferReaderWithPos<File>>,
-    // writer of the current log.
-    writer: BufferWriterWithPos<File>,
-    // an in-memory [key -> log pointer] map.
-    index: HashMap<String, CommandPos>,
-    // stale log size
-    uncompacted: u64,
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    sync_policy: SyncPolicy,
+    compaction_threshold: u64,
+    log_encoding: LogEncoding,
+    buffered_writes: bool,
+    #[cfg(feature = "mmap")]
+    mmap_reads: bool,
+    max_value_bytes: Option<u64>,
+    max_key_bytes: Option<u64>,
+    bloom_false_positive_rate: f64,
+    background_compaction: bool,
+    max_generations: Option<u64>,
+    value_cache: Option<usize>,
+    reader_pool: Option<usize>,
+    max_open_files: Option<usize>,
+    compress_records: bool,
+    blob_threshold: Option<u64>,
+    buffer_size: usize,
+    overwrite_in_place: bool,
+    max_keys: Option<usize>,
+    eviction_policy: EvictionPolicy,
+    expiry_sweep_interval: Option<Duration>,
+    compaction_strategy: CompactionStrategy,
 }
 
-impl KvStore {
-    /// Open the `KvStore` at a given path. Return the KvStore.
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            log_encoding: LogEncoding::default(),
+            buffered_writes: false,
+            #[cfg(feature = "mmap")]
+            mmap_reads: false,
+            max_value_bytes: None,
+            max_key_bytes: None,
+            bloom_false_positive_rate: DEFAULT_BLOOM_FALSE_POSITIVE_RATE,
+            background_compaction: false,
+            max_generations: None,
+            value_cache: None,
+            reader_pool: None,
+            max_open_files: None,
+            compress_records: false,
+            blob_threshold: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            overwrite_in_place: false,
+            max_keys: None,
+            eviction_policy: EvictionPolicy::default(),
+            expiry_sweep_interval: None,
+            compaction_strategy: CompactionStrategy::default(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates a blank new set of options, every option set to its default.
+    pub fn new() -> Self {
+        KvStoreOptions::default()
+    }
+
+    /// Sets how many bytes of stale log records trigger a compaction, 1MB by default.
     ///
-    /// This will create a new directory if the given dir does not exist.
+    /// Opening fails with `KvsError::InvalidOption` if the threshold is zero.
+    pub fn compaction_threshold(&mut self, compaction_threshold: u64) -> &mut Self {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Sets when written records are synced to disk, `SyncPolicy::Never` by default.
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the serialization of records in new log files, `LogEncoding::Json` by default.
     ///
-    /// # Errors
+    /// Existing log files are still read with the encoding they were written in,
+    /// so a store can switch to or from `LogEncoding::JsonLines` too.
+    pub fn log_encoding(&mut self, log_encoding: LogEncoding) -> &mut Self {
+        self.log_encoding = log_encoding;
+        self
+    }
+
+    /// Sets whether writes stay in the in-process buffer, `false` by default.
     ///
-    /// It propagates I/O or deserialilzation errors during the log re-play.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        fs::create_dir_all(&path)?;
+    /// Buffered writes are flushed to the OS when the buffer is full, on
+    /// `KvStore::flush`, before reading from the current log, and when a sync is
+    /// due under the sync policy. This saves a syscall per write, but writes
+    /// still in the buffer are lost if the process crashes.
+    pub fn buffered_writes(&mut self, buffered_writes: bool) -> &mut Self {
+        self.buffered_writes = buffered_writes;
+        self
+    }
 
-        let mut readers = HashMap::new();
-        let mut index = HashMap::new();
+    /// Sets the size limit of a value, unlimited by default.
+    ///
+    /// Writing a larger value fails with `KvsError::ValueTooLarge`, and nothing is
+    /// written to the log.
+    pub fn max_value_bytes(&mut self, max_value_bytes: u64) -> &mut Self {
+        self.max_value_bytes = Some(max_value_bytes);
+        self
+    }
 
-        let gen_list = sorted_gen_list(&path)?;
-        let mut uncompacted = 0;
-        for &gen in &gen_list {
-            let mut reader = BufferReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
-        }
+    /// Sets the length limit of a key in bytes, unlimited by default.
+    ///
+    /// Any operation on a longer key fails with `KvsError::InvalidKey`, as on an
+    /// empty key.
+    pub fn max_key_bytes(&mut self, max_key_bytes: u64) -> &mut Self {
+        self.max_key_bytes = Some(max_key_bytes);
+        self
+    }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+    /// Sets the false positive rate of the bloom filter of each generation, 1% by
+    /// default.
+    ///
+    /// Opening fails with `KvsError::InvalidOption` if the rate is not between 0
+    /// and 1, exclusive.
+    pub fn bloom_false_positive_rate(&mut self, bloom_false_positive_rate: f64) -> &mut Self {
+        self.bloom_false_positive_rate = bloom_false_positive_rate;
+        self
+    }
 
-        let writer = new_log_file(&path, current_gen, &mut readers)?;
+    /// Sets whether compactions run on a background thread, `false` by default.
+    ///
+    /// Instead of compacting inline, the write that crosses the compaction
+    /// threshold wakes the thread, which compacts in steps of `compact_step` so
+    /// reads and writes go on in between. The thread stops when the last handle
+    /// to the store is dropped.
+    pub fn background_compaction(&mut self, background_compaction: bool) -> &mut Self {
+        self.background_compaction = background_compaction;
+        self
+    }
 
-        Ok(KvStore {
-            path,
-            current_gen,
-            readers,
-            writer,
-            index,
-            uncompacted,
-        })
+    /// Sets how many generations, the log files of the store, trigger a
+    /// compaction once exceeded, unlimited by default.
+    ///
+    /// Every open starts a new generation, so a store reopened often collects
+    /// small logs that never add up to the compaction threshold. The limit is
+    /// checked on open and after each write. Opening fails with
+    /// `KvsError::InvalidOption` if it is less than 2, the generations left by a
+    /// compaction.
+    pub fn max_generations(&mut self, max_generations: u64) -> &mut Self {
+        self.max_generations = Some(max_generations);
+        self
     }
 
-    fn compact(&mut self) -> Result<()> {
-        // compaction generateion
-        let compaction_gen = self.current_gen + 1;
+    /// Caches up to `capacity` recently read values in memory, no cache by default.
+    ///
+    /// A `get` of a cached key returns the value without reading the log, and
+    /// the least recently used value is evicted when the cache is full. Writing
+    /// a key drops it from the cache. Opening fails with
+    /// `KvsError::InvalidOption` if the capacity is zero.
+    pub fn value_cache(&mut self, capacity: usize) -> &mut Self {
+        self.value_cache = Some(capacity);
+        self
+    }
 
-        // current generation number +2, +1 for compaction
-        self.current_gen += 2;
-        self.writer = self.new_log_file(self.current_gen)?;
+    /// Lets `get` read the log outside the store lock, keeping up to `capacity`
+    /// idle file handles per log, disabled by default.
+    ///
+    /// Without a pool, every read seeks the single reader of its log under the
+    /// store lock, so concurrent `get`s run one at a time. With it, the lock is
+    /// only held to look the key up, and each read seeks a file handle of its
+    /// own. More concurrent reads than `capacity` open extra handles, closed
+    /// after use. Memory-mapped reads do not use the pool. Opening fails with
+    /// `KvsError::InvalidOption` if the capacity is zero.
+    pub fn reader_pool(&mut self, capacity: usize) -> &mut Self {
+        self.reader_pool = Some(capacity);
+        self
+    }
 
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+    /// Keeps at most `max_open_files` log files open for reading, unlimited by
+    /// default.
+    ///
+    /// A store with many generations otherwise holds a file descriptor per
+    /// generation. With a limit, the log used the longest ago is closed when
+    /// another one is opened, and reopened when it is read again. The handles of
+    /// `KvStoreOptions::reader_pool` and of the current log writer are not
+    /// counted. Opening fails with `KvsError::InvalidOption` if the limit is zero.
+    pub fn max_open_files(&mut self, max_open_files: usize) -> &mut Self {
+        self.max_open_files = Some(max_open_files);
+        self
+    }
 
-        // compaction log 从 pos = 0 开始写入
-        let mut next_pos = 0;
-        // 遍历目前 in-memory index 中保存的 key 对应的 CommandPos
-        for active_cmd in &mut self.index.values_mut() {
-            // 根据 gen 拿到对应的 reader
-            let reader = self
-                .readers
-                .get_mut(&active_cmd.gen)
-                .expect("Cannot find the reader");
-            // 读取 log 中对应的 Command
-            // 判断当前 reader 的游标位置，读取对应的 Command 是否需要移动游标
-            if active_cmd.start != reader.pos {
-                // 需要移动移动游标
-                reader.seek(SeekFrom::Start(active_cmd.start))?;
-            }
-            let mut entry_reader = reader.take(active_cmd.length);
-            // 将对应 reader 中的内容，copy 到 compaction_reader 中来
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
+    /// Sets whether the log files are memory-mapped for reading, `false` by default.
+    ///
+    /// A read then decodes the record straight from the mapped file instead of
+    /// seeking and copying it through a buffer. The mapping of a log is renewed
+    /// when a record past its end is read.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_reads(&mut self, mmap_reads: bool) -> &mut Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
 
-            // 更新 in-memory index 中 CommandPos 对应的信息
-            *active_cmd = CommandPos::new(compaction_gen, next_pos, next_pos + len);
+    /// Sets whether new log records are compressed with lz4, `false` by default.
+    ///
+    /// A record is only stored compressed if that makes it smaller, so logs mix
+    /// compressed and plain records, and a store written with or without
+    /// compression opens either way. A batch of several commands is never
+    /// compressed, as its commands are read one by one.
+    #[cfg(feature = "compression")]
+    pub fn compress_records(&mut self, compress_records: bool) -> &mut Self {
+        self.compress_records = compress_records;
+        self
+    }
 
-            next_pos += len;
-        }
+    /// Sets the size in bytes above which a value is stored out of the log, in a
+    /// blob file, not set by default.
+    ///
+    /// The log record of such a value only holds its location in the blob file,
+    /// so compactions copy the large value once per compaction instead of along
+    /// with every record around it. Values written by `set` and `set_bytes`
+    /// are stored this way; values with a TTL and values of a batch stay in the
+    /// log. A compaction moves the live values to the blob file of its own
+    /// generation, and a blob file is deleted together with the log of its
+    /// generation. Unless the sync policy is `SyncPolicy::Never`, the value is
+    /// synced to the blob file before the record pointing at it is written.
+    /// `KvStore::get_reader` streams such a value from its blob file.
+    pub fn blob_threshold(&mut self, blob_threshold: u64) -> &mut Self {
+        self.blob_threshold = Some(blob_threshold);
+        self
+    }
 
-        // 释放 stale 的空间
-        let stale_gen_list: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gen_list {
-            // 将 log 文件对应的 reader 释放掉
-            self.readers.remove(&stale_gen);
+    /// Sets the capacity in bytes of the buffer of each log reader and writer,
+    /// 8KB by default.
+    ///
+    /// A larger buffer makes fewer syscalls for large sequential writes and
+    /// reads, as in a bulk load or a compaction of a large store, which writes
+    /// its log and blob file through buffers of the same size. Buffered writes
+    /// also stay in the process until the buffer of the writer is full. Opening
+    /// fails with `KvsError::InvalidOption` if the size is zero.
+    pub fn buffer_size(&mut self, buffer_size: usize) -> &mut Self {
+        self.buffer_size = buffer_size;
+        self
+    }
 
-            // 将 log file 也给释放掉
-            fs::remove_file(log_path(&self.path, stale_gen))?;
-        }
+    /// Sets whether a `set` overwrites the record of the old value in place when
+    /// the new record has the same length, `false` by default.
+    ///
+    /// Only a plain `set`/`set_bytes` record in the current log is overwritten,
+    /// never one with an expiry, an idempotency token, a blob value or inside a
+    /// batch, so a counter-like key does not make the log grow nor adds up stale
+    /// bytes.
+    ///
+    /// This gives up the crash safety of an append-only log. Opening recovers
+    /// from a torn record only at the end of the log; a crash in the middle of
+    /// an overwrite leaves a torn record before the end, and the next open fails
+    /// with `KvsError::CorruptLog` until the log is repaired. The overwritten
+    /// record also carries a newer LSN than the records after it, and its old
+    /// value is gone from `get_as_of` right away instead of at the next
+    /// compaction. Overwrites are synced like appends, see `sync_policy`.
+    ///
+    /// Opening fails with `KvsError::InvalidOption` if the reader pool or
+    /// memory-mapped reads, which read records while they may be overwritten,
+    /// are enabled too.
+    pub fn overwrite_in_place(&mut self, overwrite_in_place: bool) -> &mut Self {
+        self.overwrite_in_place = overwrite_in_place;
+        self
+    }
+
+    /// Sets the maximum number of keys of the store, unlimited by default.
+    ///
+    /// A write that would add a key over the limit is handled by the eviction
+    /// policy. Evicted keys are removed by remove records written after the
+    /// record of the new key, so a failed write evicts nothing, and their stale
+    /// records are reclaimed by the next compaction like those of any remove.
+    /// Keys of an existing store over the limit are not evicted on open, only
+    /// new keys are held back. Opening fails with `KvsError::InvalidOption` if
+    /// the limit is zero.
+    pub fn max_keys(&mut self, max_keys: usize) -> &mut Self {
+        self.max_keys = Some(max_keys);
+        self
+    }
+
+    /// Sets what a write of a new key does once the store holds `max_keys` keys,
+    /// `EvictionPolicy::RejectNew` by default.
+    ///
+    /// `EvictionPolicy::EvictLru` tracks the order keys were read or written
+    /// in memory. Reads are not logged, so after a reopen keys are evicted in
+    /// the order of their last write. Without `max_keys` the policy has no
+    /// effect.
+    pub fn eviction_policy(&mut self, eviction_policy: EvictionPolicy) -> &mut Self {
+        self.eviction_policy = eviction_policy;
+        self
+    }
+
+    /// Runs `KvStore::sweep_expired` on a background thread every `interval`,
+    /// disabled by default.
+    ///
+    /// Without it, an expired key only leaves the index once it is read or
+    /// compacted away, so keys set with a TTL and never read again pile up. A
+    /// sweep writes a remove record for every expired key in one batch, synced
+    /// under the sync policy like any write, and the stale records count
+    /// towards the compaction threshold. The thread stops when the last handle
+    /// to the store is dropped, and is not started for a store opened
+    /// read-only. Opening fails with `KvsError::InvalidOption` if the interval
+    /// is zero.
+    pub fn expiry_sweep_interval(&mut self, interval: Duration) -> &mut Self {
+        self.expiry_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Sets which generations the compactions triggered by writes merge,
+    /// `CompactionStrategy::Full` by default.
+    ///
+    /// The strategy applies to inline and background compactions alike.
+    /// `KvStore::compact` and `KvStore::compact_step` always merge every
+    /// generation, and a full compaction still runs once the stale records
+    /// exceed `compaction_threshold` or the generations exceed
+    /// `max_generations`. Opening fails with `KvsError::InvalidOption` if the
+    /// threshold of `CompactionStrategy::SizeTiered` is zero.
+    pub fn compaction_strategy(&mut self, compaction_strategy: CompactionStrategy) -> &mut Self {
+        self.compaction_strategy = compaction_strategy;
+        self
+    }
 
-        // 重置
-        self.uncompacted = 0;
+    /// Opens the `KvStore` at `path` with the options specified by `self`.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self)
+    }
 
+    fn validate(&self) -> Result<()> {
+        if self.compaction_threshold == 0 {
+            return Err(KvsError::InvalidOption(
+                "compaction threshold must be nonzero".to_owned(),
+            ));
+        }
+        if !(self.bloom_false_positive_rate > 0.0 && self.bloom_false_positive_rate < 1.0) {
+            return Err(KvsError::InvalidOption(format!(
+                "bloom false positive rate {} is not between 0 and 1",
+                self.bloom_false_positive_rate
+            )));
+        }
+        if matches!(self.max_generations, Some(max) if max < 2) {
+            return Err(KvsError::InvalidOption(
+                "max generations must be at least 2".to_owned(),
+            ));
+        }
+        if self.value_cache == Some(0) {
+            return Err(KvsError::InvalidOption(
+                "value cache capacity must be nonzero".to_owned(),
+            ));
+        }
+        if self.reader_pool == Some(0) {
+            return Err(KvsError::InvalidOption(
+                "reader pool capacity must be nonzero".to_owned(),
+            ));
+        }
+        if self.max_open_files == Some(0) {
+            return Err(KvsError::InvalidOption(
+                "max open files must be nonzero".to_owned(),
+            ));
+        }
+        if self.buffer_size == 0 {
+            return Err(KvsError::InvalidOption(
+                "buffer size must be nonzero".to_owned(),
+            ));
+        }
+        if self.expiry_sweep_interval == Some(Duration::ZERO) {
+            return Err(KvsError::InvalidOption(
+                "expiry sweep interval must be nonzero".to_owned(),
+            ));
+        }
+        if self.compaction_strategy == (CompactionStrategy::SizeTiered { threshold: 0 }) {
+            return Err(KvsError::InvalidOption(
+                "size-tiered compaction threshold must be nonzero".to_owned(),
+            ));
+        }
+        if self.max_keys == Some(0) {
+            return Err(KvsError::InvalidOption(
+                "max keys must be nonzero".to_owned(),
+            ));
+        }
+        if self.overwrite_in_place && self.reader_pool.is_some() {
+            return Err(KvsError::InvalidOption(
+                "overwrite in place does not work with the reader pool".to_owned(),
+            ));
+        }
+        #[cfg(feature = "mmap")]
+        if self.overwrite_in_place && self.mmap_reads {
+            return Err(KvsError::InvalidOption(
+                "overwrite in place does not work with memory-mapped reads".to_owned(),
+            ));
+        }
         Ok(())
     }
+}
 
-    fn new_log_file(&mut self, gen: u64) -> Result<BufferWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+/// Statistics about a compaction run, returned by `KvStore::compact`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Total size of the log files before the compaction.
+    pub bytes_before: u64,
+    /// Total size of the log files after the compaction.
+    pub bytes_after: u64,
+    /// Number of live entries copied into the compacted log.
+    pub entries_retained: u64,
+    /// Number of stale log files removed.
+    pub files_removed: u64,
+}
+
+/// One line of a snapshot written by `KvStore::export`.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    key: String,
+    value: String,
+}
+
+/// A snapshot of the size of a `KvStore`, returned by `KvStore::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Number of keys in the index.
+    pub live_keys: u64,
+    /// Total size of the log files.
+    pub total_log_bytes: u64,
+    /// Size of the stale records a compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Number of log files.
+    pub generation_count: u64,
+}
+
+/// What a compaction would reclaim, returned by `KvStore::compaction_estimate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// Size of the stale and expired records a compaction would drop.
+    pub reclaimable_bytes: u64,
+    /// Size of the records a compaction would keep.
+    pub live_bytes: u64,
+    /// Number of keys a compaction would keep.
+    pub live_entries: u64,
+}
+
+/// Where the value of a key lives in the log, returned by `KvStore::get_with_meta`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// The log generation of the record.
+    pub gen: u64,
+    /// The offset of the record in the log.
+    pub start: u64,
+    /// The length of the record, frame header included.
+    pub length: u64,
+}
+
+/// The result of `KvStore::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of index entries checked.
+    pub checked: u64,
+    /// The entries that do not point to a valid record of their key.
+    pub problems: Vec<VerifyProblem>,
+}
+
+/// An index entry that does not point to a valid record of its key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyProblem {
+    /// The key of the entry.
+    pub key: String,
+    /// The log generation the entry points to.
+    pub gen: u64,
+    /// The offset of the record in the log.
+    pub offset: u64,
+    /// What is wrong with the record.
+    pub kind: VerifyProblemKind,
+}
+
+/// What is wrong with the record an index entry points to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyProblemKind {
+    /// The record lies past the end of its log, or the log is missing.
+    Dangling,
+    /// The frame header of the record declares another length than the index.
+    LengthMismatch {
+        /// the length declared by the frame header, header included
+        actual: u64,
+    },
+    /// The record fails its checksum or cannot be deserialized.
+    Corrupt,
+    /// The record is about another key.
+    KeyMismatch {
+        /// the key of the record
+        found: String,
+    },
+    /// The record removes the key instead of setting it.
+    Removed,
+}
+
+/// The result of `KvStore::repair`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The logs cut at a corrupt or torn record, as `(gen, offset)` of the record.
+    pub truncated: Vec<(u64, u64)>,
+    /// The index entries dropped because they did not point to a valid record.
+    pub dropped: Vec<VerifyProblem>,
+    /// The compaction rewriting the remaining records into a clean log.
+    pub compaction: CompactionStats,
+}
+
+/// A record of a log file, as listed by `KvStore::dump_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The log generation of the record.
+    pub gen: u64,
+    /// The offset of the record in the log.
+    pub offset: u64,
+    /// The length of the record, frame header included.
+    pub len: u64,
+    /// The kind of command: `Set`, `SetBytes`, `SetEx`, `SetIdempotent` or `Remove`.
+    pub op: &'static str,
+    /// The key of the command.
+    pub key: String,
+}
+
+/// An iterator over the key/value pairs of a `KvStore`, returned by `KvStore::iter`.
+///
+/// The keys and the positions of their values are a snapshot of the index taken
+/// when the iterator is created, and each value is read from the log only when
+/// the iterator reaches it. Writes after the snapshot are not seen: an
+/// overwritten or removed key still yields its value at the time of the
+/// snapshot, since the log is append-only. A compaction or `clear` deleting the
+/// log of a value makes the iterator yield `KvsError::MissingReader` for it.
+pub struct KvIter<'a> {
+    store: &'a KvStore,
+    entries: std::collections::btree_map::IntoIter<String, CommandPos>,
+}
+
+impl Iterator for KvIter<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, cmd_pos) = self.entries.next()?;
+        let mut inner = self.store.lock();
+        let value = match read_command(&mut inner.readers, &cmd_pos) {
+            Ok(Command::Set { value, .. })
+            | Ok(Command::SetEx { value, .. })
+            | Ok(Command::SetIdempotent { value, .. }) => Ok(value),
+            Ok(Command::SetBytes { value, .. }) => String::from_utf8(value).map_err(Into::into),
+            Ok(Command::Remove { .. })
+            | Ok(Command::Clear)
+            | Ok(Command::Batch(_))
+            | Ok(Command::BlobRef { .. }) => Err(KvsError::UnexpectedCommandType),
+            Err(e) => Err(e),
+        };
+        Some(value.map(|value| (key, value)))
     }
 }
 
-impl KvsEngine for KvStore {
-    /// Set the value of a string key to a string
+/// A builder to configure and open a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStoreBuilder, Result, SyncPolicy};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+///
+/// let store = KvStoreBuilder::new()
+///     .dir(current_dir()?)
+///     .compaction_threshold(64 * 1024)
+///     .sync_policy(SyncPolicy::EveryWrite)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreBuilder {
+    dir: Option<PathBuf>,
+    options: KvStoreOptions,
+}
+
+impl KvStoreBuilder {
+    /// Creates a builder with every option set to its default.
+    pub fn new() -> Self {
+        KvStoreBuilder::default()
+    }
+
+    /// Sets the directory for the log and other data. Required.
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Sets how many bytes of stale log records trigger a compaction, 1MB by default.
+    pub fn compaction_threshold(mut self, compaction_threshold: u64) -> Self {
+        self.options.compaction_threshold(compaction_threshold);
+        self
+    }
+
+    /// Sets when written records are synced to disk, `SyncPolicy::Never` by default.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.options.sync_policy(sync_policy);
+        self
+    }
+
+    /// Sets the serialization of records in new log files, `LogEncoding::Json` by default.
+    pub fn log_encoding(mut self, log_encoding: LogEncoding) -> Self {
+        self.options.log_encoding(log_encoding);
+        self
+    }
+
+    /// Sets whether writes stay in the in-process buffer, `false` by default.
+    pub fn buffered_writes(mut self, buffered_writes: bool) -> Self {
+        self.options.buffered_writes(buffered_writes);
+        self
+    }
+
+    /// Sets the size limit of a value, unlimited by default.
+    pub fn max_value_bytes(mut self, max_value_bytes: u64) -> Self {
+        self.options.max_value_bytes(max_value_bytes);
+        self
+    }
+
+    /// Sets the length limit of a key in bytes, unlimited by default.
+    pub fn max_key_bytes(mut self, max_key_bytes: u64) -> Self {
+        self.options.max_key_bytes(max_key_bytes);
+        self
+    }
+
+    /// Sets the false positive rate of the bloom filter of each generation.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// See `KvStoreOptions::bloom_false_positive_rate`.
+    pub fn bloom_false_positive_rate(mut self, bloom_false_positive_rate: f64) -> Self {
+        self.options
+            .bloom_false_positive_rate(bloom_false_positive_rate);
+        self
+    }
+
+    /// Sets whether compactions run on a background thread.
     ///
-    /// # Errors
+    /// See `KvStoreOptions::background_compaction`.
+    pub fn background_compaction(mut self, background_compaction: bool) -> Self {
+        self.options.background_compaction(background_compaction);
+        self
+    }
+
+    /// Sets how many generations trigger a compaction once exceeded.
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
+    /// See `KvStoreOptions::max_generations`.
+    pub fn max_generations(mut self, max_generations: u64) -> Self {
+        self.options.max_generations(max_generations);
+        self
+    }
 
-        if let Command::Set { key, value: _ } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, CommandPos::new(self.current_gen, pos, self.writer.pos))
-            {
-                self.uncompacted += old_cmd.length;
-            }
-        }
+    /// Caches up to `capacity` recently read values in memory.
+    ///
+    /// See `KvStoreOptions::value_cache`.
+    pub fn value_cache(mut self, capacity: usize) -> Self {
+        self.options.value_cache(capacity);
+        self
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
+    /// Lets `get` read the log outside the store lock with pooled file handles.
+    ///
+    /// See `KvStoreOptions::reader_pool`.
+    pub fn reader_pool(mut self, capacity: usize) -> Self {
+        self.options.reader_pool(capacity);
+        self
+    }
 
-        Ok(())
+    /// Keeps at most `max_open_files` log files open for reading, unlimited by
+    /// default.
+    ///
+    /// See `KvStoreOptions::max_open_files`.
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.options.max_open_files(max_open_files);
+        self
     }
 
-    /// Get the string value of the a string key.
+    /// Sets whether the log files are memory-mapped for reading, `false` by default.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.options.mmap_reads(mmap_reads);
+        self
+    }
+
+    /// Sets whether new log records are compressed with lz4, `false` by default.
+    #[cfg(feature = "compression")]
+    pub fn compress_records(mut self, compress_records: bool) -> Self {
+        self.options.compress_records(compress_records);
+        self
+    }
+
+    /// Sets the size in bytes above which a value is stored in a blob file, not
+    /// set by default.
     ///
-    /// If the key does not exist, return `None`.
+    /// See `KvStoreOptions::blob_threshold`.
+    pub fn blob_threshold(mut self, blob_threshold: u64) -> Self {
+        self.options.blob_threshold(blob_threshold);
+        self
+    }
+
+    /// Sets the capacity in bytes of the buffer of each log reader and writer,
+    /// 8KB by default.
     ///
-    /// # Errors
+    /// See `KvStoreOptions::buffer_size`.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.options.buffer_size(buffer_size);
+        self
+    }
+
+    /// Sets whether a `set` overwrites a record of the same length in the
+    /// current log in place, `false` by default. This gives up the crash safety
+    /// of an append-only log.
     ///
-    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            // key --> command's start postion
-            reader.seek(SeekFrom::Start(cmd_pos.start))?;
-            // key --> command's length
-            let cmd_reader = reader.take(cmd_pos.length);
-            if let Command::Set { key: _, value } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+    /// See `KvStoreOptions::overwrite_in_place`.
+    pub fn overwrite_in_place(mut self, overwrite_in_place: bool) -> Self {
+        self.options.overwrite_in_place(overwrite_in_place);
+        self
     }
 
-    /// Remove a given key.
+    /// Removes expired keys on a background thread every `interval`, disabled by
+    /// default.
     ///
-    /// # Errors
+    /// See `KvStoreOptions::expiry_sweep_interval`.
+    pub fn expiry_sweep_interval(mut self, interval: Duration) -> Self {
+        self.options.expiry_sweep_interval(interval);
+        self
+    }
+
+    /// Sets which generations the compactions triggered by writes merge,
+    /// `CompactionStrategy::Full` by default.
+    ///
+    /// See `KvStoreOptions::compaction_strategy`.
+    pub fn compaction_strategy(mut self, compaction_strategy: CompactionStrategy) -> Self {
+        self.options.compaction_strategy(compaction_strategy);
+        self
+    }
+
+    /// Sets the maximum number of keys of the store, unlimited by default.
+    ///
+    /// See `KvStoreOptions::max_keys`.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.options.max_keys(max_keys);
+        self
+    }
+
+    /// Sets what a write of a new key does once the store holds `max_keys` keys,
+    /// `EvictionPolicy::RejectNew` by default.
+    ///
+    /// See `KvStoreOptions::eviction_policy`.
+    pub fn eviction_policy(mut self, eviction_policy: EvictionPolicy) -> Self {
+        self.options.eviction_policy(eviction_policy);
+        self
+    }
+
+    /// Validates the options and opens the `KvStore`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidOption` if no directory is set or an option
+    /// is out of range, and propagates errors opening the store.
+    pub fn build(self) -> Result<KvStore> {
+        let dir = self
+            .dir
+            .ok_or_else(|| KvsError::InvalidOption("data directory is not set".to_owned()))?;
+        self.options.open(dir)
+    }
+}
+
+/// The `KvStore` used HashMap, storing in memroy, not on a disk
+///
+/// Example:
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+///
+/// let store = KvStore::open(current_dir()?)?;
+///
+/// store.set("key1".to_owned(), "value1".to_owned());
+/// assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+/// assert_eq!(store.get("key2".to_owned())?, None);
+///
+/// store.remove("key1".to_owned());
+/// assert_eq!(store.get("key1".to_owned())?, None);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// `KvStore` is a cheap handle: cloning it shares the same log and index, so
+/// clones can be handed out to other threads.
+#[derive(Clone)]
+pub struct KvStore {
+    inner: Arc<Mutex<KvStoreInner>>,
+    // file handles for reads outside the lock, `None` if not enabled
+    reader_pool: Option<Arc<ReaderPool>>,
+}
+
+struct KvStoreInner {
+    // directory for the log and other data.
+    path: PathBuf,
+    current_gen: u64,
+    // map generation number to the file reader.
+    readers: Readers,
+    // writer of the current log, `None` if opened read-only.
+    writer: Option<BufferWriterWithPos<File>>,
+    // the locked `LOCK` file, released when closed. `None` if opened read-only.
+    _lock: Option<File>,
+    // an in-memory [key -> log pointer] map, ordered by key.
+    index: BTreeMap<String, CommandPos>,
+    // stale log size
+    uncompacted: u64,
+    sync_policy: SyncPolicy,
+    // the last time the current log was synced to disk.
+    last_sync: Instant,
+    // stale log size that triggers a compaction
+    compaction_threshold: u64,
+    // number of generations that triggers a compaction once exceeded
+    max_generations: Option<u64>,
+    // which generations the compactions triggered by writes merge
+    compaction_strategy: CompactionStrategy,
+    // total size of the small generations older than the current one, see
+    // `small_generations`, `None` until computed again after a compaction
+    tier_bytes: Option<u64>,
+    // serialization of records in new log files
+    log_encoding: LogEncoding,
+    // whether new records are compressed when that makes them smaller
+    compress_records: bool,
+    // size above which a value is written to the blob file
+    blob_threshold: Option<u64>,
+    // writer of the blob file of the generation, opened on the first large value
+    blob_writer: Option<(u64, BufferWriterWithPos<File>)>,
+    // capacity of the buffer of each log reader and writer
+    buffer_size: usize,
+    // whether a `set` overwrites a record of the same length in the current log
+    overwrite_in_place: bool,
+    // handle of the log of the generation opened for writing in place, on the first overwrite
+    overwrite_file: Option<(u64, File)>,
+    // whether writes are left in the buffer of the writer
+    buffered_writes: bool,
+    // whether the writer holds records not flushed to the OS yet
+    unflushed: bool,
+    // whether new readers memory-map their log
+    #[cfg(feature = "mmap")]
+    mmap_reads: bool,
+    // size limit of a value
+    max_value_bytes: Option<u64>,
+    // length limit of a key
+    max_key_bytes: Option<u64>,
+    // map generation number to the bloom filter of the keys in its log.
+    blooms: HashMap<u64, BloomFilter>,
+    bloom_false_positive_rate: f64,
+    // the compaction in progress, if any
+    compaction: Option<CompactionProgress>,
+    // recently read values, `None` if not enabled
+    cache: Option<ValueCache>,
+    // shared with `KvStore`, to close the handles of deleted logs
+    reader_pool: Option<Arc<ReaderPool>>,
+    // wakes the background compaction thread, `None` if compactions run inline
+    compaction_trigger: Option<SyncSender<()>>,
+    // dropped with the store to stop the expiry sweep thread, if any
+    _sweep_stop: Option<Sender<()>>,
+    // the keys and tokens of the `set_idempotent` calls written to the logs,
+    // kept when the current log rolls over
+    idempotency_tokens: HashSet<(String, String)>,
+    // subscribed replicas, each is sent every record written to the log
+    subscribers: Vec<Sender<Vec<u8>>>,
+    // the LSN of the last record written
+    lsn: u64,
+    // maximum number of keys, unlimited if `None`
+    max_keys: Option<usize>,
+    // the order keys were used in, `None` unless evicting under `max_keys`
+    recency: Option<KeyRecency>,
+}
+
+/// The state of an incremental compaction between two `KvStore::compact_step`.
+struct CompactionProgress {
+    // generation of the compaction log
+    gen: u64,
+    writer: BufferWriterWithPos<File>,
+    // writer of the blob file of `gen`, opened on the first value moved into it
+    blob_writer: Option<BufferWriterWithPos<File>>,
+    bloom: BloomFilter,
+    // the last key of the index visited, the next step resumes after it
+    last_key: Option<String>,
+    // total size of the log files when the compaction started
+    bytes_before: u64,
+    // size of the records in the logs older than `gen`
+    old_bytes: u64,
+    // size of the records copied out of the older logs
+    copied: u64,
+    // the highest LSN of the records copied
+    max_lsn: u64,
+    // the oldest generation merged, the older ones are left alone
+    floor: u64,
+}
+
+impl KvStore {
+    /// Open the `KvStore` at a given path. Return the KvStore.
+    ///
+    /// This will create a new directory if the given dir does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialilzation errors during the log re-play.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, &KvStoreOptions::default())
+    }
+
+    /// Open the `KvStore` at a given path with the given `options`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialilzation errors during the log re-play.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<KvStore> {
+        options.validate()?;
+        let path = path.into();
+        fs::create_dir_all(&path).map_err(|e| path_io(&path, e))?;
+        let lock = KvStore::lock_dir(&path)?;
+        // 其它 I/O 错误只能带上目录，至少让用户知道是哪个 store
+        KvStore::open_dir(path.clone(), options, Some(lock)).map_err(|e| match e {
+            KvsError::Io(e) => path_io(&path, e),
+            e => e,
+        })
+    }
+
+    /// Locks the store directory, so no other instance writes to the same log.
+    ///
+    /// The lock is released when the returned file is closed.
+    fn lock_dir(path: &Path) -> Result<File> {
+        check_not_sled(path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path.join(LOCK_FILE))
+            .map_err(|e| path_io(&path.join(LOCK_FILE), e))?;
+        file.try_lock_exclusive()
+            .map_err(|_| KvsError::AlreadyLocked {
+                path: path.to_owned(),
+            })?;
+        Ok(file)
+    }
+
+    /// Opens a store over a single log held by `source`, such as an in-memory
+    /// `Cursor<Vec<u8>>`, instead of the files of a directory.
+    ///
+    /// The log has the format of a log file of a store, so `source` can also be
+    /// an existing log file, with the restrictions of `IoKvStore`. An empty
+    /// source gets a new log in `LogEncoding::Json`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptLog` for a bad record followed by other
+    /// records, and propagates I/O or deserialization errors during the log
+    /// re-play.
+    pub fn from_io<S: Read + Write + Seek>(source: S) -> Result<IoKvStore<S>> {
+        IoKvStore::open(source)
+    }
+
+    /// Open the `KvStore` at a given path for reading only.
+    ///
+    /// No log file is created or modified, so other processes can read the same
+    /// directory. Writes return `KvsError::ReadOnly`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialilzation errors during the log re-play.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let path = path.into();
+        check_not_sled(&path)?;
+        // 只读的 instance 不写 log，不需要加锁
+        KvStore::open_dir(path, &KvStoreOptions::default(), None)
+    }
+
+    /// Opens the store at `path`, holding `lock` for writing or read-only if
+    /// `lock` is `None`.
+    fn open_dir(path: PathBuf, options: &KvStoreOptions, lock: Option<File>) -> Result<KvStore> {
+        let read_only = lock.is_none();
+        let mut readers = Readers::new(options.max_open_files);
+        let mut index = BTreeMap::new();
+        let mut blooms = HashMap::new();
+        let bloom_rate = options.bloom_false_positive_rate;
+
+        let gen_list = sorted_gen_list(&path)?;
+        // 各个 generation 并行地 load，再按 generation 从小到大合并
+        let loaded = gen_list
+            .par_iter()
+            .map(|&gen| -> Result<_> {
+                let mut reader = LogReader::open(&log_path(&path, gen), options.buffer_size)?;
+                // 已经保存的 bloom filter 不需要重新构建
+                let saved = BloomFilter::load(&bloom_path(&path, gen))?;
+                let (mut bloom, build) = match saved {
+                    Some(bloom) => (bloom, false),
+                    None => (BloomFilter::new(bloom_rate), true),
+                };
+                let building = if build { Some(&mut bloom) } else { None };
+                let gen_index = load(gen, &mut reader, &path, read_only, building)?;
+                // 限制了打开的文件数时，先关闭，读的时候再打开
+                if options.max_open_files.is_some() {
+                    reader.close();
+                }
+                // 之前的 generation 不会再写入，可以保存下来
+                if build && !read_only {
+                    bloom.save(&bloom_path(&path, gen))?;
+                }
+                Ok((gen, reader, bloom, gen_index))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut uncompacted = 0;
+        // 新的 record 接着最大的 LSN 继续编号
+        let mut lsn = 0;
+        // 重启之后重试的 set_idempotent 也要被去重；打开时会新建一个空的 log，
+        // 所以不能只看最后一个 generation
+        let mut idempotency_tokens = HashSet::new();
+        for (gen, reader, bloom, mut gen_index) in loaded {
+            lsn = lsn.max(gen_index.max_lsn);
+            idempotency_tokens.extend(std::mem::take(&mut gen_index.tokens));
+            uncompacted += gen_index.merge_into(&mut index);
+            readers.insert(gen, reader);
+            blooms.insert(gen, bloom);
+        }
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        if !read_only {
+            blooms.insert(current_gen, BloomFilter::new(bloom_rate));
+        }
+
+        let writer = if read_only {
+            None
+        } else {
+            Some(new_log_file(
+                &path,
+                current_gen,
+                options.log_encoding,
+                options.buffer_size,
+                &mut readers,
+            )?)
+        };
+        #[cfg(feature = "mmap")]
+        if options.mmap_reads {
+            readers
+                .values_mut()
+                .for_each(|reader| reader.use_mmap = true);
+        }
+
+        let reader_pool = options
+            .reader_pool
+            .map(|capacity| Arc::new(ReaderPool::new(path.clone(), capacity)));
+        // 没有读写记录，按写入顺序（LSN）近似 key 的使用顺序
+        let recency = match (options.max_keys, options.eviction_policy) {
+            (Some(_), EvictionPolicy::EvictLru) => {
+                let mut keys: Vec<_> = index.iter().collect();
+                keys.sort_by_key(|(_, cmd_pos)| cmd_pos.lsn);
+                let mut recency = KeyRecency::default();
+                keys.into_iter().for_each(|(key, _)| recency.touch(key));
+                Some(recency)
+            }
+            _ => None,
+        };
+        let store = KvStore {
+            reader_pool: reader_pool.clone(),
+            inner: Arc::new(Mutex::new(KvStoreInner {
+                path,
+                current_gen,
+                readers,
+                writer,
+                _lock: lock,
+                index,
+                uncompacted,
+                sync_policy: options.sync_policy,
+                last_sync: Instant::now(),
+                compaction_threshold: options.compaction_threshold,
+                max_generations: options.max_generations,
+                compaction_strategy: options.compaction_strategy,
+                tier_bytes: None,
+                log_encoding: options.log_encoding,
+                compress_records: options.compress_records,
+                blob_threshold: options.blob_threshold,
+                blob_writer: None,
+                buffer_size: options.buffer_size,
+                overwrite_in_place: options.overwrite_in_place,
+                overwrite_file: None,
+                buffered_writes: options.buffered_writes,
+                unflushed: false,
+                #[cfg(feature = "mmap")]
+                mmap_reads: options.mmap_reads,
+                max_value_bytes: options.max_value_bytes,
+                max_key_bytes: options.max_key_bytes,
+                blooms,
+                bloom_false_positive_rate: bloom_rate,
+                compaction: None,
+                cache: options.value_cache.map(ValueCache::new),
+                reader_pool,
+                compaction_trigger: None,
+                _sweep_stop: None,
+                idempotency_tokens,
+                subscribers: Vec::new(),
+                lsn,
+                max_keys: options.max_keys,
+                recency,
+            })),
+        };
+        if options.background_compaction && !read_only {
+            let (trigger, triggered) = mpsc::sync_channel(1);
+            let inner = Arc::downgrade(&store.inner);
+            thread::Builder::new()
+                .name("kvs-compaction".to_owned())
+                .spawn(move || background_compaction(inner, triggered))?;
+            store.lock().compaction_trigger = Some(trigger);
+        }
+        if let (Some(interval), false) = (options.expiry_sweep_interval, read_only) {
+            let (stop, stopped) = mpsc::channel();
+            let inner = Arc::downgrade(&store.inner);
+            thread::Builder::new()
+                .name("kvs-expiry-sweep".to_owned())
+                .spawn(move || expiry_sweep(inner, stopped, interval))?;
+            store.lock()._sweep_stop = Some(stop);
+        }
+        // 每次 open 都新增一个 generation，超过上限时不用等到下一次写入
+        if !read_only {
+            let mut inner = store.lock();
+            if inner.too_many_generations() {
+                inner.maybe_compact()?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// Set the value of a string key to arbitrary bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.lock().set_bytes(key, value)
+    }
+
+    /// Get the raw bytes of the value of a string key.
+    ///
+    /// Values written by `set` are returned as their UTF-8 bytes. If the key does
+    /// not exist, return `None`.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.lock().get_bytes(key)
+    }
+
+    /// Get a reader of the value of a string key, returning `None` if the key
+    /// does not exist.
+    ///
+    /// A value stored in a blob file (see `KvStoreOptions::blob_threshold`) is
+    /// streamed from the file, reading no more than its length, instead of
+    /// being allocated whole. A smaller value is decoded from the log into
+    /// memory. The reader does not hold the store lock.
+    pub fn get_reader(&self, key: String) -> Result<Option<impl Read>> {
+        self.lock().get_reader(key)
+    }
+
+    /// Get the string value a string key had at `lsn`, the value of its last
+    /// record with an LSN up to `lsn`. See `KvStore::current_lsn`.
+    ///
+    /// The logs are scanned under the lock instead of looking the key up in the
+    /// index, so this is meant for debugging and audits. The history is only
+    /// there until a compaction drops the stale records: after that, an `lsn`
+    /// older than the record left for the key returns `None`.
+    pub fn get_as_of(&self, key: String, lsn: u64) -> Result<Option<String>> {
+        self.lock().get_as_of(key, lsn)
+    }
+
+    /// Get the string value of a string key together with where its record lives
+    /// in the log.
+    ///
+    /// The location changes when the key is overwritten or a compaction moves
+    /// the record. If the key does not exist, return `None`.
+    pub fn get_with_meta(&self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        self.lock().get_with_meta(key)
+    }
+
+    /// Apply a batch of set/remove operations, in order, with a single flush.
+    ///
+    /// All commands are serialized first and written to the log at once, and the
+    /// in-memory index is only updated after the flush succeeds.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` without writing anything if a `Remove`
+    /// targets a key that is absent at that point of the batch.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.lock().write_batch(ops)
+    }
+
+    /// Set the value of a string key to a string that expires after `ttl`.
+    ///
+    /// Once expired, the key behaves as if it was removed and its space is
+    /// reclaimed by the next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.lock().set_with_ttl(key, value, ttl)
+    }
+
+    /// Set the value of a string key to a string, unless a `set_idempotent` of
+    /// the key with the same `token` was already written to the store.
+    ///
+    /// Returns whether the value was written. This makes a client retry of a
+    /// write that may have reached the log safe, also after a crash and a
+    /// reopen: the tokens of the logs are loaded on open and kept when the log
+    /// rolls over, so the retry is skipped. Records of a log with the same key
+    /// and token are replayed once. A compaction drops the records of
+    /// overwritten values, so their tokens can be reused after the next reopen.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_idempotent(&self, key: String, value: String, token: String) -> Result<bool> {
+        self.lock().set_idempotent(key, value, token)
+    }
+
+    /// Removes every expired key from the index, writing a remove of each to the
+    /// log, and returns how many were removed.
+    ///
+    /// Expired keys otherwise stay in the index until read or compacted away.
+    /// The removes go to the log as a single record, and both the expired
+    /// records and the removes are dropped by the next compaction. See
+    /// `KvStoreOptions::expiry_sweep_interval` to sweep periodically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ReadOnly` on a store opened read-only, and
+    /// propagates I/O or serialization errors during writing the log.
+    pub fn sweep_expired(&self) -> Result<usize> {
+        self.lock().sweep_expired()
+    }
+
+    /// Returns the key/value pairs whose keys fall within `(start, end)`, sorted
+    /// ascending by key.
+    ///
+    /// An empty or inverted range returns an empty `Vec`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn scan(&self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        self.lock().scan(start, end)
+    }
+
+    /// Returns an iterator over all live key/value pairs, sorted ascending by key.
+    ///
+    /// Only the index is copied up front, the values are read lazily, so a large
+    /// store can be streamed without holding all its values in memory. See
+    /// `KvIter` for what the iterator sees of writes made during the iteration.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during flushing buffered writes.
+    pub fn iter(&self) -> Result<KvIter<'_>> {
+        let entries = self.lock().snapshot_index()?;
+        Ok(KvIter {
+            store: self,
+            entries: entries.into_iter(),
+        })
+    }
+
+    /// Returns all live keys starting with `prefix`, sorted ascending.
+    ///
+    /// This only consults the in-memory index and never touches the log.
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        self.lock().keys_with_prefix(prefix)
+    }
+
+    /// Set the value of `key` in the namespace `ns`.
+    ///
+    /// Namespaces partition the keys of one store: the same key in two
+    /// namespaces holds two values. The key is stored as `ns`, a NUL separator
+    /// and `key`, so `scan` and `keys_with_prefix` see namespaced keys too.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidNamespace` if `ns` is empty or contains a NUL.
+    pub fn set_ns(&self, ns: &str, key: String, value: String) -> Result<()> {
+        let key = namespaced(ns, &key)?;
+        self.lock().set(key, value)
+    }
+
+    /// Gets the value of `key` in the namespace `ns`.
+    pub fn get_ns(&self, ns: &str, key: String) -> Result<Option<String>> {
+        let key = namespaced(ns, &key)?;
+        self.lock().get(key)
+    }
+
+    /// Removes `key` from the namespace `ns`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key is not in the namespace.
+    pub fn remove_ns(&self, ns: &str, key: String) -> Result<()> {
+        let key = namespaced(ns, &key)?;
+        self.lock().remove(key)
+    }
+
+    /// Returns the live keys of the namespace `ns`, without the namespace, sorted
+    /// ascending.
+    pub fn keys_ns(&self, ns: &str) -> Result<Vec<String>> {
+        let prefix = namespaced(ns, "")?;
+        Ok(self
+            .lock()
+            .keys_with_prefix(&prefix)
+            .into_iter()
+            .map(|key| key[prefix.len()..].to_owned())
+            .collect())
+    }
+
+    /// Returns the key/value pairs of the namespace `ns` whose keys fall within
+    /// `(start, end)`, like `scan`. The keys are returned without the namespace.
+    pub fn scan_ns(
+        &self,
+        ns: &str,
+        start: Bound<String>,
+        end: Bound<String>,
+    ) -> Result<Vec<(String, String)>> {
+        let prefix = namespaced(ns, "")?;
+        let bound = |bound: Bound<String>, unbounded: Bound<String>| match bound {
+            Bound::Included(key) => Bound::Included(format!("{}{}", prefix, key)),
+            Bound::Excluded(key) => Bound::Excluded(format!("{}{}", prefix, key)),
+            Bound::Unbounded => unbounded,
+        };
+        // 分隔符的下一个字符是这个 namespace 之后的第一个 key
+        let after = format!("{}{}", ns, (NAMESPACE_SEPARATOR as u8 + 1) as char);
+        let start = bound(start, Bound::Included(prefix.clone()));
+        let end = bound(end, Bound::Excluded(after));
+        Ok(self
+            .lock()
+            .scan(start, end)?
+            .into_iter()
+            .map(|(key, value)| (key[prefix.len()..].to_owned(), value))
+            .collect())
+    }
+
+    /// Removes all the keys of the namespace `ns`, returning how many were removed.
+    ///
+    /// The removals are written to the log as one batch, so after a crash either
+    /// the whole namespace is gone or none of it.
+    pub fn drop_namespace(&self, ns: &str) -> Result<u64> {
+        let prefix = namespaced(ns, "")?;
+        let mut inner = self.lock();
+        let ops: Vec<_> = inner
+            .keys_with_prefix(&prefix)
+            .into_iter()
+            .map(|key| BatchOp::Remove { key })
+            .collect();
+        let count = ops.len() as u64;
+        if count > 0 {
+            inner.write_batch(ops)?;
+        }
+        Ok(count)
+    }
+
+    /// Compacts the log now, regardless of the compaction threshold.
+    ///
+    /// Live values are copied into a new log and the stale logs are removed.
+    /// When nothing is stale this is a cheap no-op that still returns the
+    /// current sizes.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during the compaction.
+    pub fn compact(&self) -> Result<CompactionStats> {
+        self.lock().compact()
+    }
+
+    /// Runs one step of an incremental compaction, starting one if none is in
+    /// progress.
+    ///
+    /// A step copies about `max_bytes` of live values, and at least one, into the
+    /// compaction log, so the store is only held for a bounded time and can serve
+    /// other requests between steps. Values written after the compaction started
+    /// are left in place. Returns the statistics of the compaction once the last
+    /// step removed the stale logs, `None` while more steps are needed.
+    /// `compact` finishes a compaction in progress.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during the compaction.
+    pub fn compact_step(&self, max_bytes: u64) -> Result<Option<CompactionStats>> {
+        self.lock().compact_step(max_bytes)
+    }
+
+    /// Returns whether an incremental compaction is in progress.
+    pub fn is_compacting(&self) -> bool {
+        self.lock().compaction.is_some()
+    }
+
+    /// Replaces the value of `key` with `new` only if its current value equals
+    /// `expected`, returning whether the swap happened.
+    ///
+    /// `None` as `expected` means the key must be absent, `None` as `new` removes
+    /// the key. The check and the write happen under one lock, so concurrent
+    /// swaps on the same key are serialized.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.lock().compare_and_swap(key, expected, new)
+    }
+
+    /// Writes all live key/value pairs to `writer` as newline-delimited json, one
+    /// `{"key": ..., "value": ...}` object per line sorted by key, and returns the
+    /// number of pairs written.
+    ///
+    /// Unlike the log, this format does not depend on the store version, so it can
+    /// be used for backups.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if a value set by `set_bytes` is not valid UTF-8,
+    /// and propagates I/O or serialization errors.
+    pub fn export(&self, writer: impl Write) -> Result<u64> {
+        self.lock().export(writer)
+    }
+
+    /// Sets every key/value pair of a snapshot written by `export`, and returns the
+    /// number of pairs read.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors; the pairs before a bad line
+    /// stay set.
+    pub fn import(&self, reader: impl Read) -> Result<u64> {
+        self.lock().import(reader)
+    }
+
+    /// Returns the current size of the store.
+    ///
+    /// A compaction runs once `uncompacted_bytes` exceeds the compaction threshold.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors reading the size of the log files.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let inner = self.lock();
+        Ok(StoreStats {
+            live_keys: inner.index.len() as u64,
+            total_log_bytes: inner.log_size()?,
+            uncompacted_bytes: inner.uncompacted,
+            generation_count: inner.readers.len() as u64,
+        })
+    }
+
+    /// Returns the log sequence number (LSN) of the last record written, 0 if
+    /// nothing was written yet.
+    ///
+    /// Every record is stamped with the next LSN, a write batch with a single
+    /// one. The LSN is persisted in the record and survives compactions, so the
+    /// sequence continues where it stopped when the store is opened again.
+    pub fn current_lsn(&self) -> u64 {
+        self.lock().lsn
+    }
+
+    /// Estimates what `compact` would reclaim, without touching the logs.
+    ///
+    /// The estimate comes from the in-memory index and counts the expired values
+    /// as reclaimable. The header bytes of the log files are not counted.
+    pub fn compaction_estimate(&self) -> CompactionEstimate {
+        let inner = self.lock();
+        let now = now_unix_ms();
+        let mut estimate = CompactionEstimate {
+            reclaimable_bytes: inner.uncompacted,
+            live_bytes: 0,
+            live_entries: 0,
+        };
+        for cmd_pos in inner.index.values() {
+            if cmd_pos.is_expired(now) {
+                estimate.reclaimable_bytes += cmd_pos.length;
+            } else {
+                estimate.live_bytes += cmd_pos.length;
+                estimate.live_entries += 1;
+            }
+        }
+        estimate
+    }
+
+    /// Checks that every entry of the index points to a valid record of its key.
+    ///
+    /// Useful to diagnose a store after a crash: it reads every live record, and
+    /// reports the broken ones instead of failing on the first.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.lock().verify()
+    }
+
+    /// Repairs the store at `path`, dropping what cannot be read.
+    ///
+    /// Every log is cut at its first corrupt or torn record, losing the records
+    /// after it. Then the index entries that `verify` finds broken are dropped,
+    /// and the remaining records are compacted into a clean log.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::AlreadyLocked` if the store is open elsewhere, and
+    /// propagates I/O errors.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<RepairReport> {
+        let path = path.into();
+        let lock = KvStore::lock_dir(&path)?;
+        let mut truncated = Vec::new();
+        for gen in sorted_gen_list(&path)? {
+            let mut reader = LogReader::open(&log_path(&path, gen), DEFAULT_BUFFER_SIZE)?;
+            let file_len = fs::metadata(log_path(&path, gen))?.len();
+            let end = match replay(gen, &mut reader, &path, true, |_, _| {}) {
+                Err(KvsError::CorruptLog { offset, .. }) => offset,
+                res => res?,
+            };
+            if end < file_len {
+                warn!("truncating log {} at offset {}", gen, end);
+                OpenOptions::new()
+                    .write(true)
+                    .open(log_path(&path, gen))?
+                    .set_len(end)?;
+                truncated.push((gen, end));
+            }
+        }
+
+        let store = KvStore::open_dir(path, &KvStoreOptions::default(), Some(lock))?;
+        let mut inner = store.lock();
+        let dropped = inner.verify()?.problems;
+        for problem in &dropped {
+            if let Some(cmd_pos) = inner.index.remove(&problem.key) {
+                inner.uncompacted += cmd_pos.length;
+            }
+        }
+        let compaction = inner.compact()?;
+        Ok(RepairReport {
+            truncated,
+            dropped,
+            compaction,
+        })
+    }
+
+    /// Applies the records of the primary that `client` is connected to, as
+    /// they are written, on a background thread, making `self` a read replica.
+    ///
+    /// Only the records written after the subscription are shipped, so the
+    /// replica should start as a copy of the primary, such as both empty, and
+    /// not be written to otherwise. The subscription holds one thread of the
+    /// pool of the primary `KvsServer`. The thread ends when the primary closes
+    /// the connection, or with the first record that fails to apply.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error of the primary if it does not ship its records.
+    pub fn follow(&self, client: KvsClient) -> Result<thread::JoinHandle<Result<()>>> {
+        let subscription = client.subscribe()?;
+        let store = self.clone();
+        Ok(thread::spawn(move || {
+            for record in subscription {
+                store.apply_replicated(&record?)?;
+            }
+            Ok(())
+        }))
+    }
+
+    /// Applies a record received from `KvsEngine::subscribe` of a primary
+    /// `KvStore`, writing it to the log of `self`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` for a record that does not decode,
+    /// and propagates I/O errors during writing the log.
+    pub fn apply_replicated(&self, record: &[u8]) -> Result<()> {
+        self.lock().apply_replicated(record)
+    }
+
+    /// Calls `f` with every record of the log `gen` of the store at `path`, or of
+    /// all its logs oldest first if `gen` is `None`.
+    ///
+    /// The logs are decoded the same way as when opening the store, but only
+    /// read: the store is not locked and a torn record at the end of a log is
+    /// skipped with a warning instead of truncated. Useful to debug a broken or
+    /// surprising store.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptLog` at the first record that cannot be
+    /// decoded, after `f` has seen the records before it, and propagates I/O
+    /// errors, such as a missing log.
+    pub fn dump_log(
+        path: impl Into<PathBuf>,
+        gen: Option<u64>,
+        mut f: impl FnMut(LogRecord),
+    ) -> Result<()> {
+        let path = path.into();
+        let gen_list = match gen {
+            Some(gen) => vec![gen],
+            None => sorted_gen_list(&path)?,
+        };
+        for gen in gen_list {
+            let mut reader = LogReader::open(&log_path(&path, gen), DEFAULT_BUFFER_SIZE)?;
+            replay(gen, &mut reader, &path, true, |cmd, cmd_pos| {
+                f(LogRecord {
+                    gen,
+                    offset: cmd_pos.start,
+                    len: cmd_pos.length,
+                    op: cmd.name(),
+                    key: cmd.into_key(),
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the generations whose log may hold a record of `key`, oldest first.
+    ///
+    /// Each generation keeps a bloom filter of its keys, stored next to its log
+    /// as `<gen>.bloom`. A generation not returned certainly has no record of
+    /// `key`, so a lookup across generations can skip its log.
+    pub fn generations_with(&self, key: &str) -> Vec<u64> {
+        let inner = self.lock();
+        let mut gens: Vec<u64> = inner
+            .blooms
+            .iter()
+            .filter(|(_, bloom)| bloom.may_contain(key))
+            .map(|(&gen, _)| gen)
+            .collect();
+        gens.sort_unstable();
+        gens
+    }
+
+    /// Flushes the buffered writes to the OS, and syncs the current log to disk
+    /// under `SyncPolicy::Interval`.
+    ///
+    /// Once it returns, every acknowledged write survives a crash of the
+    /// process, and under a sync policy other than `SyncPolicy::Never` a power
+    /// loss too, so it marks a checkpoint. Without
+    /// `KvStoreOptions::buffered_writes` every write is flushed already.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during flushing or syncing the log, including
+    /// those of buffered writes that had not reached the OS yet.
+    pub fn flush(&self) -> Result<()> {
+        let mut inner = self.lock();
+        inner.flush_buffered()?;
+        // SyncPolicy::EveryWrite 在每次写入时已经 sync 过了
+        if let SyncPolicy::Interval(_) = inner.sync_policy {
+            if let Some(writer) = inner.writer.as_mut() {
+                writer.sync()?;
+            }
+            inner.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Flushes and syncs the current log to disk, then drops this handle.
+    ///
+    /// Dropping the last handle flushes the log too, but can only log a failure;
+    /// `close` returns it. Other clones of the handle keep working.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during flushing or syncing the log.
+    pub fn close(self) -> Result<()> {
+        let mut inner = self.lock();
+        inner.flush_buffered()?;
+        if let Some(writer) = inner.writer.as_mut() {
+            writer.sync()?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, KvStoreInner> {
+        self.inner.lock().expect("KvStore mutex poisoned")
+    }
+}
+
+impl KvStoreInner {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.append_value(Command::set(key, value))
+    }
+
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.append_value(Command::set_bytes(key, value))
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expire_at = now_unix_ms().saturating_add(ttl.as_millis() as u64);
+        self.append_value(Command::set_ex(key, value, expire_at))
+    }
+
+    fn set_idempotent(&mut self, key: String, value: String, token: String) -> Result<bool> {
+        let id = (key, token);
+        if self.idempotency_tokens.contains(&id) {
+            return Ok(false);
+        }
+        self.append_value(Command::SetIdempotent {
+            key: id.0.clone(),
+            value,
+            token: id.1.clone(),
+        })?;
+        self.idempotency_tokens.insert(id);
+        Ok(true)
+    }
+
+    /// Appends a `Set`/`SetBytes`/`SetEx`/`SetIdempotent` command to the log and
+    /// points the index at it.
+    ///
+    /// The keys evicted to make room for a new key are removed only once the
+    /// record of the new value is written, so a failed write evicts nothing.
+    fn append_value(&mut self, cmd: Command) -> Result<()> {
+        validate_key(cmd.key(), self.max_key_bytes)?;
+        self.check_value_len(cmd.value_len().unwrap_or(0))?;
+        let victims = if self.index.contains_key(cmd.key()) {
+            Vec::new()
+        } else {
+            self.pick_victims(1, &HashSet::new())?
+        };
+        if let Err(e) = self.write_value(cmd) {
+            self.restore_victims(&victims);
+            return Err(e);
+        }
+        if victims.is_empty() {
+            self.maybe_compact()
+        } else {
+            self.write_commands(victims.into_iter().map(Command::remove).collect())
+        }
+    }
+
+    /// Writes the record of a value command and points the index at it.
+    fn write_value(&mut self, cmd: Command) -> Result<()> {
+        let in_blob = matches!(
+            (self.blob_threshold, cmd.blob_value()),
+            (Some(threshold), Some(value)) if value.len() as u64 > threshold
+        );
+        let lsn = self.next_lsn();
+        let mut overwritten = false;
+        let (start, end) = if in_blob {
+            self.write_blob_record(&cmd, lsn)?
+        } else {
+            let record = encode_record(&cmd, self.log_encoding, self.compress_records, lsn)?;
+            match self.overwrite_record(&cmd, &record)? {
+                Some(pos) => {
+                    overwritten = true;
+                    pos
+                }
+                None => self.write_log(&record)?,
+            }
+        };
+        self.record_key(cmd.key());
+
+        self.touch_key(cmd.key());
+
+        let expire_at = cmd.expire_at();
+        if let Some(old_cmd) = self.index.insert(
+            cmd.into_key(),
+            CommandPos::new(self.current_gen, start, end)
+                .with_expire_at(expire_at)
+                .with_in_blob(in_blob)
+                .with_lsn(lsn),
+        ) {
+            // 原地覆盖的 record 没有变成 stale
+            if !overwritten {
+                self.uncompacted += old_cmd.length;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `record` of `cmd` over the record of the old value if
+    /// `overwrite_in_place` is set, the old record is a `Set`/`SetBytes` in the
+    /// current log and has the same length. Returns the position of the record,
+    /// `None` if it has to be appended instead.
+    fn overwrite_record(&mut self, cmd: &Command, record: &[u8]) -> Result<Option<(u64, u64)>> {
+        if !self.overwrite_in_place
+            || self.writer.is_none()
+            || !matches!(cmd, Command::Set { .. } | Command::SetBytes { .. })
+        {
+            return Ok(None);
+        }
+        let old_cmd = match self.index.get(cmd.key()) {
+            Some(old_cmd)
+                if old_cmd.gen == self.current_gen
+                    && !old_cmd.in_batch
+                    && !old_cmd.in_blob
+                    && old_cmd.length == record.len() as u64 =>
+            {
+                old_cmd.clone()
+            }
+            _ => return Ok(None),
+        };
+        // 旧的 record 可能还在 writer 的 buffer 中
+        self.flush_buffered()?;
+        // SetEx 的过期时间和 SetIdempotent 的 token 在 replay 时需要，不能被覆盖
+        if !matches!(
+            self.readers.reader(old_cmd.gen)?.read_record(&old_cmd)?,
+            Command::Set { .. } | Command::SetBytes { .. }
+        ) {
+            return Ok(None);
+        }
+        let start = old_cmd.start;
+        let gen = self.current_gen;
+        let file = match &mut self.overwrite_file {
+            Some((file_gen, file)) if *file_gen == gen => file,
+            overwrite_file => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(log_path(&self.path, gen))?;
+                &mut overwrite_file.insert((gen, file)).1
+            }
+        };
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(record)?;
+        if self.sync_policy != SyncPolicy::Never {
+            file.sync_data()?;
+        }
+        // reader 的 buffer 中可能还是旧的 record，seek 会丢弃 buffer
+        self.readers
+            .reader(self.current_gen)?
+            .file()?
+            .seek(SeekFrom::Start(start))?;
+        self.ship(record);
+        Ok(Some((start, start + record.len() as u64)))
+    }
+
+    /// Writes the value of `cmd` to the blob file of the current generation, then
+    /// a `Command::BlobRef` to it stamped with `lsn` to the log. Returns the
+    /// position of the record.
+    fn write_blob_record(&mut self, cmd: &Command, lsn: u64) -> Result<(u64, u64)> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let value = cmd.blob_value().expect("not a value of a blob");
+        let gen = self.current_gen;
+        let writer = match &mut self.blob_writer {
+            Some((blob_gen, writer)) if *blob_gen == gen => writer,
+            blob_writer => {
+                &mut blob_writer
+                    .insert((gen, open_blob_file(&self.path, gen, self.buffer_size)?))
+                    .1
+            }
+        };
+        let offset = write_blob(writer, value)?;
+        // 指向 blob 的 record 落盘之前，blob 必须已经落盘
+        if self.sync_policy != SyncPolicy::Never {
+            writer.sync()?;
+        }
+        let blob_ref = Command::BlobRef {
+            key: cmd.key().to_owned(),
+            blob: gen,
+            offset,
+            length: value.len() as u64,
+        };
+        let pos = self.append_log(&encode_record(&blob_ref, self.log_encoding, false, lsn)?)?;
+        // replica 没有这个 blob 文件，发送完整的 value
+        if !self.subscribers.is_empty() {
+            let record = encode_record(cmd, self.log_encoding, self.compress_records, lsn)?;
+            self.ship(&record);
+        }
+        Ok(pos)
+    }
+
+    fn write_batch(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        // 先校验 remove 的 key 是否存在，考虑 batch 内前面的 set/remove
+        let mut batch_keys: HashMap<&str, bool> = HashMap::new();
+        for op in &ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    validate_key(key, self.max_key_bytes)?;
+                    self.check_value_len(value.len())?;
+                    batch_keys.insert(key, true);
+                }
+                BatchOp::Remove { key } => {
+                    validate_key(key, self.max_key_bytes)?;
+                    self.drop_if_expired(key);
+                    let exists = batch_keys
+                        .get(key.as_str())
+                        .copied()
+                        .unwrap_or_else(|| self.index.contains_key(key));
+                    if !exists {
+                        return Err(KvsError::KeyNotFound);
+                    }
+                    batch_keys.insert(key, false);
+                }
+            }
+        }
+        let added = batch_keys
+            .iter()
+            .filter(|(key, &set)| set && !self.index.contains_key(**key))
+            .count();
+        let removed = batch_keys
+            .iter()
+            .filter(|(key, &set)| !set && self.index.contains_key(**key))
+            .count();
+        // batch 中的 key 不能被淘汰
+        let victims = if added > removed {
+            self.pick_victims(added - removed, &batch_keys.keys().copied().collect())?
+        } else {
+            Vec::new()
+        };
+
+        // 淘汰和 batch 写在同一条 record 中，一起写入或一起失败
+        let cmds = victims
+            .iter()
+            .cloned()
+            .map(Command::remove)
+            .chain(ops.into_iter().map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(key, value),
+                BatchOp::Remove { key } => Command::remove(key),
+            }))
+            .collect();
+        let res = self.write_commands(cmds);
+        if res.is_err() {
+            self.restore_victims(&victims);
+        }
+        res
+    }
+
+    /// Writes already validated commands to the log with a single flush, then
+    /// applies them to the index in order.
+    ///
+    /// Several commands are written as one `Command::Batch` record, so a crash
+    /// keeps either all or none of them.
+    fn write_commands(&mut self, cmds: Vec<Command>) -> Result<()> {
+        // 所有 command 先序列化到内存中，记录每个 command 的相对位置
+        let mut records = Vec::with_capacity(cmds.len());
+        // 一条 record，一个 LSN
+        let lsn = self.next_lsn();
+        let buf = if cmds.len() > 1 {
+            // 每个 command 的位置指向 Batch record 中它自己的那一段
+            let offsets = self.log_encoding.batch_offsets(&cmds)?;
+            let batch = Command::Batch(cmds);
+            let buf = encode_record(&batch, self.log_encoding, false, lsn)?;
+            let Command::Batch(cmds) = batch else {
+                unreachable!()
+            };
+            let payload_offset = payload_offset(self.log_encoding, lsn);
+            for (cmd, (offset, len)) in cmds.into_iter().zip(offsets) {
+                let start = payload_offset + offset;
+                records.push((cmd, start, start + len, true));
+            }
+            buf
+        } else {
+            let mut buf = Vec::new();
+            for cmd in cmds {
+                let start = buf.len() as u64;
+                buf.extend_from_slice(&encode_record(
+                    &cmd,
+                    self.log_encoding,
+                    self.compress_records,
+                    lsn,
+                )?);
+                records.push((cmd, start, buf.len() as u64, false));
+            }
+            buf
+        };
+
+        // 一次写入 + 一次 flush
+        let (base, _) = self.write_log(&buf)?;
+
+        // flush 成功之后才更新 index
+        for (cmd, start, end, in_batch) in records {
+            self.record_key(cmd.key());
+            match cmd {
+                Command::Remove { key } => {
+                    if let Some(old_cmd) = self.index.remove(&key) {
+                        self.uncompacted += old_cmd.length;
+                    }
+                    self.forget_key(&key);
+                    // the "remove" command itself can be deleted in the next compaction.
+                    self.uncompacted += end - start;
+                }
+                cmd => {
+                    let cmd_pos = CommandPos::new(self.current_gen, base + start, base + end)
+                        .with_expire_at(cmd.expire_at())
+                        .with_in_batch(in_batch)
+                        .with_lsn(lsn);
+                    self.touch_key(cmd.key());
+                    if let Some(old_cmd) = self.index.insert(cmd.into_key(), cmd_pos) {
+                        self.uncompacted += old_cmd.length;
+                    }
+                }
+            }
+        }
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.cached_value(&key)? {
+            self.touch_key(&key);
+            return Ok(Some(value));
+        }
+        let value = match self.read_command(&key)? {
+            Some(Command::Set { value, .. })
+            | Some(Command::SetEx { value, .. })
+            | Some(Command::SetIdempotent { value, .. }) => value,
+            Some(Command::SetBytes { value, .. }) => String::from_utf8(value)?,
+            Some(Command::Remove { .. })
+            | Some(Command::Clear)
+            | Some(Command::Batch(_))
+            | Some(Command::BlobRef { .. }) => return Err(KvsError::UnexpectedCommandType),
+            None => return Ok(None),
+        };
+        self.touch_key(&key);
+        if let Some(cache) = self.cache.as_mut() {
+            let cmd_pos = &self.index[&key];
+            cache.insert(key, cmd_pos.gen, cmd_pos.start, value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    /// Looks `key` up for a read outside the lock, returning where to read its
+    /// value unless the value is known already.
+    fn lookup(&mut self, key: &str) -> Result<Lookup> {
+        let use_mmap = self
+            .index
+            .get(key)
+            .and_then(|cmd_pos| self.readers.get(&cmd_pos.gen))
+            .is_some_and(LogReader::uses_mmap);
+        if use_mmap {
+            return Ok(Lookup::Done(self.get(key.to_owned())?));
+        }
+        if let Some(value) = self.cached_value(key)? {
+            self.touch_key(key);
+            return Ok(Lookup::Done(Some(value)));
+        }
+        validate_key(key, self.max_key_bytes)?;
+        // 锁外的 reader 只能读到已经写到文件中的 record
+        self.flush_buffered()?;
+        self.drop_if_expired(key);
+        if self.index.contains_key(key) {
+            self.touch_key(key);
+        }
+        match self.index.get(key) {
+            Some(cmd_pos) => Ok(Lookup::Read {
+                encoding: self.readers.reader(cmd_pos.gen)?.encoding,
+                cmd_pos: cmd_pos.clone(),
+                cache: self.cache.is_some(),
+            }),
+            None => Ok(Lookup::Done(None)),
+        }
+    }
+
+    /// Caches `value`, read outside the lock from the record at `cmd_pos`.
+    fn cache_value(&mut self, key: String, cmd_pos: &CommandPos, value: String) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.insert(key, cmd_pos.gen, cmd_pos.start, value);
+        }
+    }
+
+    /// Returns the cached value of `key`, if it was read from the record the index
+    /// points at.
+    fn cached_value(&mut self, key: &str) -> Result<Option<String>> {
+        if self.cache.is_none() {
+            return Ok(None);
+        }
+        validate_key(key, self.max_key_bytes)?;
+        self.drop_if_expired(key);
+        match (self.cache.as_mut(), self.index.get(key)) {
+            (Some(cache), Some(cmd_pos)) => Ok(cache.get(key, cmd_pos.gen, cmd_pos.start)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_as_of(&mut self, key: String, lsn: u64) -> Result<Option<String>> {
+        validate_key(&key, self.max_key_bytes)?;
+        self.flush_buffered()?;
+        let mut gen_list: Vec<u64> = self.readers.keys().cloned().collect();
+        gen_list.sort_unstable();
+        // LSN 不超过 `lsn` 的最后一个 record，compaction 之后 log 中的 LSN 不是按顺序的
+        let mut found: Option<(u64, Option<Command>)> = None;
+        for gen in gen_list {
+            let reader = self.readers.reader(gen)?;
+            replay(gen, reader, &self.path, true, |cmd, cmd_pos| {
+                if cmd_pos.lsn > lsn
+                    || matches!(&found, Some((found_lsn, _)) if *found_lsn > cmd_pos.lsn)
+                {
+                    return;
+                }
+                match cmd {
+                    Command::Clear => found = Some((cmd_pos.lsn, None)),
+                    Command::Remove { key: removed } if removed == key => {
+                        found = Some((cmd_pos.lsn, None))
+                    }
+                    Command::Remove { .. } | Command::Batch(_) => {}
+                    cmd if cmd.key() == key => found = Some((cmd_pos.lsn, Some(cmd))),
+                    _ => {}
+                }
+            })?;
+        }
+        let cmd = match found {
+            Some((_, Some(cmd))) => resolve_blob(&self.path, cmd)?,
+            _ => return Ok(None),
+        };
+        match cmd {
+            Command::Set { value, .. }
+            | Command::SetEx { value, .. }
+            | Command::SetIdempotent { value, .. } => Ok(Some(value)),
+            Command::SetBytes { value, .. } => Ok(Some(String::from_utf8(value)?)),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    fn get_with_meta(&mut self, key: String) -> Result<Option<(String, EntryMeta)>> {
+        let value = match self.get(key.clone())? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let cmd_pos = &self.index[&key];
+        let meta = EntryMeta {
+            gen: cmd_pos.gen,
+            start: cmd_pos.start,
+            length: cmd_pos.length,
+        };
+        Ok(Some((value, meta)))
+    }
+
+    fn get_reader(&mut self, key: String) -> Result<Option<ValueReader>> {
+        validate_key(&key, self.max_key_bytes)?;
+        self.flush_buffered()?;
+        self.drop_if_expired(&key);
+        match self.index.get(&key) {
+            Some(cmd_pos) => Ok(Some(open_value(&mut self.readers, &self.path, cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        match self.read_command(&key)? {
+            Some(Command::Set { value, .. })
+            | Some(Command::SetEx { value, .. })
+            | Some(Command::SetIdempotent { value, .. }) => Ok(Some(value.into_bytes())),
+            Some(Command::SetBytes { value, .. }) => Ok(Some(value)),
+            Some(Command::Remove { .. })
+            | Some(Command::Clear)
+            | Some(Command::Batch(_))
+            | Some(Command::BlobRef { .. }) => Err(KvsError::UnexpectedCommandType),
+            None => Ok(None),
+        }
+    }
+
+    fn scan(&mut self, start: Bound<String>, end: Bound<String>) -> Result<Vec<(String, String)>> {
+        self.flush_buffered()?;
+        if is_empty_range(&start, &end) {
+            return Ok(Vec::new());
+        }
+
+        let now = now_unix_ms();
+        let mut pairs = Vec::new();
+        for (key, cmd_pos) in self.index.range((start, end)) {
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            let value = match read_command(&mut self.readers, cmd_pos)? {
+                Command::Set { value, .. }
+                | Command::SetEx { value, .. }
+                | Command::SetIdempotent { value, .. } => value,
+                Command::SetBytes { value, .. } => String::from_utf8(value)?,
+                Command::Remove { .. }
+                | Command::Clear
+                | Command::Batch(_)
+                | Command::BlobRef { .. } => return Err(KvsError::UnexpectedCommandType),
+            };
+            pairs.push((key.clone(), value));
+        }
+        Ok(pairs)
+    }
+
+    fn export(&mut self, mut writer: impl Write) -> Result<u64> {
+        self.flush_buffered()?;
+        let now = now_unix_ms();
+        let mut count = 0;
+        for (key, cmd_pos) in &self.index {
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            let value = match read_command(&mut self.readers, cmd_pos)? {
+                Command::Set { value, .. }
+                | Command::SetEx { value, .. }
+                | Command::SetIdempotent { value, .. } => value,
+                Command::SetBytes { value, .. } => String::from_utf8(value)?,
+                Command::Remove { .. }
+                | Command::Clear
+                | Command::Batch(_)
+                | Command::BlobRef { .. } => return Err(KvsError::UnexpectedCommandType),
+            };
+            let entry = SnapshotEntry {
+                key: key.clone(),
+                value,
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    fn import(&mut self, reader: impl Read) -> Result<u64> {
+        let mut count = 0;
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: SnapshotEntry = serde_json::from_str(&line)?;
+            self.set(entry.key, entry.value)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn snapshot_index(&mut self) -> Result<BTreeMap<String, CommandPos>> {
+        // 读取 value 之前，buffer 中的 record 必须已经写到文件中
+        self.flush_buffered()?;
+        let now = now_unix_ms();
+        Ok(self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos.clone()))
+            .collect())
+    }
+
+    fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        // 所有以 prefix 开头的 key 在有序的 index 中是连续的一段
+        let now = now_unix_ms();
+        self.index
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Reads the command the index points at for `key`, `None` if the key is absent.
+    ///
+    /// An expired key is dropped from the index and its space counted as `uncompacted`.
+    fn read_command(&mut self, key: &str) -> Result<Option<Command>> {
+        validate_key(key, self.max_key_bytes)?;
+        self.flush_buffered()?;
+        self.drop_if_expired(key);
+        match self.index.get(key) {
+            Some(cmd_pos) => Ok(Some(read_command(&mut self.readers, cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            // 删除一个不存在的 key 不需要写 log
+            None if current.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    fn contains_key(&mut self, key: &str) -> Result<bool> {
+        validate_key(key, self.max_key_bytes)?;
+        self.drop_if_expired(key);
+        Ok(self.index.contains_key(key))
+    }
+
+    fn sweep_expired(&mut self) -> Result<usize> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let now = now_unix_ms();
+        let expired: Vec<_> = self
+            .index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+            .map(|(key, _)| Command::remove(key.clone()))
+            .collect();
+        let count = expired.len();
+        if count > 0 {
+            self.write_commands(expired)?;
+        }
+        Ok(count)
+    }
+
+    /// Drops `key` from the index if its value has expired.
+    fn drop_if_expired(&mut self, key: &str) {
+        if matches!(self.index.get(key), Some(cmd_pos) if cmd_pos.is_expired(now_unix_ms())) {
+            let old_cmd = self.index.remove(key).expect("expired key not found");
+            self.uncompacted += old_cmd.length;
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        validate_key(&key, self.max_key_bytes)?;
+        self.drop_if_expired(&key);
+        if self.index.contains_key(&key) {
+            let cmd = Command::remove(key);
+            let lsn = self.next_lsn();
+            self.write_log(&encode_record(
+                &cmd,
+                self.log_encoding,
+                self.compress_records,
+                lsn,
+            )?)?;
+            self.record_key(cmd.key());
+
+            if let Command::Remove { key } = cmd {
+                // key 在之前的 if 已经判断为存在，这里 remove 一定会返回 Some，否则可以直接 panic
+                let old_cmd = self.index.remove(&key).expect("remove key not found");
+                self.uncompacted += old_cmd.length;
+                self.forget_key(&key);
+            }
+
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = self.get(key.clone())?;
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        if self.contains_key(&key)? {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    fn rename(&mut self, from: String, to: String) -> Result<()> {
+        validate_key(&to, self.max_key_bytes)?;
+        let cmd = self.read_command(&from)?.ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        // 新 key 的 set 和旧 key 的 remove 一起写入，index 一次更新
+        self.write_commands(vec![cmd.with_key(to), Command::remove(from)])
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        // 进行中的 compaction 会把清空的数据 copy 回来，直接放弃
+        self.compaction = None;
+
+        // Clear 写在新的 generation 中并落盘，删除旧的 log 时 crash 的话 load 也会把它们清空
+        self.flush_buffered()?;
+        self.current_gen += 1;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+        self.blooms.insert(
+            self.current_gen,
+            BloomFilter::new(self.bloom_false_positive_rate),
+        );
+        let lsn = self.next_lsn();
+        let (start, end) = self.write_log(&encode_record(
+            &Command::Clear,
+            self.log_encoding,
+            self.compress_records,
+            lsn,
+        )?)?;
+        self.flush_buffered()?;
+        if let Some(writer) = self.writer.as_mut() {
+            writer.sync()?;
+        }
+
+        let stale_gen_list: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&gen| gen < self.current_gen)
+            .cloned()
+            .collect();
+        if let Some(pool) = &self.reader_pool {
+            pool.retire(&stale_gen_list);
+        }
+        for stale_gen in stale_gen_list {
+            self.readers.remove(&stale_gen);
+            fs::remove_file(log_path(&self.path, stale_gen))?;
+            self.blooms.remove(&stale_gen);
+            remove_bloom_file(&self.path, stale_gen)?;
+            remove_blob_file(&self.path, stale_gen)?;
+        }
+        self.blob_writer = None;
+        self.overwrite_file = None;
+        self.index.clear();
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+        if let Some(recency) = self.recency.as_mut() {
+            recency.clear();
+        }
+        // 只剩下 Clear 自己是 stale 的
+        self.uncompacted = end - start;
+        self.tier_bytes = None;
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<CompactionStats> {
+        self.compact_with(true)
+    }
+
+    /// Runs a compaction to the end, of every generation if `full`, otherwise
+    /// of those picked by the compaction strategy.
+    fn compact_with(&mut self, full: bool) -> Result<CompactionStats> {
+        loop {
+            if let Some(stats) = self.compact_step_with(u64::MAX, full)? {
+                return Ok(stats);
+            }
+        }
+    }
+
+    /// Compacts once `uncompacted` exceeds the compaction threshold, there are
+    /// more than `max_generations` or the small generations of
+    /// `CompactionStrategy::SizeTiered` add up to its threshold, or wakes the
+    /// background compaction thread.
+    fn maybe_compact(&mut self) -> Result<()> {
+        if self.uncompacted <= self.compaction_threshold
+            && !self.too_many_generations()
+            && !self.tier_full()?
+        {
+            return Ok(());
+        }
+        match &self.compaction_trigger {
+            // 已经唤醒过的话 channel 是满的，不需要再唤醒
+            Some(trigger) => {
+                let _ = trigger.try_send(());
+            }
+            None => {
+                self.compact_with(false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the current generation and the small generations before it add
+    /// up to the threshold of `CompactionStrategy::SizeTiered`.
+    fn tier_full(&mut self) -> Result<bool> {
+        let threshold = match self.compaction_strategy {
+            CompactionStrategy::Full => return Ok(false),
+            CompactionStrategy::SizeTiered { threshold } => threshold,
+        };
+        let older = match self.tier_bytes {
+            Some(bytes) => bytes,
+            None => {
+                let (_, bytes) = self.small_generations(threshold)?;
+                *self.tier_bytes.insert(bytes)
+            }
+        };
+        let current = self.writer.as_ref().map_or(0, |writer| writer.pos);
+        Ok(older + current >= threshold)
+    }
+
+    /// Returns the oldest of the generations older than the current one and
+    /// newer than any generation of at least half `threshold` bytes, the current
+    /// generation if there is none, and their total size.
+    fn small_generations(&self, threshold: u64) -> Result<(u64, u64)> {
+        let mut gens: Vec<u64> = self.readers.keys().cloned().collect();
+        gens.sort_unstable();
+        let mut floor = self.current_gen;
+        let mut bytes = 0;
+        for gen in gens.into_iter().rev().filter(|&gen| gen < self.current_gen) {
+            let len = fs::metadata(log_path(&self.path, gen))?.len();
+            if len >= threshold / 2 {
+                break;
+            }
+            floor = gen;
+            bytes += len;
+        }
+        Ok((floor, bytes))
+    }
+
+    /// The oldest generation the next compaction merges if not `full`: every
+    /// generation past the compaction threshold or `max_generations`, otherwise
+    /// only the small generations of `CompactionStrategy::SizeTiered`.
+    fn compaction_floor(&self, full: bool) -> Result<u64> {
+        match self.compaction_strategy {
+            CompactionStrategy::SizeTiered { threshold }
+                if !full
+                    && self.uncompacted <= self.compaction_threshold
+                    && !self.too_many_generations() =>
+            {
+                Ok(self.small_generations(threshold)?.0)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// Whether there are more generations than `max_generations`.
+    fn too_many_generations(&self) -> bool {
+        matches!(self.max_generations, Some(max) if self.readers.len() as u64 > max)
+    }
+
+    /// Copies at least one and about `max_bytes` of live records into the
+    /// compaction log, starting a compaction if none is in progress.
+    ///
+    /// Returns the statistics of the compaction once it is finished.
+    fn compact_step(&mut self, max_bytes: u64) -> Result<Option<CompactionStats>> {
+        self.compact_step_with(max_bytes, true)
+    }
+
+    /// Like `compact_step`, a compaction started by it merges every generation
+    /// if `full`, otherwise those picked by the compaction strategy.
+    fn compact_step_with(&mut self, max_bytes: u64, full: bool) -> Result<Option<CompactionStats>> {
+        self.flush_buffered()?;
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        let mut progress = match self.compaction.take() {
+            Some(progress) => progress,
+            None => {
+                let floor = self.compaction_floor(full)?;
+                // 没有 stale 的数据，generation 也没有超过上限，不需要 compaction
+                if floor == 0 && self.uncompacted == 0 && !self.too_many_generations() {
+                    let bytes = self.log_size()?;
+                    return Ok(Some(CompactionStats {
+                        bytes_before: bytes,
+                        bytes_after: bytes,
+                        entries_retained: self.index.len() as u64,
+                        files_removed: 0,
+                    }));
+                }
+                self.start_compaction(floor)?
+            }
+        };
+
+        let now = now_unix_ms();
+        let mut written = 0;
+        let mut expired = Vec::new();
+        let mut finished = true;
+        let resume_from = match progress.last_key.take() {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        // 从上一次停下的 key 开始，按 key 的顺序遍历 in-memory index
+        for (key, active_cmd) in self.index.range_mut((resume_from, Bound::Unbounded)) {
+            if written > 0 && written >= max_bytes {
+                finished = false;
+                break;
+            }
+            progress.last_key = Some(key.clone());
+            // compaction 开始之后写入的 value 在更新的 generation 中，不需要移动；
+            // 比 floor 更老的 generation 不参与这次 compaction
+            if active_cmd.gen >= progress.gen || active_cmd.gen < progress.floor {
+                continue;
+            }
+            // 过期的 key 不再写入 compaction log，直接丢弃
+            if active_cmd.is_expired(now) {
+                expired.push(key.clone());
+                continue;
+            }
+
+            // 根据 gen 拿到对应的 reader
+            let log_reader = self.readers.reader(active_cmd.gen)?;
+            let compaction_writer = &mut progress.writer;
+            // compaction log 写在 header byte 之后
+            let start = compaction_writer.pos;
+            if active_cmd.in_blob {
+                // value 移动到 compaction generation 的 blob 文件中，旧的 blob 文件随 log 一起删除
+                let cmd = log_reader.read_command(active_cmd)?;
+                let value = cmd.blob_value().ok_or(KvsError::UnexpectedCommandType)?;
+                let blob_writer = match &mut progress.blob_writer {
+                    Some(writer) => writer,
+                    blob_writer => blob_writer.insert(open_blob_file(
+                        &self.path,
+                        progress.gen,
+                        self.buffer_size,
+                    )?),
+                };
+                let blob_ref = Command::BlobRef {
+                    key: key.clone(),
+                    blob: progress.gen,
+                    offset: write_blob(blob_writer, value)?,
+                    length: value.len() as u64,
+                };
+                written += value.len() as u64;
+                compaction_writer.write_all(&encode_record(
+                    &blob_ref,
+                    self.log_encoding,
+                    false,
+                    active_cmd.lsn,
+                )?)?;
+            // Batch 中的 command 没有自己的 frame，需要重新编码成单独的 record
+            } else if log_reader.encoding == Some(self.log_encoding) && !active_cmd.in_batch {
+                let reader = log_reader.file()?;
+                // 读取 log 中对应的 Command
+                // 判断当前 reader 的游标位置，读取对应的 Command 是否需要移动游标
+                if active_cmd.start != reader.pos {
+                    // 需要移动移动游标
+                    reader.seek(SeekFrom::Start(active_cmd.start))?;
+                }
+                let mut entry_reader = reader.take(active_cmd.length);
+                // 将对应 reader 中的内容，copy 到 compaction_reader 中来
+                io::copy(&mut entry_reader, compaction_writer)?;
+            } else {
+                // 旧格式或者其它 encoding 的 log，需要重新编码
+                let cmd = log_reader.read_command(active_cmd)?;
+                compaction_writer.write_all(&encode_record(
+                    &cmd,
+                    self.log_encoding,
+                    self.compress_records,
+                    active_cmd.lsn,
+                )?)?;
+            }
+            progress.bloom.insert(key);
+            progress.copied += active_cmd.length;
+            progress.max_lsn = progress.max_lsn.max(active_cmd.lsn);
+            written += compaction_writer.pos - start;
+
+            // 更新 in-memory index 中 CommandPos 对应的信息
+            *active_cmd = CommandPos::new(progress.gen, start, compaction_writer.pos)
+                .with_expire_at(active_cmd.expire_at)
+                .with_in_blob(active_cmd.in_blob)
+                .with_lsn(active_cmd.lsn);
+        }
+        for key in expired {
+            let old_cmd = self.index.remove(&key).expect("expired key not found");
+            self.uncompacted += old_cmd.length;
+        }
+        // index 已经指向 compaction log，reader 需要能读到
+        progress.writer.flush()?;
+
+        if finished {
+            self.finish_compaction(progress).map(Some)
+        } else {
+            self.compaction = Some(progress);
+            Ok(None)
+        }
+    }
+
+    /// Starts a compaction: later writes go to a new generation, and the live
+    /// records of the older generations from `floor` on are copied into the
+    /// compaction log.
+    fn start_compaction(&mut self, floor: u64) -> Result<CompactionProgress> {
+        let bytes_before = self.log_size()?;
+        // 参与 compaction 的 log 中（除了 header byte）所有的数据在 compaction 之后都会被删除
+        let mut old_bytes = 0;
+        for (&gen, reader) in self.readers.iter().filter(|(&gen, _)| gen >= floor) {
+            let header_len = if reader.encoding.is_some() { 1 } else { 0 };
+            old_bytes += fs::metadata(log_path(&self.path, gen))?
+                .len()
+                .saturating_sub(header_len);
+        }
+
+        // compaction generateion
+        let compaction_gen = self.current_gen + 1;
+
+        // current generation number +2, +1 for compaction
+        self.current_gen += 2;
+        self.tier_bytes = None;
+        self.writer = Some(self.new_log_file(self.current_gen)?);
+        // 旧的 current log 会被 compact 掉
+        self.overwrite_file = None;
+        self.blooms.insert(
+            self.current_gen,
+            BloomFilter::new(self.bloom_false_positive_rate),
+        );
+
+        Ok(CompactionProgress {
+            gen: compaction_gen,
+            writer: self.new_log_file(compaction_gen)?,
+            blob_writer: None,
+            bloom: BloomFilter::with_capacity(
+                self.index.len() as u64,
+                self.bloom_false_positive_rate,
+            ),
+            last_key: None,
+            bytes_before,
+            old_bytes,
+            copied: 0,
+            max_lsn: 0,
+            floor,
+        })
+    }
+
+    /// Removes the logs older than the finished compaction log of `progress`.
+    fn finish_compaction(&mut self, progress: CompactionProgress) -> Result<CompactionStats> {
+        let CompactionProgress {
+            gen: compaction_gen,
+            mut writer,
+            blob_writer,
+            mut bloom,
+            bytes_before,
+            old_bytes,
+            copied,
+            mut max_lsn,
+            floor,
+            ..
+        } = progress;
+        // 留下的 generation 中可能还有被删除的 key 的 record，写一个 Remove 盖住它
+        let mut tombstone_bytes = 0;
+        for (key, lsn) in self.tombstones(floor, compaction_gen)? {
+            bloom.insert(&key);
+            let record = encode_record(&Command::remove(key), self.log_encoding, false, lsn)?;
+            writer.write_all(&record)?;
+            tombstone_bytes += record.len() as u64;
+            max_lsn = max_lsn.max(lsn);
+        }
+        // 最大的 LSN 在被丢弃的 record 中、之后也还没有写入时，写一个空的 Batch 记下它，
+        // 重新打开之后 LSN 不会倒退
+        let current_log_empty = matches!(&self.writer, Some(writer) if writer.pos <= 1);
+        if max_lsn < self.lsn && current_log_empty {
+            writer.write_all(&encode_record(
+                &Command::Batch(Vec::new()),
+                self.log_encoding,
+                false,
+                self.lsn,
+            )?)?;
+        }
+        // 删除旧的 log 之前，确保 compaction log 已经落盘
+        writer.flush()?;
+        if self.sync_policy != SyncPolicy::Never {
+            writer.sync()?;
+            if let Some(mut blob_writer) = blob_writer {
+                blob_writer.sync()?;
+            }
+        }
+        bloom.save(&bloom_path(&self.path, compaction_gen))?;
+        self.blooms.insert(compaction_gen, bloom);
+
+        // 释放 stale 的空间
+        let stale_gen_list: Vec<_> = self
+            .readers
+            .keys()
+            .filter(|&&gen| gen >= floor && gen < compaction_gen)
+            .cloned()
+            .collect();
+        let files_removed = stale_gen_list.len() as u64;
+        if let Some(pool) = &self.reader_pool {
+            pool.retire(&stale_gen_list);
+        }
+        for stale_gen in stale_gen_list {
+            // 将 log 文件对应的 reader 释放掉
+            self.readers.remove(&stale_gen);
+
+            // 将 log file 也给释放掉
+            fs::remove_file(log_path(&self.path, stale_gen))?;
+            self.blooms.remove(&stale_gen);
+            remove_bloom_file(&self.path, stale_gen)?;
+            remove_blob_file(&self.path, stale_gen)?;
+        }
+        if matches!(&self.blob_writer, Some((gen, _)) if *gen < compaction_gen) {
+            self.blob_writer = None;
+        }
+
+        // 旧的 log 中没有被 copy 的部分都是 stale 的，已经被释放
+        self.uncompacted = self.uncompacted.saturating_sub(old_bytes - copied) + tombstone_bytes;
+        self.tier_bytes = None;
+
+        Ok(CompactionStats {
+            bytes_before,
+            bytes_after: self.log_size()?,
+            entries_retained: self.index.len() as u64,
+            files_removed,
+        })
+    }
+
+    /// Returns the keys with a record in the generations from `floor` to below
+    /// `gen` but none in the index, that an older generation may still have a
+    /// record of, with the highest LSN of their records.
+    fn tombstones(&self, floor: u64, gen: u64) -> Result<BTreeMap<String, u64>> {
+        let mut keys = BTreeMap::new();
+        let older: Vec<u64> = self
+            .readers
+            .keys()
+            .filter(|&&g| g < floor)
+            .cloned()
+            .collect();
+        if older.is_empty() {
+            return Ok(keys);
+        }
+        let mut merged: Vec<u64> = self
+            .readers
+            .keys()
+            .filter(|&&g| g >= floor && g < gen)
+            .cloned()
+            .collect();
+        merged.sort_unstable();
+        for merged_gen in merged {
+            let mut reader = LogReader::open(&log_path(&self.path, merged_gen), self.buffer_size)?;
+            replay(merged_gen, &mut reader, &self.path, true, |cmd, cmd_pos| {
+                let key = cmd.into_key();
+                if !key.is_empty() {
+                    let lsn = keys.entry(key).or_insert(0);
+                    *lsn = cmd_pos.lsn.max(*lsn);
+                }
+            })?;
+        }
+        // 没有 bloom filter 的 generation 可能有任何 key
+        keys.retain(|key, _| {
+            !self.index.contains_key(key)
+                && older.iter().any(|g| {
+                    self.blooms
+                        .get(g)
+                        .is_none_or(|bloom| bloom.may_contain(key))
+                })
+        });
+        Ok(keys)
+    }
+
+    fn verify(&mut self) -> Result<VerifyReport> {
+        self.flush_buffered()?;
+        let mut report = VerifyReport::default();
+        for (key, cmd_pos) in &self.index {
+            report.checked += 1;
+            let problem = if self.readers.contains(cmd_pos.gen) {
+                self.readers.reader(cmd_pos.gen)?.verify(key, cmd_pos)?
+            } else {
+                Some(VerifyProblemKind::Dangling)
+            };
+            if let Some(kind) = problem {
+                report.problems.push(VerifyProblem {
+                    key: key.clone(),
+                    gen: cmd_pos.gen,
+                    offset: cmd_pos.start,
+                    kind,
+                });
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns the total size in bytes of all log files of the store.
+    fn log_size(&self) -> Result<u64> {
+        let mut size = 0;
+        for &gen in self.readers.keys() {
+            size += fs::metadata(log_path(&self.path, gen))?.len();
+        }
+        Ok(size)
+    }
+
+    /// Appends `buf` to the current log, flushes it to the OS and syncs it according
+    /// to the sync policy. Returns the start and end position of `buf` in the log.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ReadOnly` if the store is opened read-only.
+    fn write_log(&mut self, buf: &[u8]) -> Result<(u64, u64)> {
+        let pos = self.append_log(buf)?;
+        self.ship(buf);
+        Ok(pos)
+    }
+
+    /// Like `write_log`, without sending `buf` to the subscribed replicas.
+    fn append_log(&mut self, buf: &[u8]) -> Result<(u64, u64)> {
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let start = writer.pos;
+        writer.write_all(buf)?;
+        let need_sync = match self.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if need_sync {
+            writer.sync()?;
+            self.last_sync = Instant::now();
+            self.unflushed = false;
+        } else if self.buffered_writes {
+            self.unflushed = true;
+        } else {
+            writer.flush()?;
+        }
+        let end = writer.pos;
+        Ok((start, end))
+    }
+
+    /// Sends the records of `buf`, just written to the log, to the subscribed
+    /// replicas, prefixed with the header byte of their encoding.
+    fn ship(&mut self, buf: &[u8]) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let mut record = Vec::with_capacity(1 + buf.len());
+        record.push(self.log_encoding.header());
+        record.extend_from_slice(buf);
+        // 断开的 replica 不再发送
+        self.subscribers
+            .retain(|subscriber| subscriber.send(record.clone()).is_ok());
+    }
+
+    /// Applies a record shipped by the primary, made by `ship`.
+    fn apply_replicated(&mut self, record: &[u8]) -> Result<()> {
+        let corrupt = || KvsError::StringError("corrupt replicated record".to_owned());
+        let (&header, mut frames) = record.split_first().ok_or_else(corrupt)?;
+        let encoding = LogEncoding::from_header(header).ok_or_else(corrupt)?;
+        if encoding == LogEncoding::JsonLines {
+            for line in frames.split_inclusive(|&b| b == b'\n') {
+                let (cmd, _) = decode_line(line).ok_or_else(corrupt)?;
+                self.apply_replicated_command(cmd)?;
+            }
+            return Ok(());
+        }
+        while !frames.is_empty() {
+            if frames.len() < FRAME_HEADER_LEN as usize {
+                return Err(corrupt());
+            }
+            let len = (FRAME_HEADER_LEN + payload_len(frames)) as usize;
+            let (frame, rest) = frames.split_at(len.min(frames.len()));
+            let payload = frame_payload(frame)?.ok_or_else(corrupt)?;
+            self.apply_replicated_command(encoding.decode(&payload)?)?;
+            frames = rest;
+        }
+        Ok(())
+    }
+
+    fn apply_replicated_command(&mut self, cmd: Command) -> Result<()> {
+        // primary 已经校验过，直接写入自己的 log
+        match cmd {
+            Command::Clear => self.clear(),
+            Command::Batch(cmds) => self.write_commands(cmds),
+            cmd => self.write_commands(vec![cmd]),
+        }
+    }
+
+    /// Returns the LSN of the next record to write.
+    fn next_lsn(&mut self) -> u64 {
+        self.lsn += 1;
+        self.lsn
+    }
+
+    /// Adds `key` to the bloom filter of the current generation and drops its cached
+    /// value, after writing a record of it.
+    fn record_key(&mut self, key: &str) {
+        if let Some(bloom) = self.blooms.get_mut(&self.current_gen) {
+            bloom.insert(key);
+        }
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(key);
+        }
+    }
+
+    /// Picks the keys to evict to make room for `added` new keys under
+    /// `max_keys`, the least recently used keys other than those of `keep`, or
+    /// fails with `KvsError::Capacity`, depending on the eviction policy.
+    ///
+    /// The keys are taken out of the recency order; the caller writes their
+    /// removal, or gives them back with `restore_victims` if its write fails.
+    fn pick_victims(&mut self, added: usize, keep: &HashSet<&str>) -> Result<Vec<String>> {
+        let max_keys = match self.max_keys {
+            Some(max_keys) => max_keys,
+            None => return Ok(Vec::new()),
+        };
+        let excess = (self.index.len() + added).saturating_sub(max_keys);
+        if excess == 0 {
+            return Ok(Vec::new());
+        }
+        let recency = match self.recency.as_mut() {
+            Some(recency) if added <= max_keys => recency,
+            _ => return Err(KvsError::Capacity { max_keys }),
+        };
+        let mut victims = Vec::with_capacity(excess);
+        let mut kept = Vec::new();
+        while victims.len() < excess {
+            match recency.pop_oldest() {
+                Some(key) if keep.contains(key.as_str()) => kept.push(key),
+                Some(key) if self.index.contains_key(&key) => victims.push(key),
+                // 已经不在 index 中的 key，比如过期的
+                Some(_) => {}
+                None => break,
+            }
+        }
+        kept.iter().for_each(|key| recency.touch(key));
+        if victims.len() < excess {
+            victims.iter().for_each(|key| recency.touch(key));
+            return Err(KvsError::Capacity { max_keys });
+        }
+        Ok(victims)
+    }
+
+    /// Puts the keys of `pick_victims` back into the recency order, after the
+    /// write they made room for failed.
+    fn restore_victims(&mut self, victims: &[String]) {
+        victims.iter().for_each(|key| self.touch_key(key));
+    }
+
+    /// Marks `key` as just used for `EvictionPolicy::EvictLru`, only call it for
+    /// a key of the index or about to be inserted.
+    fn touch_key(&mut self, key: &str) {
+        if let Some(recency) = self.recency.as_mut() {
+            recency.touch(key);
+        }
+    }
+
+    fn forget_key(&mut self, key: &str) {
+        if let Some(recency) = self.recency.as_mut() {
+            recency.forget(key);
+        }
+    }
+
+    /// Checks a value of `len` bytes against `max_value_bytes`.
+    fn check_value_len(&self, len: usize) -> Result<()> {
+        match self.max_value_bytes {
+            Some(limit) if len as u64 > limit => Err(KvsError::ValueTooLarge {
+                size: len as u64,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes the buffered writes, so the readers can see them.
+    fn flush_buffered(&mut self) -> Result<()> {
+        if self.unflushed {
+            if let Some(writer) = self.writer.as_mut() {
+                writer.flush()?;
+            }
+            self.unflushed = false;
+        }
+        Ok(())
+    }
+
+    fn new_log_file(&mut self, gen: u64) -> Result<BufferWriterWithPos<File>> {
+        let writer = new_log_file(
+            &self.path,
+            gen,
+            self.log_encoding,
+            self.buffer_size,
+            &mut self.readers,
+        )?;
+        #[cfg(feature = "mmap")]
+        if let Some(reader) = self.readers.get_mut(&gen) {
+            reader.use_mmap = self.mmap_reads;
+        }
+        Ok(writer)
+    }
+}
+
+impl Drop for KvStoreInner {
+    fn drop(&mut self) {
+        // BufWriter 的 drop 会忽略 flush 的错误，这里先 flush，至少记录下来
+        if let Some(writer) = self.writer.as_mut() {
+            let res = match self.sync_policy {
+                SyncPolicy::Never => writer.flush(),
+                _ => writer.sync(),
+            };
+            if let Err(e) = res {
+                error!("error on flushing the log of {:?}, {}", self.path, e);
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Set the value of a string key to a string
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock().set(key, value)
+    }
+
+    /// Get the string value of the a string key.
+    ///
+    /// If the key does not exist, return `None`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let pool = match &self.reader_pool {
+            Some(pool) => pool,
+            None => return self.lock().get(key),
+        };
+        let (encoding, cmd_pos, cache) = match self.lock().lookup(&key)? {
+            Lookup::Done(value) => return Ok(value),
+            Lookup::Read {
+                encoding,
+                cmd_pos,
+                cache,
+            } => (encoding, cmd_pos, cache),
+        };
+        let value = match pool.read(encoding, &cmd_pos) {
+            Ok(Command::Set { value, .. })
+            | Ok(Command::SetEx { value, .. })
+            | Ok(Command::SetIdempotent { value, .. }) => value,
+            Ok(Command::SetBytes { value, .. }) => String::from_utf8(value)?,
+            Ok(Command::Remove { .. })
+            | Ok(Command::Clear)
+            | Ok(Command::Batch(_))
+            | Ok(Command::BlobRef { .. }) => return Err(KvsError::UnexpectedCommandType),
+            // 在 lookup 之后 log 被 compaction 删除了，在锁内重新读取
+            Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                return self.lock().get(key)
+            }
+            Err(e) => return Err(e),
+        };
+        if cache {
+            self.lock().cache_value(key, &cmd_pos, value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    /// Set the value of a string key and return its previous value, or `None`
+    /// if it did not exist.
+    ///
+    /// The read and the write happen under one lock, so concurrent callers
+    /// can't interleave.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        self.lock().get_set(key, value)
+    }
+
+    /// Set the value of a string key only if it does not exist yet, and return
+    /// whether it was written.
+    ///
+    /// The check against the index and the write happen under one lock, an
+    /// expired key counts as absent.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.lock().set_if_absent(key, value)
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+    fn remove(&self, key: String) -> Result<()> {
+        self.lock().remove(key)
+    }
+
+    /// Removes all keys.
+    ///
+    /// A `Clear` record is written to a new log and synced, then all older logs
+    /// are deleted. If the store crashes before all are deleted, replaying the
+    /// `Clear` on the next open drops what is left. A compaction in progress is
+    /// abandoned.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ReadOnly` on a store opened read-only, and
+    /// propagates I/O errors during writing or deleting the logs.
+    fn clear(&self) -> Result<()> {
+        self.lock().clear()
+    }
+
+    /// Moves the value of `from` to `to`, overwriting the value of `to` if it
+    /// exists.
+    ///
+    /// A set of `to` and a remove of `from` are written to the log together and
+    /// the index is only updated after the flush succeeds.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `from` is not found.
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        self.lock().rename(from, to)
+    }
+
+    /// Returns whether the given key exists.
+    ///
+    /// This only consults the in-memory index and never touches the log.
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.lock().contains_key(key)
+    }
+
+    /// Returns a receiver of every record written to the log from now on.
+    ///
+    /// The records are queued until received, so a subscriber that falls behind
+    /// holds on to them in memory. A dropped receiver is unsubscribed at the
+    /// next write.
+    fn subscribe(&self) -> Result<Receiver<Vec<u8>>> {
+        let (tx, rx) = mpsc::channel();
+        self.lock().subscribers.push(tx);
+        Ok(rx)
+    }
+}
+
+/// A store over a single log in a `Read + Write + Seek` source, opened by
+/// `KvStore::from_io`.
+///
+/// Every write is appended to the source and flushed. There is a single
+/// generation, and no compaction, so the log keeps growing. A torn record at
+/// the end of the log is dropped on open and overwritten by the next write.
+/// Values in blob files cannot be read, and logs written before the log header
+/// cannot be opened.
+pub struct IoKvStore<S: Read + Write + Seek> {
+    source: S,
+    encoding: LogEncoding,
+    // an in-memory [key -> log pointer] map, ordered by key.
+    index: BTreeMap<String, CommandPos>,
+    // where the next record is written
+    end: u64,
+    // the LSN of the last record written
+    lsn: u64,
+}
+
+impl<S: Read + Write + Seek> IoKvStore<S> {
+    fn open(mut source: S) -> Result<IoKvStore<S>> {
+        let mut len = source.seek(SeekFrom::End(0))?;
+        let encoding = if len == 0 {
+            let encoding = LogEncoding::default();
+            source.write_all(&[encoding.header()])?;
+            source.flush()?;
+            len = 1;
+            encoding
+        } else {
+            let mut header = [0u8; 1];
+            source.seek(SeekFrom::Start(0))?;
+            source.read_exact(&mut header)?;
+            LogEncoding::from_header(header[0]).ok_or_else(|| {
+                KvsError::StringError("a log without a header cannot be opened".to_owned())
+            })?
+        };
+
+        let mut gen_index = GenIndex::default();
+        let now = now_unix_ms();
+        let end = replay_records(0, &mut source, encoding, len, |cmd, cmd_pos| {
+            gen_index.apply_command(cmd, cmd_pos, now)
+        })?;
+        let lsn = gen_index.max_lsn;
+        let mut index = BTreeMap::new();
+        gen_index.merge_into(&mut index);
+        Ok(IoKvStore {
+            source,
+            encoding,
+            index,
+            end,
+            lsn,
+        })
+    }
+
+    /// Set the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        validate_key(&key, None)?;
+        let cmd = Command::set(key, value);
+        let (start, end, lsn) = self.append(&cmd)?;
+        self.index
+            .insert(cmd.into_key(), CommandPos::new(0, start, end).with_lsn(lsn));
+        Ok(())
+    }
+
+    /// Get the string value of a string key, `None` if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let cmd_pos = match self.index.get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_unix_ms()) => cmd_pos,
+            _ => return Ok(None),
+        };
+        let mut buf = vec![0u8; cmd_pos.length as usize];
+        self.source.seek(SeekFrom::Start(cmd_pos.start))?;
+        self.source.read_exact(&mut buf)?;
+        match decode_record(Some(self.encoding), &buf, cmd_pos)? {
+            Command::Set { value, .. }
+            | Command::SetEx { value, .. }
+            | Command::SetIdempotent { value, .. } => Ok(Some(value)),
+            Command::SetBytes { value, .. } => Ok(Some(String::from_utf8(value)?)),
+            Command::BlobRef { .. } => Err(KvsError::StringError(
+                "values in blob files cannot be read".to_owned(),
+            )),
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Remove a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidKey` if the key is invalid, like `set`, and
+    /// `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        validate_key(&key, None)?;
+        match self.index.get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_unix_ms()) => {}
+            _ => return Err(KvsError::KeyNotFound),
+        }
+        self.append(&Command::remove(key.clone()))?;
+        self.index.remove(&key);
+        Ok(())
+    }
+
+    /// Returns the source, with every write flushed to it.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// Appends the record of `cmd` to the log, returning its position and LSN.
+    fn append(&mut self, cmd: &Command) -> Result<(u64, u64, u64)> {
+        let lsn = self.lsn + 1;
+        let record = encode_record(cmd, self.encoding, false, lsn)?;
+        self.source.seek(SeekFrom::Start(self.end))?;
+        self.source.write_all(&record)?;
+        self.source.flush()?;
+        self.lsn = lsn;
+        let start = self.end;
+        self.end += record.len() as u64;
+        Ok((start, self.end, lsn))
+    }
+}
+
+/// Wraps the I/O error `e` on `path` in `KvsError::PathIo`.
+fn path_io(path: &Path, e: io::Error) -> KvsError {
+    KvsError::PathIo {
+        path: path.to_owned(),
+        source: e,
+    }
+}
+
+/// Fails with `KvsError::WrongEngine` if `path` holds a sled database.
+fn check_not_sled(path: &Path) -> Result<()> {
+    if is_sled_dir(path) {
+        return Err(KvsError::WrongEngine {
+            path: path.to_owned(),
+            expected: "kvs",
+            found: "sled",
+        });
+    }
+    Ok(())
+}
+
+/// Reads the command located at `cmd_pos` from the log.
+///
+/// # Errors
+///
+/// It returns `KvsError::MissingReader` if no reader is open for `cmd_pos.gen`.
+fn read_command(readers: &mut Readers, cmd_pos: &CommandPos) -> Result<Command> {
+    readers.reader(cmd_pos.gen)?.read_command(cmd_pos)
+}
+
+/// Opens a reader of the value set by the record at `cmd_pos`.
+///
+/// A value in a blob file is read from the file, limited to its length; a value
+/// in the log is decoded into memory first.
+fn open_value(readers: &mut Readers, dir: &Path, cmd_pos: &CommandPos) -> Result<ValueReader> {
+    match readers.reader(cmd_pos.gen)?.read_record(cmd_pos)? {
+        Command::BlobRef {
+            blob,
+            offset,
+            length,
+            ..
+        } => {
+            let mut file = File::open(blob_path(dir, blob))?;
+            file.seek(SeekFrom::Start(offset))?;
+            Ok(ValueReader::Blob(file.take(length)))
+        }
+        Command::Set { value, .. }
+        | Command::SetEx { value, .. }
+        | Command::SetIdempotent { value, .. } => {
+            Ok(ValueReader::Inline(Cursor::new(value.into_bytes())))
+        }
+        Command::SetBytes { value, .. } => Ok(ValueReader::Inline(Cursor::new(value))),
+        Command::Remove { .. } | Command::Clear | Command::Batch(_) => {
+            Err(KvsError::UnexpectedCommandType)
+        }
+    }
+}
+
+/// The reader `KvStore::get_reader` returns.
+enum ValueReader {
+    Blob(io::Take<File>),
+    Inline(Cursor<Vec<u8>>),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::Blob(reader) => reader.read(buf),
+            ValueReader::Inline(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Serializes `cmd` with `encoding` into a `[length][crc32][lsn][payload]` frame,
+/// with the payload compressed if `compress` and that makes it smaller, or into
+/// a line for `LogEncoding::JsonLines`.
+fn encode_record(
+    cmd: &Command,
+    encoding: LogEncoding,
+    compress: bool,
+    lsn: u64,
+) -> Result<Vec<u8>> {
+    if encoding == LogEncoding::JsonLines {
+        let mut line = line_prefix(lsn).into_bytes();
+        encoding.encode(cmd, &mut line)?;
+        line.extend_from_slice(b"}\n");
+        return Ok(line);
+    }
+    let mut payload = Vec::new();
+    encoding.encode(cmd, &mut payload)?;
+    let mut flags = LSN_FLAG;
+    if let Some(compressed) = compress.then(|| codec::compress(&payload)).flatten() {
+        payload = compressed;
+        flags |= COMPRESSED_FLAG;
+    }
+    let mut body = Vec::with_capacity(LSN_LEN as usize + payload.len());
+    body.extend_from_slice(&lsn.to_le_bytes());
+    body.extend_from_slice(&payload);
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN as usize + body.len());
+    frame.extend_from_slice(&(body.len() as u32 | flags).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Decodes the record at `cmd_pos`, read from a log in `encoding`.
+///
+/// A `None` encoding is a legacy log without frames, and a command of a batch
+/// is read without the frame of its batch.
+fn decode_record(
+    encoding: Option<LogEncoding>,
+    buf: &[u8],
+    cmd_pos: &CommandPos,
+) -> Result<Command> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(serde_json::from_slice(buf)?),
+    };
+    let corrupt = || KvsError::CorruptLog {
+        gen: cmd_pos.gen,
+        offset: cmd_pos.start,
+    };
+    let payload = if cmd_pos.in_batch {
+        Cow::Borrowed(buf)
+    } else if encoding == LogEncoding::JsonLines {
+        return decode_line(buf).map(|(cmd, _)| cmd).ok_or_else(corrupt);
+    } else {
+        frame_payload(buf)?.ok_or_else(corrupt)?
+    };
+    encoding.decode(&payload).map_err(|_| corrupt())
+}
+
+/// Returns the offset of the payload in a record stamped with `lsn`, where the
+/// commands of a batch are located from.
+fn payload_offset(encoding: LogEncoding, lsn: u64) -> u64 {
+    match encoding {
+        LogEncoding::JsonLines => line_prefix(lsn).len() as u64,
+        _ => FRAME_HEADER_LEN + LSN_LEN,
+    }
+}
+
+/// A record of a `LogEncoding::JsonLines` log.
+#[derive(Deserialize)]
+struct Line {
+    lsn: u64,
+    cmd: Command,
+}
+
+/// Returns the start of the line of a record stamped with `lsn`, before the
+/// json of its command.
+fn line_prefix(lsn: u64) -> String {
+    format!(r#"{{"lsn":{},"cmd":"#, lsn)
+}
+
+/// Decodes a line of a `LogEncoding::JsonLines` log into its command and LSN,
+/// `None` if it does not parse.
+fn decode_line(line: &[u8]) -> Option<(Command, u64)> {
+    let line: Line = serde_json::from_slice(line).ok()?;
+    Some((line.cmd, line.lsn))
+}
+
+/// Returns the length in the header of a frame, the LSN and the payload, without
+/// the flags.
+fn payload_len(header: &[u8]) -> u64 {
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    (len & !(COMPRESSED_FLAG | LSN_FLAG)) as u64
+}
+
+/// Returns the payload of a complete frame if its length and checksum match,
+/// whether it is compressed, and its LSN if it was written with one.
+fn decode_frame(frame: &[u8]) -> Option<(&[u8], bool, Option<u64>)> {
+    if frame.len() < FRAME_HEADER_LEN as usize {
+        return None;
+    }
+    let (header, body) = frame.split_at(FRAME_HEADER_LEN as usize);
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if payload_len(header) as usize != body.len() || crc32fast::hash(body) != crc {
+        return None;
+    }
+    let compressed = len & COMPRESSED_FLAG != 0;
+    if len & LSN_FLAG == 0 {
+        return Some((body, compressed, None));
+    }
+    if body.len() < LSN_LEN as usize {
+        return None;
+    }
+    let (lsn_bytes, payload) = body.split_at(LSN_LEN as usize);
+    let mut lsn = [0u8; LSN_LEN as usize];
+    lsn.copy_from_slice(lsn_bytes);
+    let lsn = u64::from_le_bytes(lsn);
+    Some((payload, compressed, Some(lsn)))
+}
+
+/// Returns the payload of a complete frame, decompressed, `None` if the frame
+/// is corrupt.
+///
+/// # Errors
+///
+/// It returns `KvsError::UnsupportedCompression` if the payload is compressed
+/// in a way this build cannot read.
+fn frame_payload(frame: &[u8]) -> Result<Option<Cow<'_, [u8]>>> {
+    match decode_frame(frame) {
+        Some((payload, false, _)) => Ok(Some(Cow::Borrowed(payload))),
+        Some((payload, true, _)) => Ok(codec::decompress(payload)?.map(Cow::Owned)),
+        None => Ok(None),
+    }
+}
+
+/// Returns `key` prefixed with the namespace `ns`.
+fn namespaced(ns: &str, key: &str) -> Result<String> {
+    if ns.is_empty() || ns.contains(NAMESPACE_SEPARATOR) {
+        return Err(KvsError::InvalidNamespace(ns.to_owned()));
+    }
+    Ok(format!("{}{}{}", ns, NAMESPACE_SEPARATOR, key))
+}
+
+/// Returns whether `(start, end)` selects no key at all.
+///
+/// `BTreeMap::range` panics on such ranges, so they are filtered out up-front.
+fn is_empty_range(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+/// Runs a compaction, step by step, every time `triggered` receives.
+///
+/// The lock of the store is released between the steps, and as the readers are
+/// only used with the lock held, no `get` reads a log while it is removed.
+fn background_compaction(inner: Weak<Mutex<KvStoreInner>>, triggered: Receiver<()>) {
+    // 所有的 KvStore 都 drop 之后，sender 也被 drop，线程退出
+    while triggered.recv().is_ok() {
+        loop {
+            let inner = match inner.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let res = inner
+                .lock()
+                .expect("KvStore mutex poisoned")
+                .compact_step_with(COMPACTION_STEP_BYTES, false);
+            match res {
+                Ok(Some(_)) => break,
+                Ok(None) => {}
+                Err(e) => {
+                    error!("background compaction failed, {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sweeps the expired keys of the store every `interval`, until `stopped` is
+/// disconnected with the store.
+fn expiry_sweep(inner: Weak<Mutex<KvStoreInner>>, stopped: Receiver<()>, interval: Duration) {
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let res = inner
+            .lock()
+            .expect("KvStore mutex poisoned")
+            .sweep_expired();
+        if let Err(e) = res {
+            error!("expiry sweep failed, {}", e);
+        }
+    }
+}
+
+/// Load the whole log file and return the value locations it leaves behind, to
+/// be merged into the index with the other generations.
+///
+/// The keys of the records are inserted into `bloom`, if any.
+///
+/// A bad last record is the trace of a crash during a write: it is dropped and,
+/// unless `read_only`, the file is truncated before it so new records are not
+/// appended after garbage.
+///
+/// # Errors
+///
+/// It returns `KvsError::CorruptLog` for a bad record followed by other records,
+/// which is not a torn write and is not repaired silently.
+fn load(
+    gen: u64,
+    log_reader: &mut LogReader,
+    dir: &Path,
+    read_only: bool,
+    mut bloom: Option<&mut BloomFilter>,
+) -> Result<GenIndex> {
+    let mut gen_index = GenIndex::default();
+    let now = now_unix_ms();
+    replay(gen, log_reader, dir, read_only, |cmd, cmd_pos| {
+        if let Some(bloom) = bloom.as_mut() {
+            bloom.insert(cmd.key());
+        }
+        gen_index.apply_command(cmd, cmd_pos, now);
+    })?;
+    Ok(gen_index)
+}
+
+/// Calls `visit` with every command of the log `gen` and its position, in
+/// order.
+///
+/// Returns the end of the last valid record, short of the file length if a
+/// torn record was dropped.
+fn replay(
+    gen: u64,
+    log_reader: &mut LogReader,
+    dir: &Path,
+    read_only: bool,
+    visit: impl FnMut(Command, CommandPos),
+) -> Result<u64> {
+    let encoding = match log_reader.encoding {
+        Some(encoding) => encoding,
+        None => return replay_legacy(gen, log_reader.file()?, dir, read_only, visit),
+    };
 
-            if let Command::Remove { key } = cmd {
-                // key 在之前的 if 已经判断为存在，这里 remove 一定会返回 Some，否则可以直接 panic
-                let old_cmd = self.index.remove(&key).expect("remove key not found");
-                self.uncompacted += old_cmd.length;
+    let reader = log_reader.file()?;
+    let file_len = reader.reader.get_ref().metadata()?.len();
+    let pos = replay_records(gen, reader, encoding, file_len, visit)?;
+    if pos < file_len {
+        drop_torn_tail(gen, pos, dir, read_only)?;
+    }
+    Ok(pos)
+}
+
+/// Visits every command of the log `gen` in `encoding`, `file_len` bytes long,
+/// read from `reader`, and returns the position after the last valid record.
+///
+/// A bad last record is left for the caller to drop.
+fn replay_records<R: Read + Seek>(
+    gen: u64,
+    reader: &mut R,
+    encoding: LogEncoding,
+    file_len: u64,
+    mut visit: impl FnMut(Command, CommandPos),
+) -> Result<u64> {
+    if encoding == LogEncoding::JsonLines {
+        return replay_lines(gen, reader, file_len, visit);
+    }
+    // 跳过文件开头的 header byte
+    let mut pos = reader.seek(SeekFrom::Start(1))?;
+    while pos < file_len {
+        let (cmd, next_pos, lsn) = match read_frame(reader, file_len - pos)? {
+            Frame::Valid { payload, len, lsn } => {
+                let next_pos = pos + len;
+                match encoding.decode(&payload) {
+                    // 空的 Batch 只记录了 LSN，见 `finish_compaction`
+                    Ok(Command::Batch(cmds)) if !cmds.is_empty() => {
+                        // 展开 Batch，每个 command 的位置是 record 中它自己的那一段
+                        let offsets = encoding.batch_offsets(&cmds)?;
+                        let lsn_len = if lsn.is_some() { LSN_LEN } else { 0 };
+                        for (cmd, (offset, len)) in cmds.into_iter().zip(offsets) {
+                            let start = pos + FRAME_HEADER_LEN + lsn_len + offset;
+                            visit(
+                                cmd,
+                                CommandPos::new(gen, start, start + len)
+                                    .with_in_batch(true)
+                                    .with_lsn(lsn.unwrap_or(0)),
+                            );
+                        }
+                        pos = next_pos;
+                        continue;
+                    }
+                    Ok(cmd) => (cmd, next_pos, lsn.unwrap_or(0)),
+                    Err(_) if next_pos == file_len => break,
+                    Err(_) => return Err(KvsError::CorruptLog { gen, offset: pos }),
+                }
             }
+            // 只有最后一条记录损坏时，才是写入时 crash 造成的，可以恢复
+            Frame::Torn => break,
+            Frame::Corrupt(len) if pos + len == file_len => break,
+            Frame::Corrupt(_) => return Err(KvsError::CorruptLog { gen, offset: pos }),
+        };
+        visit(cmd, CommandPos::new(gen, pos, next_pos).with_lsn(lsn));
+        pos = next_pos;
+    }
+    Ok(pos)
+}
 
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+/// Visits every command of the `LogEncoding::JsonLines` log `gen`, like
+/// `replay_records`.
+fn replay_lines<R: Read + Seek>(
+    gen: u64,
+    reader: &mut R,
+    file_len: u64,
+    mut visit: impl FnMut(Command, CommandPos),
+) -> Result<u64> {
+    // 跳过文件开头的 header byte
+    let mut pos = reader.seek(SeekFrom::Start(1))?;
+    let mut lines = BufReader::new(reader);
+    let mut line = Vec::new();
+    while pos < file_len {
+        line.clear();
+        lines.read_until(b'\n', &mut line)?;
+        let next_pos = pos + line.len() as u64;
+        let decoded = match line.last() {
+            Some(b'\n') => decode_line(&line),
+            _ => None,
+        };
+        let (cmd, lsn) = match decoded {
+            Some(decoded) => decoded,
+            // 只有最后一行损坏时，才是写入时 crash 造成的，可以恢复
+            None if next_pos >= file_len => break,
+            None => return Err(KvsError::CorruptLog { gen, offset: pos }),
+        };
+        match cmd {
+            Command::Batch(cmds) if !cmds.is_empty() => {
+                // 每个 command 的位置是行中它自己的那一段，行首必须是写入时的样子
+                let prefix = line_prefix(lsn);
+                if !line.starts_with(prefix.as_bytes()) {
+                    return Err(KvsError::CorruptLog { gen, offset: pos });
+                }
+                let offsets = LogEncoding::JsonLines.batch_offsets(&cmds)?;
+                for (cmd, (offset, len)) in cmds.into_iter().zip(offsets) {
+                    let start = pos + prefix.len() as u64 + offset;
+                    visit(
+                        cmd,
+                        CommandPos::new(gen, start, start + len)
+                            .with_in_batch(true)
+                            .with_lsn(lsn),
+                    );
+                }
+            }
+            cmd => visit(cmd, CommandPos::new(gen, pos, next_pos).with_lsn(lsn)),
         }
+        pos = next_pos;
     }
+    Ok(pos)
 }
 
-/// Load the whole log file and store value locations in the index map.
-fn load(
+/// Drops the torn record at `pos`, the tail of the log `gen`, left by a crash
+/// during a write.
+///
+/// The log is truncated to `pos`, unless the store is opened read-only.
+fn drop_torn_tail(gen: u64, pos: u64, dir: &Path, read_only: bool) -> Result<()> {
+    let err = KvsError::CorruptLog { gen, offset: pos };
+    if read_only {
+        warn!("{}, ignoring the rest of the log", err);
+    } else {
+        warn!("{}, truncating the log", err);
+        OpenOptions::new()
+            .write(true)
+            .open(log_path(dir, gen))?
+            .set_len(pos)?;
+    }
+    Ok(())
+}
+
+/// Replays a log file written without frames.
+///
+/// A record cut short at the end of the file is dropped like a torn frame.
+fn replay_legacy(
     gen: u64,
     reader: &mut BufferReaderWithPos<File>,
-    index: &mut HashMap<String, CommandPos>,
+    dir: &Path,
+    read_only: bool,
+    mut visit: impl FnMut(Command, CommandPos),
 ) -> Result<u64> {
     //  make sure we read from the beginning of the file
     let mut pos = reader.seek(SeekFrom::Start(0))?;
     let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    // number of bytes that can be saved after a compaction
-    let mut uncompacted = 0;
     while let Some(cmd) = stream.next() {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            // json 在文件末尾被截断
+            Err(e) if e.is_eof() => {
+                drop_torn_tail(gen, pos, dir, read_only)?;
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        };
         let next_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, value: _ } => {
-                if let Some(old_cmd) = index.insert(key, CommandPos::new(gen, pos, next_pos)) {
-                    uncompacted += old_cmd.length;
+        visit(cmd, CommandPos::new(gen, pos, next_pos));
+        pos = next_pos;
+    }
+    Ok(pos)
+}
+
+/// What one log generation does to the index, built by `load` independently of
+/// the other generations.
+#[derive(Debug, Default)]
+struct GenIndex {
+    // whether the log has a `Clear`, dropping every key of the older generations
+    cleared: bool,
+    // the last position of each key in the log, `None` if it was removed
+    entries: HashMap<String, Option<CommandPos>>,
+    // number of bytes of the log that can be saved after a compaction
+    uncompacted: u64,
+    // the highest LSN of the records of the log
+    max_lsn: u64,
+    // the keys and tokens of the `SetIdempotent` records of the log
+    tokens: HashSet<(String, String)>,
+}
+
+impl GenIndex {
+    /// Applies a replayed command located at `cmd_pos`.
+    fn apply_command(&mut self, cmd: Command, cmd_pos: CommandPos, now: u64) {
+        self.max_lsn = self.max_lsn.max(cmd_pos.lsn);
+        match cmd {
+            Command::Set { key, .. } | Command::SetBytes { key, .. } => {
+                self.update(key, Some(cmd_pos));
+            }
+            Command::SetIdempotent { key, token, .. } => {
+                if self.tokens.insert((key.clone(), token)) {
+                    self.update(key, Some(cmd_pos));
+                } else {
+                    // 重试写入的重复 record，只有第一条生效
+                    self.uncompacted += cmd_pos.length;
                 }
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.length;
+            Command::BlobRef { key, .. } => {
+                self.update(key, Some(cmd_pos.with_in_blob(true)));
+            }
+            cmd @ Command::SetEx { .. } => {
+                let cmd_pos = cmd_pos.with_expire_at(cmd.expire_at());
+                if cmd_pos.is_expired(now) {
+                    // 已经过期的 value 和被 remove 的一样，下次 compaction 时可以回收
+                    self.uncompacted += cmd_pos.length;
+                    self.update(cmd.into_key(), None);
+                } else {
+                    self.update(cmd.into_key(), Some(cmd_pos));
                 }
+            }
+            Command::Remove { key } => {
+                self.update(key, None);
 
                 // 这里是一个优化
                 // the "remove" command itself can be deleted in the next compaction.
                 // so we add its length to `uncompacted`
-                uncompacted += next_pos - pos;
+                self.uncompacted += cmd_pos.length;
             }
+            Command::Clear => {
+                self.uncompacted += self
+                    .entries
+                    .values()
+                    .flatten()
+                    .map(|old| old.length)
+                    .sum::<u64>();
+                self.entries.clear();
+                self.cleared = true;
+                self.uncompacted += cmd_pos.length;
+            }
+            // replay 时 Batch 已经展开成了其中的每个 command
+            Command::Batch(_) => {}
         }
-        pos = next_pos;
     }
-    Ok(uncompacted)
+
+    fn update(&mut self, key: String, cmd_pos: Option<CommandPos>) {
+        if let Some(Some(old_cmd)) = self.entries.insert(key, cmd_pos) {
+            self.uncompacted += old_cmd.length;
+        }
+    }
+
+    /// Applies the generation on top of `index`, which holds the older
+    /// generations.
+    ///
+    /// Returns the number of bytes that became stale.
+    fn merge_into(self, index: &mut BTreeMap<String, CommandPos>) -> u64 {
+        let mut uncompacted = self.uncompacted;
+        if self.cleared {
+            uncompacted += index.values().map(|old_cmd| old_cmd.length).sum::<u64>();
+            index.clear();
+        }
+        for (key, cmd_pos) in self.entries {
+            let old_cmd = match cmd_pos {
+                Some(cmd_pos) => index.insert(key, cmd_pos),
+                None => index.remove(&key),
+            };
+            if let Some(old_cmd) = old_cmd {
+                uncompacted += old_cmd.length;
+            }
+        }
+        uncompacted
+    }
+}
+
+/// A frame read by `read_frame`.
+enum Frame {
+    /// A frame whose checksum matches, with its decompressed payload, its
+    /// length including the header, and its LSN if it was written with one.
+    Valid {
+        payload: Vec<u8>,
+        len: u64,
+        lsn: Option<u64>,
+    },
+    /// A frame cut short by the end of the file.
+    Torn,
+    /// A complete frame failing its checksum, with its length including the header.
+    Corrupt(u64),
+}
+
+/// Reads one `[length][crc32][payload]` frame out of the `remaining` bytes of
+/// the file.
+fn read_frame<R: Read>(reader: &mut R, remaining: u64) -> Result<Frame> {
+    let mut header = [0u8; FRAME_HEADER_LEN as usize];
+    if remaining < FRAME_HEADER_LEN || !read_full(reader, &mut header)? {
+        return Ok(Frame::Torn);
+    }
+    let len = payload_len(&header);
+    if len > remaining - FRAME_HEADER_LEN {
+        return Ok(Frame::Torn);
+    }
+    let mut frame = header.to_vec();
+    frame.resize((FRAME_HEADER_LEN + len) as usize, 0);
+    if !read_full(reader, &mut frame[FRAME_HEADER_LEN as usize..])? {
+        return Ok(Frame::Torn);
+    }
+    Ok(match frame_payload(&frame)? {
+        Some(payload) => Frame::Valid {
+            payload: payload.into_owned(),
+            len: FRAME_HEADER_LEN + len,
+            lsn: decode_frame(&frame).and_then(|(_, _, lsn)| lsn),
+        },
+        None => Frame::Corrupt(FRAME_HEADER_LEN + len),
+    })
+}
+
+/// Fills `buf` completely, returning `false` if the reader hits EOF first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
 }
 
 /// Returns sorted generation numbers in the given directory.
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+pub(super) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     // TODO: 文件查找与遍历，这个有空就看一下
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
         .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
@@ -303,34 +4286,484 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+fn bloom_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.bloom", gen))
+}
+
+fn blob_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.blob", gen))
+}
+
+/// Removes the blob file of `gen`, if a large value was written to it.
+fn remove_blob_file(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(blob_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Opens the blob file of `gen` for appending values.
+fn open_blob_file(dir: &Path, gen: u64, buffer_size: usize) -> Result<BufferWriterWithPos<File>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(blob_path(dir, gen))?;
+    file.seek(SeekFrom::End(0))?;
+    BufferWriterWithPos::new(file, buffer_size)
+}
+
+/// Appends `value` to a blob file and flushes it. Returns the offset of `value`.
+fn write_blob(writer: &mut BufferWriterWithPos<File>, value: &[u8]) -> Result<u64> {
+    let offset = writer.pos;
+    writer.write_all(value)?;
+    writer.flush()?;
+    Ok(offset)
+}
+
+/// Replaces a `Command::BlobRef` with the `Command::SetBytes` of the value it
+/// points at, read from the blob file in `dir`.
+fn resolve_blob(dir: &Path, cmd: Command) -> Result<Command> {
+    match cmd {
+        Command::BlobRef {
+            key,
+            blob,
+            offset,
+            length,
+        } => {
+            let mut file = File::open(blob_path(dir, blob))?;
+            file.seek(SeekFrom::Start(offset))?;
+            let mut value = vec![0u8; length as usize];
+            file.read_exact(&mut value)?;
+            Ok(Command::SetBytes { key, value })
+        }
+        cmd => Ok(cmd),
+    }
+}
+
+/// Removes the bloom filter file of `gen`, if it was saved.
+fn remove_bloom_file(dir: &Path, gen: u64) -> Result<()> {
+    match fs::remove_file(bloom_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
-/// Returns the writer to the log.
+/// The header byte of `encoding` is written right away. Returns the writer to the log,
+/// the writer and the reader with buffers of `buffer_size` bytes.
 fn new_log_file(
     path: &Path,
     gen: u64,
-    readers: &mut HashMap<u64, BufferReaderWithPos<File>>,
+    encoding: LogEncoding,
+    buffer_size: usize,
+    readers: &mut Readers,
 ) -> Result<BufferWriterWithPos<File>> {
     let path = log_path(path, gen);
-    let writer = BufferWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
+    let mut writer = BufferWriterWithPos::new(
+        OpenOptions::new().create(true).append(true).open(&path)?,
+        buffer_size,
     )?;
-    readers.insert(gen, BufferReaderWithPos::new(File::open(&path)?)?);
+    if writer.pos == 0 {
+        writer.write_all(&[encoding.header()])?;
+        writer.flush()?;
+    }
+    readers.insert(gen, LogReader::open(&path, buffer_size)?);
 
     Ok(writer)
 }
 
-#[derive(Debug)]
+/// The result of `KvStoreInner::lookup`.
+enum Lookup {
+    /// The value, with no need to read the log.
+    Done(Option<String>),
+    /// The record of the value, in a log written in `encoding`.
+    Read {
+        encoding: Option<LogEncoding>,
+        cmd_pos: CommandPos,
+        // whether the value should be cached after reading it
+        cache: bool,
+    },
+}
+
+/// Idle file handles of the log files, so a read can seek a handle of its own
+/// outside the store lock.
+struct ReaderPool {
+    dir: PathBuf,
+    // number of idle handles kept per generation
+    capacity: usize,
+    idle: Mutex<PoolInner>,
+}
+
+#[derive(Default)]
+struct PoolInner {
+    // map generation number to its idle file handles
+    handles: HashMap<u64, Vec<File>>,
+    // the deleted generations, their handles are not kept
+    retired: HashSet<u64>,
+}
+
+impl ReaderPool {
+    fn new(dir: PathBuf, capacity: usize) -> Self {
+        ReaderPool {
+            dir,
+            capacity,
+            idle: Mutex::default(),
+        }
+    }
+
+    /// Reads and decodes the command at `cmd_pos`, in a log written in `encoding`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Io` of kind `NotFound` if the log has been deleted.
+    fn read(&self, encoding: Option<LogEncoding>, cmd_pos: &CommandPos) -> Result<Command> {
+        let idle = self.lock().handles.get_mut(&cmd_pos.gen).and_then(Vec::pop);
+        let mut file = match idle {
+            Some(file) => file,
+            None => File::open(log_path(&self.dir, cmd_pos.gen))?,
+        };
+        file.seek(SeekFrom::Start(cmd_pos.start))?;
+        let mut buf = vec![0u8; cmd_pos.length as usize];
+        file.read_exact(&mut buf)?;
+
+        let mut pool = self.lock();
+        if !pool.retired.contains(&cmd_pos.gen) {
+            let handles = pool.handles.entry(cmd_pos.gen).or_default();
+            if handles.len() < self.capacity {
+                handles.push(file);
+            }
+        }
+        drop(pool);
+        resolve_blob(&self.dir, decode_record(encoding, &buf, cmd_pos)?)
+    }
+
+    /// Closes the handles of the generations `gens`, which are being deleted.
+    fn retire(&self, gens: &[u64]) {
+        let mut pool = self.lock();
+        pool.retired.extend(gens);
+        pool.handles
+            .retain(|handle_gen, _| !gens.contains(handle_gen));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolInner> {
+        self.idle.lock().expect("ReaderPool mutex poisoned")
+    }
+}
+
+/// The log readers by generation, with at most `max_open` of their files open
+/// at a time.
+///
+/// The reader used the longest ago is closed first, and reopened by its next
+/// read.
+struct Readers {
+    readers: HashMap<u64, LogReader>,
+    // `None` 表示不限制打开的文件数
+    max_open: Option<usize>,
+    // 每次使用 reader 加一，记录在 reader 的 last_used 中
+    tick: u64,
+}
+
+impl Readers {
+    fn new(max_open: Option<usize>) -> Self {
+        Readers {
+            readers: HashMap::new(),
+            max_open,
+            tick: 0,
+        }
+    }
+
+    /// Returns the reader of the log generation `gen`, its file open, closing
+    /// another one if that exceeds the limit.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MissingReader` if there is no reader for `gen`.
+    fn reader(&mut self, gen: u64) -> Result<&mut LogReader> {
+        self.tick += 1;
+        let reader = self
+            .readers
+            .get_mut(&gen)
+            .ok_or(KvsError::MissingReader { gen })?;
+        reader.last_used = self.tick;
+        if !reader.is_open() {
+            reader.file()?;
+            self.close_excess(gen);
+        }
+        Ok(self.readers.get_mut(&gen).expect("reader just used"))
+    }
+
+    /// Returns the reader of `gen` without opening its file.
+    fn get(&self, gen: &u64) -> Option<&LogReader> {
+        self.readers.get(gen)
+    }
+
+    /// Returns the reader of `gen` without opening its file.
+    #[cfg(feature = "mmap")]
+    fn get_mut(&mut self, gen: &u64) -> Option<&mut LogReader> {
+        self.readers.get_mut(gen)
+    }
+
+    fn contains(&self, gen: u64) -> bool {
+        self.readers.contains_key(&gen)
+    }
+
+    fn insert(&mut self, gen: u64, mut reader: LogReader) {
+        self.tick += 1;
+        reader.last_used = self.tick;
+        let opened = reader.is_open();
+        self.readers.insert(gen, reader);
+        if opened {
+            self.close_excess(gen);
+        }
+    }
+
+    fn remove(&mut self, gen: &u64) -> Option<LogReader> {
+        self.readers.remove(gen)
+    }
+
+    fn len(&self) -> usize {
+        self.readers.len()
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &u64> {
+        self.readers.keys()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&u64, &LogReader)> {
+        self.readers.iter()
+    }
+
+    #[cfg(feature = "mmap")]
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut LogReader> {
+        self.readers.values_mut()
+    }
+
+    #[cfg(test)]
+    fn values(&self) -> impl Iterator<Item = &LogReader> {
+        self.readers.values()
+    }
+
+    /// Closes the files of the readers used the longest ago, except the one of
+    /// `keep`, until at most `max_open` are open.
+    fn close_excess(&mut self, keep: u64) {
+        let max_open = match self.max_open {
+            Some(max_open) => max_open,
+            None => return,
+        };
+        let mut open: Vec<(u64, u64)> = self
+            .readers
+            .iter()
+            .filter(|(_, reader)| reader.is_open())
+            .map(|(&gen, reader)| (reader.last_used, gen))
+            .collect();
+        if open.len() <= max_open {
+            return;
+        }
+        open.sort_unstable();
+        let excess = open.len() - max_open;
+        for (_, gen) in open
+            .into_iter()
+            .filter(|&(_, gen)| gen != keep)
+            .take(excess)
+        {
+            if let Some(reader) = self.readers.get_mut(&gen) {
+                reader.close();
+            }
+        }
+    }
+}
+
+/// The reader of one generation's log file, and the format the file is written in.
+struct LogReader {
+    path: PathBuf,
+    // `None` while closed by `Readers` to bound the open files
+    reader: Option<BufferReaderWithPos<File>>,
+    // the tick of `Readers` at the last use
+    last_used: u64,
+    // `None` for a legacy log without header byte
+    encoding: Option<LogEncoding>,
+    // whether records are read from `map` instead of `reader`
+    #[cfg(feature = "mmap")]
+    use_mmap: bool,
+    // the log mapped up to its length at mapping time
+    #[cfg(feature = "mmap")]
+    map: Option<Mmap>,
+    // capacity of the buffer of `reader`
+    buffer_size: usize,
+    // number of records read, to check what hits the file in tests
+    #[cfg(test)]
+    reads: u64,
+}
+
+impl LogReader {
+    fn open(path: &Path, buffer_size: usize) -> Result<LogReader> {
+        let mut reader = BufferReaderWithPos::new(File::open(path)?, buffer_size)?;
+        let mut first = [0u8; 1];
+        // 旧格式的 log 以 json 开头，没有 header byte
+        let encoding = match reader.read(&mut first)? {
+            1 => LogEncoding::from_header(first[0]),
+            _ => None,
+        };
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(LogReader {
+            path: path.to_owned(),
+            reader: Some(reader),
+            last_used: 0,
+            encoding,
+            #[cfg(feature = "mmap")]
+            use_mmap: false,
+            #[cfg(feature = "mmap")]
+            map: None,
+            buffer_size,
+            #[cfg(test)]
+            reads: 0,
+        })
+    }
+
+    /// Returns the reader of the log file, opening it again if it was closed.
+    fn file(&mut self) -> Result<&mut BufferReaderWithPos<File>> {
+        if self.reader.is_none() {
+            self.reader = Some(BufferReaderWithPos::new(
+                File::open(&self.path)?,
+                self.buffer_size,
+            )?);
+        }
+        Ok(self.reader.as_mut().expect("log reader is open"))
+    }
+
+    fn is_open(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    /// The directory of the log, where its blob files are.
+    fn dir(&self) -> &Path {
+        self.path.parent().unwrap_or_else(|| Path::new(""))
+    }
+
+    /// Closes the log file, until the next read.
+    fn close(&mut self) {
+        self.reader = None;
+    }
+
+    /// Whether records are read from the memory-mapped log.
+    fn uses_mmap(&self) -> bool {
+        #[cfg(feature = "mmap")]
+        return self.use_mmap;
+        #[cfg(not(feature = "mmap"))]
+        false
+    }
+
+    /// Reads and decodes the command located at `cmd_pos`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptLog` if a framed record fails its checksum.
+    fn read_command(&mut self, cmd_pos: &CommandPos) -> Result<Command> {
+        let cmd = self.read_record(cmd_pos)?;
+        resolve_blob(self.dir(), cmd)
+    }
+
+    /// Reads the record at `cmd_pos`, leaving a `Command::BlobRef` unresolved.
+    fn read_record(&mut self, cmd_pos: &CommandPos) -> Result<Command> {
+        #[cfg(test)]
+        {
+            self.reads += 1;
+        }
+        #[cfg(feature = "mmap")]
+        if self.use_mmap {
+            let encoding = self.encoding;
+            return decode_record(encoding, self.mapped_record(cmd_pos)?, cmd_pos);
+        }
+        let reader = self.file()?;
+        // key --> command's start postion
+        reader.seek(SeekFrom::Start(cmd_pos.start))?;
+        // key --> command's length
+        let mut buf = vec![0u8; cmd_pos.length as usize];
+        reader.read_exact(&mut buf)?;
+        decode_record(self.encoding, &buf, cmd_pos)
+    }
+
+    /// Returns the record at `cmd_pos` in the mapped log, mapping it again if the
+    /// record was appended after the last mapping.
+    #[cfg(feature = "mmap")]
+    fn mapped_record(&mut self, cmd_pos: &CommandPos) -> Result<&[u8]> {
+        let end = (cmd_pos.start + cmd_pos.length) as usize;
+        if self.map.as_ref().is_none_or(|map| map.len() < end) {
+            // Safety: log 文件只会被追加，不会被改写；只有打开时会截断末尾不完整的记录，
+            // 那时还没有映射
+            let file = self.file()?.reader.get_ref();
+            self.map = Some(unsafe { Mmap::map(file)? });
+        }
+        let map = self.map.as_ref().expect("log is mapped");
+        map.get(cmd_pos.start as usize..end).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "record past the end of the log",
+            )
+            .into()
+        })
+    }
+
+    /// Checks that `cmd_pos` points to a valid record setting `key`.
+    ///
+    /// Returns what is wrong with the record, `None` if it is fine.
+    fn verify(&mut self, key: &str, cmd_pos: &CommandPos) -> Result<Option<VerifyProblemKind>> {
+        let reader = self.file()?;
+        let file_len = reader.reader.get_ref().metadata()?.len();
+        if cmd_pos.start + cmd_pos.length > file_len {
+            return Ok(Some(VerifyProblemKind::Dangling));
+        }
+        reader.seek(SeekFrom::Start(cmd_pos.start))?;
+        let mut buf = vec![0u8; cmd_pos.length as usize];
+        reader.read_exact(&mut buf)?;
+
+        let cmd = match self.encoding {
+            None => serde_json::from_slice(&buf).ok(),
+            Some(encoding) if cmd_pos.in_batch => encoding.decode(&buf).ok(),
+            Some(LogEncoding::JsonLines) => decode_line(&buf).map(|(cmd, _)| cmd),
+            Some(encoding) => {
+                if buf.len() >= FRAME_HEADER_LEN as usize {
+                    let len = payload_len(&buf);
+                    if len + FRAME_HEADER_LEN != cmd_pos.length {
+                        return Ok(Some(VerifyProblemKind::LengthMismatch {
+                            actual: len + FRAME_HEADER_LEN,
+                        }));
+                    }
+                }
+                frame_payload(&buf)?.and_then(|payload| encoding.decode(&payload).ok())
+            }
+        };
+        let problem = match cmd {
+            None => Some(VerifyProblemKind::Corrupt),
+            Some(Command::Remove { .. }) => Some(VerifyProblemKind::Removed),
+            Some(cmd) => {
+                let found = cmd.into_key();
+                if found == key {
+                    None
+                } else {
+                    Some(VerifyProblemKind::KeyMismatch { found })
+                }
+            }
+        };
+        Ok(problem)
+    }
+}
+
+#[derive(Debug, Clone)]
 /// Represents the positon and length of a json-serialized command in the log.
-/// Include the command generation
+/// Include the command generation and the optional expiry of the value.
 struct CommandPos {
     gen: u64,
     start: u64,
     length: u64,
+    expire_at: Option<u64>,
+    // 是否是 Batch record 中的一个 command，这样的 command 没有自己的 frame
+    in_batch: bool,
+    // 是否是 BlobRef record，value 在 blob 文件中
+    in_blob: bool,
+    // the LSN of the record, 0 if written without one
+    lsn: u64,
 }
 
 impl CommandPos {
@@ -339,8 +4772,44 @@ impl CommandPos {
             gen,
             start,
             length: end - start,
+            expire_at: None,
+            in_batch: false,
+            in_blob: false,
+            lsn: 0,
         }
     }
+
+    fn with_in_batch(mut self, in_batch: bool) -> Self {
+        self.in_batch = in_batch;
+        self
+    }
+
+    fn with_lsn(mut self, lsn: u64) -> Self {
+        self.lsn = lsn;
+        self
+    }
+
+    fn with_in_blob(mut self, in_blob: bool) -> Self {
+        self.in_blob = in_blob;
+        self
+    }
+
+    fn with_expire_at(mut self, expire_at: Option<u64>) -> Self {
+        self.expire_at = expire_at;
+        self
+    }
+
+    fn is_expired(&self, now_unix_ms: u64) -> bool {
+        matches!(self.expire_at, Some(expire_at) if expire_at <= now_unix_ms)
+    }
+}
+
+/// Milliseconds elapsed since the unix epoch.
+fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 struct BufferWriterWithPos<W: Write + Seek> {
@@ -349,15 +4818,23 @@ struct BufferWriterWithPos<W: Write + Seek> {
 }
 
 impl<W: Write + Seek> BufferWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn new(mut inner: W, capacity: usize) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BufferWriterWithPos {
-            writer: BufWriter::new(inner),
+            writer: BufWriter::with_capacity(capacity, inner),
             pos,
         })
     }
 }
 
+impl BufferWriterWithPos<File> {
+    /// Flushes the buffer and syncs the file content to disk.
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
 impl<W: Write + Seek> Write for BufferWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
@@ -384,10 +4861,10 @@ struct BufferReaderWithPos<R: Read + Seek> {
 }
 
 impl<R: Read + Seek> BufferReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
+    fn new(mut inner: R, capacity: usize) -> Result<Self> {
+        let pos = inner.stream_position()?;
         Ok(BufferReaderWithPos {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -396,7 +4873,7 @@ impl<R: Read + Seek> BufferReaderWithPos<R> {
 impl<R: Read + Seek> Read for BufferReaderWithPos<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let len = self.reader.read(buf)?;
-        // self.pos += len as u64;
+        self.pos += len as u64;
         Ok(len)
     }
 }
@@ -407,3 +4884,226 @@ impl<R: Read + Seek> Seek for BufferReaderWithPos<R> {
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    #[test]
+    fn missing_reader_is_an_error() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+
+        let gen = store.lock().current_gen;
+        store.lock().readers.remove(&gen);
+        assert!(matches!(
+            store.get("key1".to_owned()),
+            Err(KvsError::MissingReader { gen: g }) if g == gen
+        ));
+        assert!(matches!(
+            store.compact(),
+            Err(KvsError::MissingReader { gen: g }) if g == gen
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn value_cache_skips_the_log() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .value_cache(2)
+            .build()?;
+        let reads = |store: &KvStore| -> u64 {
+            store
+                .lock()
+                .readers
+                .values()
+                .map(|reader| reader.reads)
+                .sum()
+        };
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(reads(&store), 1);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(reads(&store), 1);
+
+        // 写入之后要重新读 log
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(reads(&store), 2);
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        // compaction 移动了 record，cache 中的 value 不再命中
+        store.set("key2".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+        store.compact()?;
+        let before = reads(&store);
+        assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(reads(&store), before + 1);
+        store.clear()?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        assert!(matches!(
+            KvStoreBuilder::new()
+                .dir(temp_dir.path())
+                .value_cache(0)
+                .build(),
+            Err(KvsError::InvalidOption(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn max_open_files_bounds_the_readers() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // 每次 open 都会新建一个 generation
+        for gen in 0..40 {
+            let store = KvStoreOptions::new()
+                .compaction_threshold(u64::MAX)
+                .open(temp_dir.path())?;
+            store.set(format!("key{}", gen), format!("value{}", gen))?;
+        }
+        let open_readers = |store: &KvStore| {
+            let inner = store.lock();
+            inner.readers.iter().filter(|(_, r)| r.is_open()).count()
+        };
+
+        let store = KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .compaction_threshold(u64::MAX)
+            .max_open_files(3)
+            .build()?;
+        assert_eq!(store.lock().readers.len(), 41);
+        assert!(open_readers(&store) <= 3);
+        for round in 0..2 {
+            for gen in 0..40 {
+                let value = store.get(format!("key{}", gen))?;
+                assert_eq!(value, Some(format!("value{}", gen)), "round {}", round);
+                assert!(open_readers(&store) <= 3);
+            }
+        }
+        assert!(store.verify()?.problems.is_empty());
+        store.compact()?;
+        assert!(open_readers(&store) <= 3);
+        for gen in 0..40 {
+            assert_eq!(
+                store.get(format!("key{}", gen))?,
+                Some(format!("value{}", gen))
+            );
+        }
+        Ok(())
+    }
+
+    // key -> (gen, start, length)
+    type Positions = BTreeMap<String, (u64, u64, u64)>;
+
+    // The index replayed one record at a time over all generations, as loaded
+    // before the generations were loaded in parallel.
+    fn serial_index(dir: &Path) -> Result<(Positions, u64)> {
+        let mut index: BTreeMap<String, CommandPos> = BTreeMap::new();
+        let mut uncompacted = 0;
+        let now = now_unix_ms();
+        for gen in sorted_gen_list(dir)? {
+            let mut reader = LogReader::open(&log_path(dir, gen), DEFAULT_BUFFER_SIZE)?;
+            replay(gen, &mut reader, dir, true, |cmd, cmd_pos| {
+                let cmd_pos = cmd_pos.with_expire_at(cmd.expire_at());
+                let old_cmd = match cmd {
+                    Command::Clear => {
+                        uncompacted += index.values().map(|old| old.length).sum::<u64>();
+                        index.clear();
+                        uncompacted += cmd_pos.length;
+                        None
+                    }
+                    Command::Remove { key } => {
+                        uncompacted += cmd_pos.length;
+                        index.remove(&key)
+                    }
+                    cmd if cmd_pos.is_expired(now) => {
+                        uncompacted += cmd_pos.length;
+                        index.remove(cmd.key())
+                    }
+                    cmd => index.insert(cmd.into_key(), cmd_pos),
+                };
+                if let Some(old_cmd) = old_cmd {
+                    uncompacted += old_cmd.length;
+                }
+            })?;
+        }
+        let index = index
+            .into_iter()
+            .map(|(key, pos)| (key, (pos.gen, pos.start, pos.length)))
+            .collect();
+        Ok((index, uncompacted))
+    }
+
+    #[test]
+    fn parallel_load_matches_serial_load() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStoreOptions::new()
+                .compaction_threshold(u64::MAX)
+                .open(temp_dir.path())
+        };
+        for round in 0..8u64 {
+            let store = open()?;
+            if round == 3 {
+                store.clear()?;
+            }
+            for i in 0..200u64 {
+                // 每一轮覆盖、删除前几轮写入的 key
+                let key = format!("key{}", (i * 7 + round * 31) % 300);
+                match (i + round) % 5 {
+                    0 => {
+                        let _ = store.remove(key);
+                    }
+                    1 => store.set_with_ttl(key, format!("{}", i), Duration::from_millis(1))?,
+                    _ => store.set(key, format!("value{}-{}", round, i))?,
+                }
+            }
+            store.set("from".to_owned(), format!("{}", round))?;
+            store.rename("from".to_owned(), format!("renamed{}", round % 3))?;
+        }
+        thread::sleep(Duration::from_millis(5));
+
+        let store = KvStore::open_read_only(temp_dir.path())?;
+        let (serial, serial_uncompacted) = serial_index(temp_dir.path())?;
+        let inner = store.lock();
+        let parallel: BTreeMap<_, _> = inner
+            .index
+            .iter()
+            .map(|(key, pos)| (key.clone(), (pos.gen, pos.start, pos.length)))
+            .collect();
+        assert!(!serial.is_empty());
+        assert_eq!(parallel, serial);
+        assert_eq!(inner.uncompacted, serial_uncompacted);
+        Ok(())
+    }
+
+    #[test]
+    fn buffer_reader_pos_advances_on_read() -> Result<()> {
+        let mut reader =
+            BufferReaderWithPos::new(Cursor::new(b"0123456789".to_vec()), DEFAULT_BUFFER_SIZE)?;
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf)?;
+        assert_eq!(reader.pos, 3);
+
+        // take 限制了读取的长度，pos 只前进实际读取的字节数
+        let mut rest = Vec::new();
+        (&mut reader).take(4).read_to_end(&mut rest)?;
+        assert_eq!(rest, b"3456");
+        assert_eq!(reader.pos, 7);
+
+        reader.seek(SeekFrom::Start(1))?;
+        reader.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"123");
+        assert_eq!(reader.pos, 4);
+        Ok(())
+    }
+}
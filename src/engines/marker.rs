@@ -0,0 +1,152 @@
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::{KvStore, KvsEngine, SledKvsEngine};
+use crate::{KvsError, Result};
+
+/// The file of a data directory recording the engine its data belongs to.
+const ENGINE_FILE: &str = "engine";
+
+/// The storage engines, named `kvs` and `sled` on the command line and in the
+/// engine marker of a data directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    /// `KvStore`
+    Kvs,
+    /// `SledKvsEngine`
+    Sled,
+}
+
+impl FromStr for EngineKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            _ => Err(KvsError::UnknownEngine(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineKind::Kvs => f.write_str("kvs"),
+            EngineKind::Sled => f.write_str("sled"),
+        }
+    }
+}
+
+/// Opens the engine `kind` on the data directory `path`, for an engine picked
+/// at runtime.
+///
+/// It neither reads nor writes the engine marker, see `detect_engine` and
+/// `write_engine_marker`.
+///
+/// # Errors
+///
+/// It propagates the errors of `KvStore::open` or `SledKvsEngine::open`.
+pub fn open_engine(kind: EngineKind, path: impl AsRef<Path>) -> Result<Box<dyn KvsEngine>> {
+    let path = path.as_ref();
+    Ok(match kind {
+        EngineKind::Kvs => Box::new(KvStore::open(path)?),
+        EngineKind::Sled => Box::new(SledKvsEngine::open(path)?),
+    })
+}
+
+/// Returns the engine recorded in the marker of the data directory `path`,
+/// `None` if it has no marker.
+///
+/// # Errors
+///
+/// It returns `KvsError::InvalidEngineMarker` if the marker does not name an
+/// engine, and propagates I/O errors reading it.
+pub fn detect_engine(path: impl AsRef<Path>) -> Result<Option<EngineKind>> {
+    let marker = path.as_ref().join(ENGINE_FILE);
+    let content = match fs::read_to_string(&marker) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    content
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| KvsError::InvalidEngineMarker {
+            path: marker,
+            content,
+        })
+}
+
+/// Records `kind` in the marker of the data directory `path`, replacing any
+/// previous marker.
+pub fn write_engine_marker(path: impl AsRef<Path>, kind: EngineKind) -> Result<()> {
+    fs::write(path.as_ref().join(ENGINE_FILE), kind.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn engine_names() {
+        for kind in [EngineKind::Kvs, EngineKind::Sled] {
+            assert_eq!(kind.to_string().parse::<EngineKind>().unwrap(), kind);
+        }
+        assert_eq!("sled".parse::<EngineKind>().unwrap(), EngineKind::Sled);
+        for name in ["", "Kvs", "SLED", " kvs", "rocksdb"] {
+            match name.parse::<EngineKind>() {
+                Err(KvsError::UnknownEngine(found)) => assert_eq!(found, name),
+                res => panic!("{:?}: {:?}", name, res),
+            }
+        }
+    }
+
+    #[test]
+    fn valid_markers() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for kind in [EngineKind::Kvs, EngineKind::Sled] {
+            write_engine_marker(temp_dir.path(), kind)?;
+            assert_eq!(detect_engine(temp_dir.path())?, Some(kind));
+        }
+        // 手写的 marker 末尾可能有换行
+        fs::write(temp_dir.path().join(ENGINE_FILE), "kvs\n")?;
+        assert_eq!(detect_engine(temp_dir.path())?, Some(EngineKind::Kvs));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_marker() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        assert_eq!(detect_engine(temp_dir.path())?, None);
+        assert_eq!(detect_engine(temp_dir.path().join("missing"))?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_marker() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        for content in ["", "Kvs", "rocksdb", "\u{0}\u{1}"] {
+            fs::write(temp_dir.path().join(ENGINE_FILE), content)?;
+            match detect_engine(temp_dir.path()) {
+                Err(KvsError::InvalidEngineMarker {
+                    path,
+                    content: found,
+                }) => {
+                    assert_eq!(path, temp_dir.path().join(ENGINE_FILE));
+                    assert_eq!(found, content);
+                }
+                res => panic!("{:?}: {:?}", content, res),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use super::{validate_key, KvsEngine};
+use crate::{KvsError, Result};
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// An engine keeping the keys in memory only, for tests and caches.
+///
+/// Nothing is written to disk: the data is gone when the last clone is dropped.
+#[derive(Clone, Default)]
+pub struct InMemoryKvsEngine {
+    map: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl InMemoryKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> Self {
+        InMemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for InMemoryKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        validate_key(&key, None)?;
+        self.map
+            .write()
+            .expect("InMemoryKvsEngine lock poisoned")
+            .insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        validate_key(&key, None)?;
+        Ok(self
+            .map
+            .read()
+            .expect("InMemoryKvsEngine lock poisoned")
+            .get(&key)
+            .cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        validate_key(&key, None)?;
+        self.map
+            .write()
+            .expect("InMemoryKvsEngine lock poisoned")
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvsError::KeyNotFound)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        validate_key(&from, None)?;
+        validate_key(&to, None)?;
+        let mut map = self.map.write().expect("InMemoryKvsEngine lock poisoned");
+        let value = map.remove(&from).ok_or(KvsError::KeyNotFound)?;
+        map.insert(to, value);
+        Ok(())
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        validate_key(&key, None)?;
+        Ok(self
+            .map
+            .write()
+            .expect("InMemoryKvsEngine lock poisoned")
+            .insert(key, value))
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        validate_key(&key, None)?;
+        let mut map = self.map.write().expect("InMemoryKvsEngine lock poisoned");
+        match map.entry(key) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                Ok(true)
+            }
+        }
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.map
+            .write()
+            .expect("InMemoryKvsEngine lock poisoned")
+            .clear();
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        validate_key(key, None)?;
+        Ok(self
+            .map
+            .read()
+            .expect("InMemoryKvsEngine lock poisoned")
+            .contains_key(key))
+    }
+}
@@ -1,29 +1,155 @@
 //! This module provides various key value storage engines.
 
-use crate::Result;
+use crate::{KvsError, Result};
+use std::sync::mpsc::Receiver;
 
 /// Trait for a key value storage engine.
-pub trait KvsEngine {
+///
+/// The trait is object safe, so the engine can be picked at runtime as a
+/// `Box<dyn KvsEngine>`, see `open_engine`. The engines of this crate are also
+/// `Clone`, every clone sharing the same data, which the servers need to give
+/// each connection its own handle; `BoxedEngine` is the `Clone` trait object.
+pub trait KvsEngine: Send + Sync + 'static {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: String, value: String) -> Result<()>;
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Gets the string value of a given string key, like `get`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key does not exist.
+    fn get_or_error(&self, key: String) -> Result<String> {
+        self.get(key)?.ok_or(KvsError::KeyNotFound)
+    }
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// Moves the value of `from` to `to` in one atomic step, overwriting the
+    /// value of `to` if it exists.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `from` is not found.
+    fn rename(&self, from: String, to: String) -> Result<()>;
+
+    /// Sets the value of a string key and returns its previous value, or `None`
+    /// if it did not exist, without letting another write in between.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>>;
+
+    /// Sets the value of a string key only if it does not exist yet, returning
+    /// whether it was written.
+    ///
+    /// The check and the write are atomic, so of several callers racing on the
+    /// same key exactly one wins, which is enough for a simple lock.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool>;
+
+    /// Removes all keys.
+    fn clear(&self) -> Result<()>;
+
+    /// Returns whether the given key exists, without reading its value.
+    fn contains_key(&self, key: &str) -> Result<bool>;
+
+    /// Returns a receiver of every log record written from now on, in order,
+    /// for a replica to apply with `KvStore::apply_replicated`.
+    ///
+    /// # Errors
+    ///
+    /// By default, it returns `KvsError::StringError`, as an engine without a
+    /// log has nothing to ship.
+    fn subscribe(&self) -> Result<Receiver<Vec<u8>>> {
+        Err(KvsError::StringError(
+            "the engine does not ship log records".to_owned(),
+        ))
+    }
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn get_or_error(&self, key: String) -> Result<String> {
+        (**self).get_or_error(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        (**self).get_set(key, value)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        (**self).set_if_absent(key, value)
+    }
+
+    fn clear(&self) -> Result<()> {
+        (**self).clear()
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        (**self).contains_key(key)
+    }
+
+    fn subscribe(&self) -> Result<Receiver<Vec<u8>>> {
+        (**self).subscribe()
+    }
+}
+
+/// Checks that `key` is not empty and not longer than `max_key_bytes`, if set.
+pub(crate) fn validate_key(key: &str, max_key_bytes: Option<u64>) -> Result<()> {
+    if key.is_empty() {
+        return Err(KvsError::InvalidKey("the key is empty".to_owned()));
+    }
+    match max_key_bytes {
+        Some(limit) if key.len() as u64 > limit => Err(KvsError::InvalidKey(format!(
+            "key of {} bytes exceeds the limit of {} bytes",
+            key.len(),
+            limit
+        ))),
+        _ => Ok(()),
+    }
 }
 
+mod bloom;
+mod boxed;
+mod cache;
+mod codec;
+mod instrumented;
 mod kvs;
+mod marker;
+mod memory;
 mod sled;
 
-pub use self::kvs::KvStore;
+pub use self::boxed::BoxedEngine;
+pub use self::codec::LogEncoding;
+pub use self::instrumented::{InstrumentedEngine, LatencyHistogram, LatencySnapshot};
+pub use self::kvs::{
+    BatchOp, CompactionEstimate, CompactionStats, CompactionStrategy, EntryMeta, EvictionPolicy,
+    IoKvStore, KvIter, KvStore, KvStoreBuilder, KvStoreOptions, LogRecord, RepairReport,
+    StoreStats, SyncPolicy, VerifyProblem, VerifyProblemKind, VerifyReport,
+};
+pub use self::marker::{detect_engine, open_engine, write_engine_marker, EngineKind};
+pub use self::memory::InMemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
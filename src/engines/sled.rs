@@ -1,11 +1,17 @@
-use super::KvsEngine;
+use super::kvs::sorted_gen_list;
+use super::{validate_key, KvsEngine};
 use crate::{KvsError, Result};
 
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use sled::{Db, Tree};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+// sled 在数据目录下创建的文件
+const SLED_FILES: [&str; 2] = ["conf", "db"];
 
 /// sled engine
+#[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
 }
@@ -15,18 +21,77 @@ impl SledKvsEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
         let path = path.into();
         fs::create_dir_all(&path)?;
+        if !sorted_gen_list(&path)?.is_empty() {
+            return Err(KvsError::WrongEngine {
+                path,
+                expected: "sled",
+                found: "kvs",
+            });
+        }
 
         let db = sled::open(&path)?;
 
         Ok(SledKvsEngine { db })
     }
+
+    /// Sets the value of a string key to arbitrary bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        validate_key(&key, None)?;
+        let tree: &Tree = &self.db;
+        tree.insert(key, value).map(|_| ())?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Gets the raw bytes of the value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        validate_key(&key, None)?;
+        let tree: &Tree = &self.db;
+        Ok(tree
+            .get(key)?
+            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec()))
+    }
+
+    /// Replaces the value of `key` with `new` only if its current value equals
+    /// `expected`, returning whether the swap happened.
+    ///
+    /// `None` as `expected` means the key must be absent, `None` as `new` removes
+    /// the key.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        validate_key(&key, None)?;
+        let tree: &Tree = &self.db;
+        let swapped = tree
+            .compare_and_swap(
+                key,
+                expected.map(String::into_bytes),
+                new.map(String::into_bytes),
+            )?
+            .is_ok();
+        tree.flush()?;
+        Ok(swapped)
+    }
+}
+
+/// Returns whether `path` holds the data of a sled database.
+pub(super) fn is_sled_dir(path: &Path) -> bool {
+    SLED_FILES.iter().all(|file| path.join(file).is_file())
 }
 
 impl KvsEngine for SledKvsEngine {
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        validate_key(&key, None)?;
         let tree: &Tree = &self.db;
         // 这里感觉 map 将 Option<IVec> 映射为 ()，感觉没啥用
         // tree.insert(key, value.into_bytes())?;
@@ -38,7 +103,8 @@ impl KvsEngine for SledKvsEngine {
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        validate_key(&key, None)?;
         let tree: &Tree = &self.db;
         Ok(tree
             .get(key)?
@@ -47,15 +113,80 @@ impl KvsEngine for SledKvsEngine {
             .transpose()?)
     }
 
+    /// Sets the value of a string key and returns its previous value, with
+    /// the atomic `Tree::insert`.
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        validate_key(&key, None)?;
+        let tree: &Tree = &self.db;
+        let old = tree.insert(key, value.into_bytes())?;
+        tree.flush()?;
+        Ok(old
+            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// Sets the value of a string key only if it does not exist yet, with
+    /// `Tree::compare_and_swap` from `None`.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        validate_key(&key, None)?;
+        let tree: &Tree = &self.db;
+        let written = tree
+            .compare_and_swap(key, None as Option<&[u8]>, Some(value.into_bytes()))?
+            .is_ok();
+        tree.flush()?;
+        Ok(written)
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove(&self, key: String) -> Result<()> {
+        validate_key(&key, None)?;
         let tree: &Tree = &self.db;
         tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
         tree.flush()?;
         Ok(())
     }
+
+    /// Moves the value of `from` to `to` in one sled transaction.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `from` is not found.
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        validate_key(&from, None)?;
+        validate_key(&to, None)?;
+        let tree: &Tree = &self.db;
+        tree.transaction(|tx| {
+            let value = tx
+                .remove(from.as_bytes())?
+                .ok_or(ConflictableTransactionError::Abort(KvsError::KeyNotFound))?;
+            tx.insert(to.as_bytes(), value)?;
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        })?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Removes all keys.
+    fn clear(&self) -> Result<()> {
+        let tree: &Tree = &self.db;
+        tree.clear()?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Returns whether the given key exists, without reading its value.
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        validate_key(key, None)?;
+        let tree: &Tree = &self.db;
+        Ok(tree.contains_key(key)?)
+    }
 }
@@ -1,4 +1,6 @@
+use crate::ErrorCode;
 use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -19,12 +21,145 @@ pub enum KvsError {
     #[error("{}", _0)]
     /// Error with a string message
     StringError(String),
+    #[error("Bincode serialization or deserialization error.")]
+    /// Bincode serialization or deserialization error.
+    Bincode(#[from] bincode::Error),
     #[error("UTF-8 error.")]
     /// Key or value is invalid UTF-8 sequence
     Utf8(#[from] std::string::FromUtf8Error),
     #[error("Sled error.")]
     /// Sled error
     Sled(#[from] sled::Error),
+    #[error("Store directory {} is locked by another instance", path.display())]
+    /// Another `KvStore` holds the lock of the directory.
+    AlreadyLocked {
+        /// the store directory
+        path: PathBuf,
+    },
+    #[error("Directory {} contains data of the {found} engine, not {expected}", path.display())]
+    /// Opening a directory that holds the data of another engine.
+    WrongEngine {
+        /// the data directory
+        path: PathBuf,
+        /// the engine that was asked for
+        expected: &'static str,
+        /// the engine the directory actually contains
+        found: &'static str,
+    },
+    #[error("The store is opened read-only")]
+    /// Writing to a store opened by `KvStore::open_read_only`.
+    ReadOnly,
+    #[error("Value of {size} bytes exceeds the limit of {limit} bytes")]
+    /// A value is larger than `KvStoreOptions::max_value_bytes`.
+    ValueTooLarge {
+        /// the size of the value
+        size: u64,
+        /// the configured limit
+        limit: u64,
+    },
+    #[error("Invalid key: {}", _0)]
+    /// A key is empty or longer than the configured limit.
+    InvalidKey(String),
+    #[error("Invalid namespace: {:?}", _0)]
+    /// A namespace is empty or contains the namespace separator.
+    InvalidNamespace(String),
+    #[error("Unauthorized")]
+    /// A request sent before authenticating to a server that requires a token,
+    /// or an authentication with the wrong token.
+    Unauthorized,
+    #[error("Invalid option: {}", _0)]
+    /// A store option is missing or out of range.
+    InvalidOption(String),
+    #[error("Corrupt log record in generation {gen} at offset {offset}")]
+    /// A log record failed its checksum or could not be decoded.
+    CorruptLog {
+        /// generation number of the log file
+        gen: u64,
+        /// byte offset of the record within the log file
+        offset: u64,
+    },
+    #[error("Protocol version mismatch: client speaks {client}, server speaks {server}, please upgrade the older one")]
+    /// The client and the server speak different versions of the wire protocol.
+    ProtocolVersionMismatch {
+        /// protocol version of the client
+        client: u32,
+        /// protocol version of the server
+        server: u32,
+    },
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {}", _0)]
+    /// A TLS session or certificate error.
+    Tls(String),
+    #[error("No log reader for generation {gen}")]
+    /// The index points at a log generation that has no open reader.
+    MissingReader {
+        /// generation number of the missing log file
+        gen: u64,
+    },
+    #[error("Cannot resolve address {addr}: {reason}")]
+    /// An address is not a `host:port` resolving to any socket address.
+    AddrResolution {
+        /// the address as given
+        addr: String,
+        /// why it did not resolve
+        reason: String,
+    },
+    #[error("Server is at its limit of {limit} connections, try again later")]
+    /// The server rejected the connection because it already serves as many as
+    /// it accepts.
+    TooManyConnections {
+        /// the connection limit of the server
+        limit: u32,
+    },
+    #[error("Unknown engine {:?}, expected kvs or sled", _0)]
+    /// The name of an engine is neither `kvs` nor `sled`.
+    UnknownEngine(String),
+    #[error("Engine marker {} holds {content:?}, not an engine name", path.display())]
+    /// The engine marker of a data directory is corrupt.
+    InvalidEngineMarker {
+        /// the marker file
+        path: PathBuf,
+        /// what the marker holds
+        content: String,
+    },
+    #[error("Log record compressed with unsupported compression type {}", _0)]
+    /// A log record is compressed in a way this build cannot read, such as lz4
+    /// without the `compression` feature.
+    UnsupportedCompression(u8),
+    #[error("Cannot connect after {attempts} attempts: {source}")]
+    /// `KvsClient::connect_with` failed every attempt to connect.
+    ConnectFailed {
+        /// the number of attempts made
+        attempts: u32,
+        /// the error of the last attempt
+        source: io::Error,
+    },
+    #[error("The store holds the maximum of {max_keys} keys")]
+    /// Writing a new key to a store holding `KvStoreOptions::max_keys` keys under
+    /// `EvictionPolicy::RejectNew`.
+    Capacity {
+        /// the maximum number of keys
+        max_keys: usize,
+    },
+    #[error("IO error on {}: {source}", path.display())]
+    /// An I/O error opening the store, on the file or directory `path`, such as a
+    /// data directory that is not writable.
+    PathIo {
+        /// the file or directory the operation failed on
+        path: PathBuf,
+        /// the error of the operation
+        source: io::Error,
+    },
+    #[error("Server error: {message}")]
+    /// The server failed a request with an error that has no variant of its own
+    /// on the client, such as an I/O error of its engine. Unlike `KvsError::Io`,
+    /// the connection to the server is fine.
+    Remote {
+        /// the kind of the error on the server
+        code: ErrorCode,
+        /// the message of the error on the server
+        message: String,
+    },
 }
 
 /// A specialized [`Result`] type for kvs operations.
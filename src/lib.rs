@@ -1,13 +1,37 @@
 #![deny(missing_docs)]
 //! A simple kvstore
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use audit::AuditLog;
+pub use client::{ConnectOptions, KvsClient, Subscription};
+#[cfg(feature = "async")]
+pub use client_async::KvsClientAsync;
+pub use client_pool::KvsClientPool;
+pub use common::{resolve_addr, ErrorCode, Protocol, MAX_FRAME_LEN, PROTOCOL_VERSION};
+pub use engines::{
+    detect_engine, open_engine, write_engine_marker, BatchOp, BoxedEngine, CompactionEstimate,
+    CompactionStats, CompactionStrategy, EngineKind, EntryMeta, EvictionPolicy, InMemoryKvsEngine,
+    InstrumentedEngine, IoKvStore, KvIter, KvStore, KvStoreBuilder, KvStoreOptions, KvsEngine,
+    LatencyHistogram, LatencySnapshot, LogEncoding, LogRecord, RepairReport, SledKvsEngine,
+    StoreStats, SyncPolicy, VerifyProblem, VerifyProblemKind, VerifyReport,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use metrics::{serve_metrics, ServerMetrics, ServerStats};
+pub use server::{KvsServer, ServerHandle};
+#[cfg(feature = "async")]
+pub use server_async::KvsServerAsync;
 
+mod audit;
 mod client;
+#[cfg(feature = "async")]
+mod client_async;
+mod client_pool;
 mod common;
 mod engines;
 mod error;
+mod metrics;
 mod server;
+#[cfg(feature = "async")]
+mod server_async;
+pub mod thread_pool;
+#[cfg(feature = "tls")]
+mod tls;
@@ -0,0 +1,185 @@
+use crate::{Result, StoreStats};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// 一次 scrape 读写的最长时间，超时后关闭连接，避免空闲连接卡住后面的 scrape
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The counters of a `KvsServer`, shared by all its connections.
+#[derive(Debug)]
+pub struct ServerMetrics {
+    requests: AtomicU64,
+    sets: AtomicU64,
+    gets: AtomicU64,
+    removes: AtomicU64,
+    errors: AtomicU64,
+    started: Instant,
+}
+
+/// A snapshot of the counters of a server, returned by `KvsClient::stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStats {
+    /// The number of requests served before this `Stats` request.
+    pub total_requests: u64,
+    /// The number of `Set` requests served.
+    pub sets: u64,
+    /// The number of `Get` requests served.
+    pub gets: u64,
+    /// The number of `Remove` requests served.
+    pub removes: u64,
+    /// The number of requests the engine failed to serve.
+    pub errors: u64,
+    /// The seconds since the server was created.
+    pub uptime_secs: u64,
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        ServerMetrics {
+            requests: AtomicU64::new(0),
+            sets: AtomicU64::new(0),
+            gets: AtomicU64::new(0),
+            removes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl ServerMetrics {
+    /// Returns the number of requests served.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests the engine failed to serve.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of all the counters.
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            total_requests: self.requests(),
+            sets: self.sets.load(Ordering::Relaxed),
+            gets: self.gets.load(Ordering::Relaxed),
+            removes: self.removes.load(Ordering::Relaxed),
+            errors: self.errors(),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+
+    /// Counts a request of the operation `op`, see `Request::op`.
+    pub(crate) fn record(&self, op: &str, ok: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let counter = match op {
+            "set" => Some(&self.sets),
+            "get" => Some(&self.gets),
+            "remove" => Some(&self.removes),
+            _ => None,
+        };
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders the counters, and the gauges of `stats` if any, in the Prometheus
+    /// text exposition format.
+    pub fn render(&self, stats: Option<&StoreStats>) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "kvs_requests_total",
+            "counter",
+            "Requests served.",
+            self.requests(),
+        );
+        metric(
+            "kvs_errors_total",
+            "counter",
+            "Requests the engine failed to serve.",
+            self.errors(),
+        );
+        if let Some(stats) = stats {
+            metric("kvs_keys", "gauge", "Live keys.", stats.live_keys);
+            metric(
+                "kvs_uncompacted_bytes",
+                "gauge",
+                "Bytes of stale records a compaction would reclaim.",
+                stats.uncompacted_bytes,
+            );
+        }
+        out
+    }
+}
+
+/// Serves `GET /metrics` over HTTP on `listener`, forever.
+///
+/// `stats` is called on every scrape; engines without statistics return `None`.
+/// Scrapes are served one at a time, and a connection that does not send its
+/// request or read the response within `SCRAPE_TIMEOUT` is closed.
+pub fn serve_metrics<F>(listener: TcpListener, metrics: Arc<ServerMetrics>, stats: F) -> Result<()>
+where
+    F: Fn() -> Result<Option<StoreStats>>,
+{
+    info!("metrics on {:?}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        // 一次 scrape 很快，直接在当前线程处理
+        let res = stream.map_err(Into::into).and_then(|stream| {
+            stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+            stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+            scrape(&stream, &metrics, &stats)
+        });
+        if let Err(e) = res {
+            error!("error on serving metrics, {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+fn scrape<F>(stream: &TcpStream, metrics: &ServerMetrics, stats: &F) -> Result<()>
+where
+    F: Fn() -> Result<Option<StoreStats>>,
+{
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // 忽略 header，读到空行为止
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut writer = stream;
+    match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => {
+            let body = metrics.render(stats()?.as_ref());
+            write!(
+                writer,
+                "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+        }
+        _ => write!(
+            writer,
+            "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        )?,
+    }
+    writer.flush()?;
+    Ok(())
+}
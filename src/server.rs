@@ -1,33 +1,260 @@
-use log::{error, info};
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsEngine, Result};
+use crate::audit::{now_unix_ms, AuditEntry, AuditLog};
+use crate::common::{
+    answer_client_handshake, encode_busy_handshake, read_frame, set_tcp_options, write_frame,
+    write_payload, AuthResponse, ClearResponse, ContainsResponse, GetManyResponse,
+    GetOrErrorResponse, GetResponse, GetSetResponse, PingResponse, Protocol, RemoteError,
+    RemoveResponse, RenameResponse, Request, SetIfAbsentResponse, SetResponse, StatsResponse,
+    SubscribeResponse, HANDSHAKE_LEN,
+};
+use crate::metrics::ServerMetrics;
+use crate::thread_pool::ThreadPool;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::{BoxedEngine, KvsEngine, KvsError, Result};
+
+// 关闭被拒绝的连接前等待 client handshake 的时间
+const REJECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+// 等待关闭的被拒绝连接数，超过时直接关闭
+const REJECT_QUEUE: usize = 64;
+
+// 向 replica 发送 record 的连接多久检查一次 server 是否在 shutdown
+const SHIP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// request id for logging, unique within the process
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
 
 /// KvsServer
-pub struct KvsServer<E: KvsEngine> {
+pub struct KvsServer<E: KvsEngine + Clone, P: ThreadPool> {
     engine: E,
+    pool: P,
+    shutdown: Arc<AtomicBool>,
+    metrics: Arc<ServerMetrics>,
+    auth_token: Option<Arc<String>>,
+    client_timeout: Option<Duration>,
+    max_connections: Option<u32>,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    audit: Option<Arc<AuditLog>>,
 }
 
-impl<E: KvsEngine> KvsServer<E> {
-    /// new a `KvsServer` with given backend `engine`
-    pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+impl<E: KvsEngine + Clone, P: ThreadPool> KvsServer<E, P> {
+    /// new a `KvsServer` with given backend `engine` and thread `pool`
+    pub fn new(engine: E, pool: P) -> Self {
+        KvsServer {
+            engine,
+            pool,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            metrics: Arc::default(),
+            auth_token: None,
+            client_timeout: None,
+            max_connections: None,
+            nodelay: true,
+            keepalive: None,
+            audit: None,
+        }
+    }
+
+    /// serve at most `limit` connections at a time, unlimited by default
+    ///
+    /// A connection over the limit is answered at the handshake with
+    /// `KvsError::TooManyConnections` and closed, so a flood of connections
+    /// cannot pile up behind the busy pool.
+    pub fn max_connections(mut self, limit: u32) -> Self {
+        self.max_connections = Some(limit);
+        self
+    }
+
+    /// close a connection that sends nothing for `timeout`, none by default
+    ///
+    /// The timeout applies to every read: a client idle between two requests for
+    /// longer is disconnected too, and has to reconnect.
+    pub fn client_timeout(mut self, timeout: Duration) -> Self {
+        self.client_timeout = Some(timeout);
+        self
+    }
+
+    /// set `TCP_NODELAY` on accepted connections to `nodelay`, true by default
+    ///
+    /// A response is a single small write, so with Nagle's algorithm on it may
+    /// wait for the client's delayed ACK before going out.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// send TCP keepalive probes on a connection idle for `time`, none by default
+    ///
+    /// Unlike `client_timeout`, this only drops a connection whose peer is gone,
+    /// not one that is merely quiet.
+    pub fn keepalive(mut self, time: Duration) -> Self {
+        self.keepalive = Some(time);
+        self
+    }
+
+    /// require clients to authenticate with `token`, by `KvsClient::auth`, before
+    /// any other request
+    ///
+    /// Until then, every request of the connection fails with `KvsError::Unauthorized`.
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(Arc::new(token.into()));
+        self
+    }
+
+    /// record every request served in `audit`, see `AuditLog`
+    ///
+    /// A failed write to the audit file is logged and does not fail the request.
+    pub fn audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// the counters of the requests served by this server
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
     }
 
     /// create a new TcpListener which is bound to `addr` and processes the connection
-    pub fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+    pub fn run<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
         // 建立 TcpListener
         let listener = TcpListener::bind(addr)?;
+        self.serve_listener(listener, Acceptor::Plain)
+    }
+
+    /// like `run`, but every connection is a TLS session
+    ///
+    /// `cert` is the PEM file of the certificate chain of the server, `key` the
+    /// PEM file of its private key. The requests and responses are the same as
+    /// over plain TCP.
+    #[cfg(feature = "tls")]
+    pub fn run_tls<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<()> {
+        let config = tls::server_config(cert.as_ref(), key.as_ref())?;
+        let listener = TcpListener::bind(addr)?;
+        self.serve_listener(listener, Acceptor::Tls(config))
+    }
+
+    /// bind a TcpListener to `addr` and process the connections on a background thread
+    ///
+    /// The returned `ServerHandle` stops the server.
+    pub fn run_in_background<A: ToSocketAddrs>(self, addr: A) -> Result<ServerHandle>
+    where
+        P: Send + 'static,
+    {
+        self.spawn_listener(TcpListener::bind(addr)?, Acceptor::Plain)
+    }
+
+    /// like `run_in_background`, but every connection is a TLS session
+    ///
+    /// See `run_tls` for `cert` and `key`.
+    #[cfg(feature = "tls")]
+    pub fn run_tls_in_background<A: ToSocketAddrs>(
+        self,
+        addr: A,
+        cert: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+    ) -> Result<ServerHandle>
+    where
+        P: Send + 'static,
+    {
+        let config = tls::server_config(cert.as_ref(), key.as_ref())?;
+        self.spawn_listener(TcpListener::bind(addr)?, Acceptor::Tls(config))
+    }
+
+    fn spawn_listener(self, listener: TcpListener, acceptor: Acceptor) -> Result<ServerHandle>
+    where
+        P: Send + 'static,
+    {
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::clone(&self.shutdown);
+        let thread = thread::spawn(move || self.serve_listener(listener, acceptor));
+        Ok(ServerHandle {
+            addr,
+            shutdown,
+            thread,
+        })
+    }
+
+    fn serve_listener(&self, listener: TcpListener, acceptor: Acceptor) -> Result<()> {
         info!("run on {:?}", listener.local_addr()?);
+        let connections = Connections::default();
+        // 被拒绝的连接由另一个线程等待 handshake 后关闭，accept 线程不等待
+        let rejected = self.max_connections.map(|_| {
+            let (sender, receiver) = mpsc::sync_channel(REJECT_QUEUE);
+            thread::spawn(move || close_rejected(receiver));
+            sender
+        });
         // 处理 tcp 连接
         for stream in listener.incoming() {
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
             match stream {
                 Ok(stream) => {
                     info!("connection established, stream: {:?}", stream);
-                    self.server(&stream)?;
+                    // 不发送 request 的 client 不能一直占用一个线程
+                    if let Err(e) = stream.set_read_timeout(self.client_timeout) {
+                        error!("connection failed, {:?}", e);
+                        continue;
+                    }
+                    if let Err(e) = set_tcp_options(&stream, self.nodelay, self.keepalive) {
+                        error!("connection failed, {:?}", e);
+                        continue;
+                    }
+                    if let (Some(limit), Some(rejected)) = (self.max_connections, &rejected) {
+                        if connections.len() >= limit as usize {
+                            warn!("too many connections, rejecting stream: {:?}", stream);
+                            reject(stream, limit, rejected);
+                            continue;
+                        }
+                    }
+                    let guard = match connections.register(&stream) {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            error!("connection failed, {:?}", e);
+                            continue;
+                        }
+                    };
+                    // 每个连接持有一份 engine 的 clone，共享同一份底层数据
+                    let engine = self.engine.clone();
+                    let metrics = Arc::clone(&self.metrics);
+                    let acceptor = acceptor.clone();
+                    let auth_token = self.auth_token.clone();
+                    let shutdown = Arc::clone(&self.shutdown);
+                    let audit = self.audit.clone();
+                    // 将连接交给线程池处理，避免一个慢请求阻塞所有的 client
+                    self.pool.spawn(move || {
+                        let _guard = guard;
+                        let conn = Connection {
+                            metrics: &metrics,
+                            auth_token: auth_token.as_deref().map(String::as_str),
+                            shutdown: &shutdown,
+                            audit: audit.as_deref(),
+                        };
+                        match acceptor.serve(engine, conn, &stream) {
+                            Err(KvsError::Io(e)) if is_timeout(&e) => {
+                                warn!("connection timed out, closing, stream: {:?}", stream);
+                            }
+                            Err(e) => error!("error on serving client, {:?}", e),
+                            Ok(()) => {}
+                        }
+                    });
                 }
                 Err(e) => {
                     error!("connection failed, {:?}", e);
@@ -35,76 +262,438 @@ impl<E: KvsEngine> KvsServer<E> {
             }
         }
 
+        // 不再接受新的连接，等待正在处理的 request 完成
+        info!("shutting down, draining connections");
+        connections.close_and_wait();
         Ok(())
     }
+}
 
-    /// server
-    pub fn server(&mut self, tcp_stream: &TcpStream) -> Result<()> {
+impl<P: ThreadPool> KvsServer<BoxedEngine, P> {
+    /// new a `KvsServer` with an `engine` picked at runtime, such as the one
+    /// returned by `open_engine`, and thread `pool`
+    pub fn new_boxed(engine: Box<dyn KvsEngine>, pool: P) -> Self {
+        KvsServer::new(BoxedEngine::new(engine), pool)
+    }
+}
+
+/// How the accepted connections are wrapped before serving them.
+#[derive(Clone)]
+enum Acceptor {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(Arc<rustls::ServerConfig>),
+}
+
+impl Acceptor {
+    fn serve<E: KvsEngine>(
+        &self,
+        engine: E,
+        conn: Connection,
+        tcp_stream: &TcpStream,
+    ) -> Result<()> {
         let peer_addr = tcp_stream.peer_addr()?;
-        let reader = BufReader::new(tcp_stream);
-        let mut writer = BufWriter::new(tcp_stream);
-        let req_stream = Deserializer::from_reader(reader).into_iter::<Request>();
-        // while let Some(req) = stream.next() {
-        // 语法糖
-        for req in req_stream {
-            match req? {
-                Request::Set { key, value } => {
-                    info!(
-                        "recving set request from addr: {:?}, key: {:?}, value: {:?}",
-                        peer_addr, key, value
-                    );
-                    match self.engine.set(key, value) {
-                        Err(e) => {
-                            serde_json::to_writer(
-                                &mut writer,
-                                &SetResponse::Err(format!("{}", e)),
-                            )?;
-                        }
-                        Ok(_) => {
-                            serde_json::to_writer(&mut writer, &SetResponse::Ok(()))?;
-                        }
-                    }
-                    writer.flush()?;
-                }
-                Request::Get { key } => {
-                    info!(
-                        "recving get request from addr: {:?}, key: {:?}",
-                        peer_addr, key
-                    );
-                    match self.engine.get(key) {
-                        Err(e) => {
-                            serde_json::to_writer(
-                                &mut writer,
-                                &GetResponse::Err(format!("{}", e)),
-                            )?;
-                        }
-                        Ok(value) => {
-                            serde_json::to_writer(&mut writer, &GetResponse::Ok(value))?;
-                        }
-                    }
-                    writer.flush()?;
-                }
-                Request::Remove { key } => {
-                    info!(
-                        "recving rm request from addr: {:?}, key: {:?}",
-                        peer_addr, key
-                    );
-                    match self.engine.remove(key) {
-                        Err(e) => {
-                            serde_json::to_writer(
-                                &mut writer,
-                                &RemoveResponse::Err(format!("{}", e)),
-                            )?;
-                        }
-                        Ok(_) => {
-                            serde_json::to_writer(&mut writer, &RemoveResponse::Ok(()))?;
-                        }
-                    }
-                    writer.flush()?;
+        match self {
+            Acceptor::Plain => serve(engine, conn, peer_addr, tcp_stream),
+            #[cfg(feature = "tls")]
+            Acceptor::Tls(config) => {
+                let stream = tls::accept(Arc::clone(config), tcp_stream)?;
+                serve(engine, conn, peer_addr, stream)
+            }
+        }
+    }
+}
+
+/// The server settings a connection is served with.
+struct Connection<'a> {
+    metrics: &'a ServerMetrics,
+    // `None` if the server does not require authentication
+    auth_token: Option<&'a str>,
+    shutdown: &'a AtomicBool,
+    // `None` if requests are not audited
+    audit: Option<&'a AuditLog>,
+}
+
+/// A handle to a server started by `KvsServer::run_in_background`.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// the address the server listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// stop the server and wait for it to exit
+    ///
+    /// No new connection is accepted. Requests already read are answered, then
+    /// every open connection is closed.
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // 连接一次自己，唤醒阻塞在 accept 上的线程
+        let _ = TcpStream::connect(self.addr);
+        self.thread
+            .join()
+            .map_err(|_| KvsError::StringError("server thread panicked".to_owned()))?
+    }
+}
+
+/// The connections being served, so a shutdown can close and wait for them.
+#[derive(Clone, Default)]
+struct Connections {
+    inner: Arc<(Mutex<ConnectionsInner>, Condvar)>,
+}
+
+#[derive(Default)]
+struct ConnectionsInner {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>,
+}
+
+impl Connections {
+    /// track `stream` until the returned guard is dropped
+    fn register(&self, stream: &TcpStream) -> Result<ConnectionGuard> {
+        let stream = stream.try_clone()?;
+        let mut inner = self.inner.0.lock().expect("connections mutex poisoned");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.streams.insert(id, stream);
+        Ok(ConnectionGuard {
+            connections: self.clone(),
+            id,
+        })
+    }
+
+    /// the number of connections being served
+    fn len(&self) -> usize {
+        let inner = self.inner.0.lock().expect("connections mutex poisoned");
+        inner.streams.len()
+    }
+
+    /// close the read side of every connection and wait until all of them are done
+    fn close_and_wait(&self) {
+        let (lock, cvar) = &*self.inner;
+        let mut inner = lock.lock().expect("connections mutex poisoned");
+        for stream in inner.streams.values() {
+            // 读端关闭之后，阻塞在读 request 上的连接会读到 EOF 并退出
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !inner.streams.is_empty() {
+            inner = cvar.wait(inner).expect("connections mutex poisoned");
+        }
+    }
+}
+
+/// Removes its connection from `Connections` when the connection is done.
+struct ConnectionGuard {
+    connections: Connections,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.connections.inner;
+        if let Ok(mut inner) = lock.lock() {
+            inner.streams.remove(&self.id);
+        }
+        cvar.notify_all();
+    }
+}
+
+/// answer the handshake of a connection over the `limit` of the server without
+/// waiting for the client, then hand it to `close_rejected` to be closed
+fn reject(stream: TcpStream, limit: u32, rejected: &SyncSender<TcpStream>) {
+    // 新连接的发送缓冲区是空的，非阻塞地写几个字节不会失败
+    if let Err(e) = stream
+        .set_nonblocking(true)
+        .and_then(|_| (&stream).write_all(&encode_busy_handshake(limit)))
+    {
+        warn!("failed to reject connection, {:?}", e);
+        return;
+    }
+    if let Err(TrySendError::Full(stream)) = rejected.try_send(stream) {
+        warn!(
+            "too many rejected connections, closing stream: {:?}",
+            stream
+        );
+    }
+}
+
+/// close the rejected connections one by one, after reading the handshake of
+/// the client for at most `REJECT_TIMEOUT`
+fn close_rejected(rejected: Receiver<TcpStream>) {
+    for mut stream in rejected {
+        // 先读掉 client 的 handshake，有未读的数据时 close 会发送 RST，client 可能读不到回复
+        let mut handshake = [0u8; HANDSHAKE_LEN];
+        let _ = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(REJECT_TIMEOUT)));
+        let _ = stream.read_exact(&mut handshake);
+    }
+}
+
+/// serve a single connection from `peer_addr` with the given `engine`
+fn serve<E: KvsEngine, S: Read + Write>(
+    engine: E,
+    conn: Connection,
+    peer_addr: SocketAddr,
+    stream: S,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    // 握手：client 发送 protocol 和协议版本，server 返回自己的协议版本
+    let mut handshake = [0u8; HANDSHAKE_LEN];
+    reader.read_exact(&mut handshake)?;
+    let (answer, protocol) = answer_client_handshake(&handshake);
+    let writer = reader.get_mut();
+    writer.write_all(&answer)?;
+    writer.flush()?;
+    let protocol = protocol?;
+    let mut authenticated = conn.auth_token.is_none();
+    // 每个 request 都有长度前缀，client 可以连续发送多个 request 再读取 response
+    while let Some(req) = read_frame::<_, Request>(&mut reader, protocol)? {
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("request", peer = %peer_addr, request_id).entered();
+        let (op, key) = (req.op(), req.key());
+        let start = Instant::now();
+
+        let mut records = None;
+        let (resp, ok) = match (req, conn.auth_token) {
+            (Request::Auth { token }, Some(expected)) => {
+                let matched = constant_time_eq(token.as_bytes(), expected.as_bytes());
+                authenticated |= matched;
+                let resp = if matched {
+                    AuthResponse::Ok(())
+                } else {
+                    AuthResponse::Err(RemoteError::from(&KvsError::Unauthorized))
+                };
+                (protocol.encode(&resp)?, matched)
+            }
+            // 健康检查不需要认证，load balancer 没有 token
+            (Request::Ping, _) => respond(&engine, protocol, Request::Ping)?,
+            (req, _) if !authenticated => (
+                error_response(protocol, &req, &KvsError::Unauthorized)?,
+                false,
+            ),
+            // 计数在 server 中，respond 看不到
+            (Request::Stats, _) => (
+                protocol.encode(&StatsResponse::Ok(conn.metrics.stats()))?,
+                true,
+            ),
+            // 之后这个连接只用于向 replica 发送 record
+            (Request::Subscribe, _) => match engine.subscribe() {
+                Ok(rx) => {
+                    records = Some(rx);
+                    (protocol.encode(&SubscribeResponse::Ok(()))?, true)
                 }
+                Err(e) => (error_response(protocol, &Request::Subscribe, &e)?, false),
+            },
+            (req, _) => respond(&engine, protocol, req)?,
+        };
+        // 在返回 response 之前计数，client 收到 response 后就能看到计数
+        conn.metrics.record(op, ok);
+        // 一个 response 一次写入
+        let mut buf = Vec::with_capacity(resp.len() + 4);
+        write_payload(&mut buf, &resp)?;
+        let writer = reader.get_mut();
+        writer.write_all(&buf)?;
+        writer.flush()?;
+
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        let result = if ok { "ok" } else { "err" };
+        #[cfg(feature = "tracing")]
+        tracing::info!(op, key = %key, latency_ms, result, "request served");
+        #[cfg(not(feature = "tracing"))]
+        info!(
+            "request {} from addr: {:?}, op: {}, key: {:?}, latency_ms: {:.3}, result: {}",
+            request_id, peer_addr, op, key, latency_ms, result
+        );
+        if let Some(audit) = conn.audit {
+            let entry = AuditEntry {
+                ts_unix_ms: now_unix_ms(),
+                peer: peer_addr,
+                op,
+                key: &key,
+                result,
+                latency_ms,
+            };
+            if let Err(e) = audit.record(&entry) {
+                error!("failed to write the audit log, {:?}", e);
             }
         }
 
-        Ok(())
+        if let Some(records) = records {
+            return ship_records(records, conn.shutdown, protocol, reader.get_mut());
+        }
+    }
+
+    Ok(())
+}
+
+/// write every record of `records` to a subscribed replica, until the engine
+/// or the server goes away
+fn ship_records<W: Write>(
+    records: Receiver<Vec<u8>>,
+    shutdown: &AtomicBool,
+    protocol: Protocol,
+    writer: &mut W,
+) -> Result<()> {
+    loop {
+        match records.recv_timeout(SHIP_POLL_INTERVAL) {
+            Ok(record) => {
+                let mut buf = Vec::new();
+                write_frame(&mut buf, protocol, &record)?;
+                writer.write_all(&buf)?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) if !shutdown.load(Ordering::SeqCst) => {}
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// apply `req` to the `engine` and serialize its response
+///
+/// Returns the response and whether the engine succeeded.
+pub(crate) fn respond<E: KvsEngine>(
+    engine: &E,
+    protocol: Protocol,
+    req: Request,
+) -> Result<(Vec<u8>, bool)> {
+    let resp = match req {
+        Request::Set { key, value } => match engine.set(key, value) {
+            Err(e) => (
+                protocol.encode(&SetResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(_) => (protocol.encode(&SetResponse::Ok(()))?, true),
+        },
+        Request::Get { key } => match engine.get(key) {
+            Err(e) => (
+                protocol.encode(&GetResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(value) => (protocol.encode(&GetResponse::Ok(value))?, true),
+        },
+        Request::Remove { key } => match engine.remove(key) {
+            Err(e) => (
+                protocol.encode(&RemoveResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(_) => (protocol.encode(&RemoveResponse::Ok(()))?, true),
+        },
+        Request::Contains { key } => match engine.contains_key(&key) {
+            Err(e) => (
+                protocol.encode(&ContainsResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(exists) => (protocol.encode(&ContainsResponse::Ok(exists))?, true),
+        },
+        Request::GetMany { keys } => match keys.into_iter().map(|key| engine.get(key)).collect() {
+            Err(e) => (
+                protocol.encode(&GetManyResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(values) => (protocol.encode(&GetManyResponse::Ok(values))?, true),
+        },
+        Request::GetOrError { key } => match engine.get_or_error(key) {
+            Err(KvsError::KeyNotFound) => {
+                (protocol.encode(&GetOrErrorResponse::KeyNotFound)?, true)
+            }
+            Err(e) => (
+                protocol.encode(&GetOrErrorResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(value) => (protocol.encode(&GetOrErrorResponse::Ok(value))?, true),
+        },
+        Request::Rename { from, to } => match engine.rename(from, to) {
+            Err(e) => (
+                protocol.encode(&RenameResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(()) => (protocol.encode(&RenameResponse::Ok(()))?, true),
+        },
+        Request::GetSet { key, value } => match engine.get_set(key, value) {
+            Err(e) => (
+                protocol.encode(&GetSetResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(old) => (protocol.encode(&GetSetResponse::Ok(old))?, true),
+        },
+        Request::SetIfAbsent { key, value } => match engine.set_if_absent(key, value) {
+            Err(e) => (
+                protocol.encode(&SetIfAbsentResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(written) => (protocol.encode(&SetIfAbsentResponse::Ok(written))?, true),
+        },
+        Request::Clear => match engine.clear() {
+            Err(e) => (
+                protocol.encode(&ClearResponse::Err(RemoteError::from(&e)))?,
+                false,
+            ),
+            Ok(()) => (protocol.encode(&ClearResponse::Ok(()))?, true),
+        },
+        // 没有配置 token 的 server 接受任何 token
+        Request::Auth { .. } => (protocol.encode(&AuthResponse::Ok(()))?, true),
+        // 不经过 engine，engine 忙于 compaction 时也能立即返回
+        Request::Ping => (protocol.encode(&PingResponse::Pong)?, true),
+        // 只有 KvsServer 可以向 replica 发送 record
+        Request::Subscribe => (
+            protocol.encode(&SubscribeResponse::Err(RemoteError::from(
+                &KvsError::StringError("the server does not ship log records".to_owned()),
+            )))?,
+            false,
+        ),
+        // KvsServer 自己回答 Stats，走到这里的 server 没有计数
+        Request::Stats => (
+            protocol.encode(&StatsResponse::Err(RemoteError::from(
+                &KvsError::StringError("the server does not keep stats".to_owned()),
+            )))?,
+            false,
+        ),
+    };
+    Ok(resp)
+}
+
+/// serialize the failure of `req` with `err`, in the response type of `req`
+fn error_response(protocol: Protocol, req: &Request, err: &KvsError) -> Result<Vec<u8>> {
+    let msg = RemoteError::from(err);
+    match req {
+        Request::Set { .. } => protocol.encode(&SetResponse::Err(msg)),
+        Request::Get { .. } => protocol.encode(&GetResponse::Err(msg)),
+        Request::Remove { .. } => protocol.encode(&RemoveResponse::Err(msg)),
+        Request::Contains { .. } => protocol.encode(&ContainsResponse::Err(msg)),
+        Request::GetMany { .. } => protocol.encode(&GetManyResponse::Err(msg)),
+        Request::Auth { .. } => protocol.encode(&AuthResponse::Err(msg)),
+        Request::GetOrError { .. } => protocol.encode(&GetOrErrorResponse::Err(msg)),
+        Request::Rename { .. } => protocol.encode(&RenameResponse::Err(msg)),
+        Request::GetSet { .. } => protocol.encode(&GetSetResponse::Err(msg)),
+        Request::SetIfAbsent { .. } => protocol.encode(&SetIfAbsentResponse::Err(msg)),
+        Request::Clear => protocol.encode(&ClearResponse::Err(msg)),
+        Request::Ping => protocol.encode(&PingResponse::Err(msg)),
+        Request::Stats => protocol.encode(&StatsResponse::Err(msg)),
+        Request::Subscribe => protocol.encode(&SubscribeResponse::Err(msg)),
+    }
+}
+
+/// whether `e` is a read that hit the read timeout of the socket
+fn is_timeout(e: &io::Error) -> bool {
+    // 不同平台上超时返回的 kind 不一样
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+/// compare `a` and `b` in a time that only depends on their lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
     }
+    // 不提前返回，避免通过耗时猜出 token 的前缀
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
 }
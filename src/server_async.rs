@@ -0,0 +1,84 @@
+use futures::{SinkExt, StreamExt};
+use log::{error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::Framed;
+
+use crate::common::{answer_client_handshake, frame_codec, Request, HANDSHAKE_LEN};
+use crate::server::respond;
+use crate::{KvsEngine, KvsError, Result};
+
+/// KvsServerAsync, an async server running on the tokio runtime
+pub struct KvsServerAsync<E: KvsEngine + Clone> {
+    engine: E,
+}
+
+impl<E: KvsEngine + Clone> KvsServerAsync<E> {
+    /// new a `KvsServerAsync` with given backend `engine`
+    pub fn new(engine: E) -> Self {
+        KvsServerAsync { engine }
+    }
+
+    /// create a new TcpListener which is bound to `addr` and processes the connection
+    pub async fn run<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        self.run_with_listener(TcpListener::bind(addr).await?).await
+    }
+
+    /// process the connections of an already bound `listener`, e.g. one bound to
+    /// port 0 whose address was read back with `local_addr`
+    pub async fn run_with_listener(&self, listener: TcpListener) -> Result<()> {
+        info!("run on {:?}", listener.local_addr()?);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    info!("connection established, stream: {:?}", stream);
+                    if let Err(e) = stream.set_nodelay(true) {
+                        error!("connection failed, {:?}", e);
+                        continue;
+                    }
+                    // 每个连接一个 task，共享同一份 engine
+                    let engine = self.engine.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(engine, stream).await {
+                            error!("error on serving client, {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("connection failed, {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// serve a single connection with the given `engine`
+async fn serve<E: KvsEngine + Clone>(engine: E, mut tcp_stream: TcpStream) -> Result<()> {
+    let peer_addr = tcp_stream.peer_addr()?;
+    // 握手：client 发送 protocol 和协议版本，server 返回自己的协议版本
+    let mut handshake = [0u8; HANDSHAKE_LEN];
+    tcp_stream.read_exact(&mut handshake).await?;
+    let (answer, protocol) = answer_client_handshake(&handshake);
+    tcp_stream.write_all(&answer).await?;
+    let protocol = protocol?;
+    let mut framed = Framed::new(tcp_stream, frame_codec());
+    while let Some(frame) = framed.next().await {
+        let req: Request = protocol.decode(&frame?)?;
+        info!(
+            "recving request from addr: {:?}, op: {}, key: {:?}",
+            peer_addr,
+            req.op(),
+            req.key()
+        );
+        let engine = engine.clone();
+        // engine 的读写是阻塞的 IO，不能占用 runtime 的 worker 线程
+        let (resp, _) = task::spawn_blocking(move || respond(&engine, protocol, req))
+            .await
+            .map_err(|e| KvsError::StringError(format!("{}", e)))??;
+        framed.send(Bytes::from(resp)).await?;
+    }
+
+    Ok(())
+}
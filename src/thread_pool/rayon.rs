@@ -0,0 +1,32 @@
+use super::ThreadPool;
+use crate::{KvsError, Result};
+use log::error;
+
+/// Wrapper of `rayon::ThreadPool`
+pub struct RayonThreadPool(rayon::ThreadPool);
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // without a handler a panicking job aborts the whole process
+            .panic_handler(|panic| {
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                error!("a job panicked in the thread pool: {}", msg);
+            })
+            .build()
+            .map_err(|e| KvsError::StringError(format!("{}", e)))?;
+        Ok(RayonThreadPool(pool))
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.spawn(job)
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error};
+
+use super::ThreadPool;
+use crate::{KvsError, Result};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A thread pool using a shared queue inside.
+///
+/// A fixed number of worker threads pull jobs from one `mpsc` channel. If a job
+/// panics, the worker thread is replaced so the pool keeps the same number of
+/// live workers.
+pub struct SharedQueueThreadPool {
+    tx: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    /// Creates a new thread pool with `threads` worker threads.
+    ///
+    /// Returns an error if `threads` is zero, as no worker would take the jobs.
+    fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::StringError(
+                "thread pool size must be nonzero".to_owned(),
+            ));
+        }
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = TaskReceiver(Arc::clone(&rx));
+            thread::Builder::new().spawn(move || run_tasks(rx))?;
+        }
+        Ok(SharedQueueThreadPool { tx })
+    }
+
+    /// Spawns a function into the thread pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.tx
+            .send(Box::new(job))
+            .expect("The thread pool has no thread.");
+    }
+}
+
+#[derive(Clone)]
+struct TaskReceiver(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for TaskReceiver {
+    fn drop(&mut self) {
+        // 只有在 job panic 导致线程 unwind 时才需要补充一个新的 worker
+        if thread::panicking() {
+            let rx = self.clone();
+            if let Err(e) = thread::Builder::new().spawn(move || run_tasks(rx)) {
+                error!("Failed to spawn a thread: {}", e);
+            }
+        }
+    }
+}
+
+fn run_tasks(rx: TaskReceiver) {
+    loop {
+        // 锁只在取 job 的时候持有，执行 job 前就释放掉
+        let job = rx.0.lock().expect("job queue mutex poisoned").recv();
+        match job {
+            Ok(job) => {
+                job();
+            }
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::{StreamOwned, DEFAULT_VERSIONS};
+use std::convert::TryFrom;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{KvsError, Result};
+
+/// Builds the server side config from a PEM certificate chain and private key.
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert, e))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(DEFAULT_VERSIONS)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Builds the client side config, trusting the PEM certificates of `ca`.
+pub(crate) fn client_config(ca: &Path) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+        roots.add(cert.map_err(|e| pem_error(ca, e))?)?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(DEFAULT_VERSIONS)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Starts the server side session over an accepted `stream`.
+pub(crate) fn accept(
+    config: Arc<ServerConfig>,
+    stream: &TcpStream,
+) -> Result<StreamOwned<ServerConnection, &TcpStream>> {
+    Ok(StreamOwned::new(ServerConnection::new(config)?, stream))
+}
+
+/// Starts the client side session over `stream`, connected to `addr`.
+///
+/// The certificate of the server is verified against the host part of `addr`,
+/// either a DNS name or an IP address.
+pub(crate) fn connect(
+    config: Arc<ClientConfig>,
+    addr: &str,
+    stream: TcpStream,
+) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    // IPv6 的地址写作 [::1]:4000
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_owned())
+        .map_err(|_| KvsError::Tls(format!("invalid server name {:?}", host)))?;
+    Ok(StreamOwned::new(
+        ClientConnection::new(config, name)?,
+        stream,
+    ))
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> KvsError {
+    KvsError::Tls(format!("invalid PEM file {}: {}", path.display(), e))
+}
+
+impl From<rustls::Error> for KvsError {
+    fn from(e: rustls::Error) -> Self {
+        KvsError::Tls(e.to_string())
+    }
+}
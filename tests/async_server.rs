@@ -0,0 +1,36 @@
+#![cfg(feature = "async")]
+
+use kvs::{KvStore, KvsClientAsync, KvsServerAsync, Result};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+// Many concurrent async clients against one server should all see their own writes.
+#[tokio::test(flavor = "multi_thread")]
+async fn async_concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // 监听端口在 spawn 之前已经绑定好，不需要等待 server 启动
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = KvsServerAsync::new(KvStore::open(temp_dir.path())?);
+    tokio::spawn(async move { server.run_with_listener(listener).await });
+
+    let handles: Vec<_> = (0..500)
+        .map(|i| {
+            tokio::spawn(async move {
+                let mut client = KvsClientAsync::connect(addr).await?;
+                let key = format!("key{}", i);
+                client.set(key.clone(), format!("value{}", i)).await?;
+                assert_eq!(client.get(key.clone()).await?, Some(format!("value{}", i)));
+                client.remove(key.clone()).await?;
+                assert_eq!(client.get(key.clone()).await?, None);
+                assert!(client.remove(key).await.is_err());
+                Ok::<_, kvs::KvsError>(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.expect("client task panicked")?;
+    }
+
+    Ok(())
+}
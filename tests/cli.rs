@@ -1,6 +1,11 @@
+// The existing tests predate these lints; keep them as written.
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -171,6 +176,90 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `--log-level` sets the verbosity and each `-v` raises it one level.
+#[test]
+fn cli_log_level() {
+    let server_stderr = |args: &[&str]| {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4008"])
+            .args(args)
+            .env_remove("RUST_LOG")
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+        fs::read_to_string(&stderr_path).expect("unable to read from stderr file")
+    };
+
+    let content = server_stderr(&["--log-level", "warn"]);
+    assert!(!content.contains("127.0.0.1:4008"));
+    let content = server_stderr(&["--log-level", "warn", "-v"]);
+    assert!(content.contains("127.0.0.1:4008"));
+    let content = server_stderr(&["--log-level", "error", "-vv"]);
+    assert!(content.contains("127.0.0.1:4008"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--log-level", "loud"])
+        .assert()
+        .failure();
+}
+
+// Settings of `kvs-server.toml` apply when no flags are given, and flags override them.
+#[test]
+fn cli_config_file() {
+    let run_server = |args: &[&str]| {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("kvs-server.toml"),
+            "addr = \"127.0.0.1:4009\"\nengine = \"sled\"\nlog_level = \"info\"\nthreads = 2\n",
+        )
+        .unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(args)
+            .env_remove("RUST_LOG")
+            .current_dir(&temp_dir)
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+        let engine = fs::read_to_string(temp_dir.path().join("engine")).unwrap();
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        (engine, content)
+    };
+
+    let (engine, content) = run_server(&[]);
+    assert_eq!(engine, "sled");
+    assert!(content.contains("127.0.0.1:4009"));
+
+    let (engine, content) = run_server(&["--addr", "127.0.0.1:4010"]);
+    assert_eq!(engine, "sled");
+    assert!(content.contains("127.0.0.1:4010"));
+    assert!(!content.contains("127.0.0.1:4009"));
+
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("custom.toml");
+    fs::write(&config_path, "unknown = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .arg("--config")
+        .arg(&config_path)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -227,6 +316,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     });
     thread::sleep(Duration::from_secs(1));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("pong in"));
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key1", "value1", "--addr", addr])
@@ -331,7 +428,406 @@ fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
 
+#[test]
+fn cli_access_server_by_hostname() {
+    cli_access_server("kvs", "localhost:4012");
+}
+
+#[test]
+fn cli_access_server_ipv6() {
+    cli_access_server("kvs", "[::1]:4013");
+}
+
+// An address that does not resolve should fail with a clear message.
+#[test]
+fn cli_unresolvable_addr() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "no-such-host.invalid:4000"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Cannot resolve address no-such-host.invalid:4000"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("AddrResolution"));
+}
+
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_sled_engine_uses_sled_layout() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+
+    for round in 0..2 {
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--engine", "sled", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        if round == 0 {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(["set", "key1", "value1", "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success()
+                .stdout(is_empty());
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait on server");
+    }
+
+    // sled keeps its own `db`/`conf` files, the kvs engine writes numbered `.log` files
+    assert!(temp_dir.path().join("db").exists());
+    let has_log_file = fs::read_dir(temp_dir.path())
+        .unwrap()
+        .any(|entry| entry.unwrap().path().extension() == Some("log".as_ref()));
+    assert!(!has_log_file);
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+}
+
+// A missing key prints `Key not found`: on stdout with success for get, on stderr
+// with failure for rm.
+#[test]
+fn cli_key_not_found() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(is_empty())
+        .stderr("Key not found\n");
+
+    // 删除过的 key 也不存在
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(is_empty())
+        .stderr("Key not found\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `--data-dir` should hold the logs and the engine marker instead of the current directory.
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--data-dir"])
+        .arg(data_dir.path().join("foo"))
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    let foo = data_dir.path().join("foo");
+    assert_eq!(fs::read_to_string(foo.join("engine")).unwrap(), "kvs");
+    let has_log_file = fs::read_dir(&foo)
+        .unwrap()
+        .any(|entry| entry.unwrap().path().extension() == Some("log".as_ref()));
+    assert!(has_log_file);
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// `kvs dump` should list every record of the logs with its offset and length.
+#[test]
+fn cli_dump() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    drop(store);
+
+    // 每条记录前面有 8 字节的 frame header 和 8 字节的 LSN，log 开头有 1 字节的 header
+    let set_len = 16 + r#"{"Set":{"key":"key1","value":"value1"}}"#.len();
+    let remove_len = 16 + r#"{"Remove":{"key":"key1"}}"#.len();
+    let expected = format!(
+        "gen=1 offset=1 len={0} Set key1\n\
+         gen=1 offset={1} len={0} Set key2\n\
+         gen=1 offset={2} len={3} Remove key1\n",
+        set_len,
+        1 + set_len,
+        1 + 2 * set_len,
+        remove_len
+    );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(expected.clone());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--gen", "1", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(expected);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--gen", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty());
+}
+
+// A store in `dir` with stale overwrites of key1 and key2, whose last record is
+// torn if `torn` is set. Returns the offset of the last record.
+fn stale_store(dir: &Path, torn: bool) -> u64 {
+    let store = KvStore::open(dir).unwrap();
+    for i in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", i)).unwrap();
+    }
+    store.set("key2".to_owned(), "value".to_owned()).unwrap();
+    drop(store);
+
+    let log = dir.join("1.log");
+    let len = fs::metadata(&log).unwrap().len();
+    let last_len = 16 + r#"{"Set":{"key":"key2","value":"value"}}"#.len() as u64;
+    if torn {
+        // 最后一条记录只写了一半
+        let file = fs::OpenOptions::new().write(true).open(&log).unwrap();
+        file.set_len(len - 3).unwrap();
+    }
+    len - last_len
+}
+
+// `kvs compact` should rewrite the stale records, also after a torn write.
+#[test]
+fn cli_compact() {
+    for &torn in &[false, true] {
+        let temp_dir = TempDir::new().unwrap();
+        stale_store(temp_dir.path(), torn);
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["compact"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(contains("2 files removed"));
+        assert!(!temp_dir.path().join("1.log").exists());
+
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        assert_eq!(
+            store.get("key1".to_owned()).unwrap(),
+            Some("value9".to_owned())
+        );
+        let key2 = if torn { None } else { Some("value".to_owned()) };
+        assert_eq!(store.get("key2".to_owned()).unwrap(), key2);
+        assert_eq!(store.stats().unwrap().uncompacted_bytes, 0);
+
+        // 打开着的 store 不能 compact
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["compact"])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .code(1)
+            .stderr(contains("cannot open the store"));
+    }
+}
+
+// `kvs repair` should cut the log at a torn or corrupt record and compact the rest.
+#[test]
+fn cli_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let last = stale_store(temp_dir.path(), true);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("truncated gen=1 at offset={}\n", last)))
+        .stdout(contains("2 files removed"));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value9".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    drop(store);
+
+    // 中间的记录损坏时 store 打不开
+    let temp_dir = TempDir::new().unwrap();
+    let last = stale_store(temp_dir.path(), false);
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log).unwrap();
+    bytes[last as usize - 10] ^= 0xff;
+    fs::write(&log, bytes).unwrap();
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("truncated gen=1 at offset="));
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value8".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).unwrap(), None);
+    drop(store);
+
+    // 打开着的 store 不能 repair
+    let _store = KvStore::open(temp_dir.path()).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .code(1)
+        .stderr(contains("cannot repair the store"));
+}
+
+// `get --format json` prints the key and the value, null for a missing key.
+#[test]
+fn cli_get_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "line1\nline2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--format", "json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"line1\\nline2\"}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--format", "json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key2\",\"value\":null}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--format", "yaml", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kvs-bench` should print one row of throughputs for every engine and pool, and
+// leave nothing behind in its directory.
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let assert = Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--keys", "200", "--threads", "2", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("write ops/s"));
+    let stdout = String::from_utf8_lossy(&assert.get_output().stdout).into_owned();
+    for engine in ["kvs", "sled"] {
+        for pool in ["naive", "shared_queue", "rayon"] {
+            assert!(stdout
+                .lines()
+                .any(|line| line.split_whitespace().take(2).eq([engine, pool])));
+        }
+    }
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
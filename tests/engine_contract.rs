@@ -0,0 +1,192 @@
+use kvs::{
+    open_engine, EngineKind, InMemoryKvsEngine, InstrumentedEngine, KvStore, KvsEngine, KvsError,
+    Result, SledKvsEngine,
+};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The behavior every engine should share.
+fn engine_contract<E: KvsEngine + Clone>(engine: E) -> Result<()> {
+    // get-miss
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains_key("key1")?);
+    assert!(matches!(
+        engine.get_or_error("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // set, overwrite
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get_or_error("key1".to_owned())?, "value2");
+    assert!(engine.contains_key("key1")?);
+
+    // 空的 value 和非 ASCII 的 key
+    engine.set("空".to_owned(), String::new())?;
+    assert_eq!(engine.get("空".to_owned())?, Some(String::new()));
+
+    // 一个 clone 的写入对另一个 clone 可见
+    let clone = engine.clone();
+    clone.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains_key("key1")?);
+
+    // 空的 key 不合法
+    assert!(matches!(
+        engine.set(String::new(), "value".to_owned()),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        engine.get(String::new()),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        engine.remove(String::new()),
+        Err(KvsError::InvalidKey(_))
+    ));
+
+    // get_set 返回之前的 value
+    assert_eq!(
+        engine.get_set("key2".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        engine.get_set("key2".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    engine.remove("key2".to_owned())?;
+
+    // set_if_absent 只有第一次写入
+    assert!(engine.set_if_absent("lock".to_owned(), "owner1".to_owned())?);
+    assert!(!engine.set_if_absent("lock".to_owned(), "owner2".to_owned())?);
+    assert_eq!(engine.get("lock".to_owned())?, Some("owner1".to_owned()));
+    engine.remove("lock".to_owned())?;
+    assert!(engine.set_if_absent("lock".to_owned(), "owner2".to_owned())?);
+    engine.remove("lock".to_owned())?;
+
+    // rename 到新的 key，再 rename 到已有的 key
+    engine.set("from".to_owned(), "value1".to_owned())?;
+    engine.rename("from".to_owned(), "to".to_owned())?;
+    assert_eq!(engine.get("from".to_owned())?, None);
+    assert_eq!(engine.get("to".to_owned())?, Some("value1".to_owned()));
+    engine.set("from".to_owned(), "value2".to_owned())?;
+    engine.rename("from".to_owned(), "to".to_owned())?;
+    assert!(!engine.contains_key("from")?);
+    assert_eq!(engine.get("to".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        engine.rename("from".to_owned(), "to".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("to".to_owned())?, Some("value2".to_owned()));
+    engine.remove("to".to_owned())?;
+
+    // clear 之后所有的 key 都不存在
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.clear()?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains_key("key2")?);
+    engine.clear()?;
+
+    // remove-miss
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        engine.remove("never set".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// What was written before a reopen should still be there after it.
+fn persistence_contract<E, F>(open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set("key3".to_owned(), "value4".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    engine.set("key4".to_owned(), "value5".to_owned())?;
+    engine.rename("key4".to_owned(), "key5".to_owned())?;
+    drop(engine);
+
+    let engine = open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    assert_eq!(engine.get("key5".to_owned())?, Some("value5".to_owned()));
+    assert!(matches!(
+        engine.remove("key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn kvs_engine_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_contract(KvStore::open(temp_dir.path())?)?;
+    persistence_contract(|path| KvStore::open(path))
+}
+
+#[test]
+fn sled_engine_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_contract(SledKvsEngine::open(temp_dir.path())?)?;
+    persistence_contract(|path| retry_sled(|| SledKvsEngine::open(path)))
+}
+
+// sled 在 drop 之后由后台线程释放锁，reopen 时可能要稍等一下
+fn retry_sled<E>(open: impl Fn() -> Result<E>) -> Result<E> {
+    for _ in 0..50 {
+        match open() {
+            Err(KvsError::Sled(_)) => thread::sleep(Duration::from_millis(10)),
+            res => return res,
+        }
+    }
+    open()
+}
+
+// The engine of each kind picked at runtime should keep its behavior behind
+// the trait object.
+#[test]
+fn open_engine_contract() -> Result<()> {
+    for kind in [EngineKind::Kvs, EngineKind::Sled] {
+        persistence_contract(|path| retry_sled(|| open_engine(kind, path)))?;
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open_engine("kvs".parse()?, temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
+
+// 内存中的 engine 不需要满足 persistence_contract
+#[test]
+fn in_memory_engine_contract() -> Result<()> {
+    engine_contract(InMemoryKvsEngine::new())
+}
+
+#[test]
+fn instrumented_engine_contract() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_contract(InstrumentedEngine::new(KvStore::open(temp_dir.path())?))?;
+    persistence_contract(|path| KvStore::open(path).map(InstrumentedEngine::new))
+}
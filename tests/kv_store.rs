@@ -1,4 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    BatchOp, CompactionStrategy, EvictionPolicy, InMemoryKvsEngine, InstrumentedEngine, KvStore,
+    KvStoreBuilder, KvStoreOptions, KvsEngine, KvsError, LogEncoding, Result, SledKvsEngine,
+    SyncPolicy, VerifyProblemKind,
+};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Cursor, Read};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +17,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +27,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +38,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +47,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +59,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +75,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +83,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +95,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +125,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +135,2434 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Clones of one store should share the same log and index across threads.
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    store.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked")?;
+    }
+
+    // every write from every thread must be visible after reopen
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..200 {
+            let key = format!("key{}_{}", thread_id, i);
+            assert_eq!(store.get(key)?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Byte values with null bytes and invalid UTF-8 should round-trip.
+#[test]
+fn set_get_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = vec![0u8, 159, 146, 150, 0, 0xff, 0xfe];
+    store.set_bytes("key1".to_owned(), value.clone())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value.clone()));
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+
+    // Open from disk again, string values written by `set` are readable as bytes
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(value));
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get_bytes("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_set_get_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    let value = vec![0u8, 159, 146, 150, 0, 0xff, 0xfe];
+    engine.set_bytes("key1".to_owned(), value.clone())?;
+    assert_eq!(engine.get_bytes("key1".to_owned())?, Some(value.clone()));
+    assert!(matches!(
+        engine.get("key1".to_owned()),
+        Err(KvsError::Utf8(_))
+    ));
+
+    drop(engine);
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get_bytes("key1".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Keys in range should come back sorted, removed keys should be skipped.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &["d", "b", "a", "e", "c"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+
+    assert_eq!(
+        store.scan(Bound::Unbounded, Bound::Unbounded)?,
+        vec![
+            ("a".to_owned(), "value_a".to_owned()),
+            ("b".to_owned(), "value_b".to_owned()),
+            ("d".to_owned(), "value_d".to_owned()),
+            ("e".to_owned(), "value_e".to_owned()),
+        ]
+    );
+    assert_eq!(
+        keys(store.scan(
+            Bound::Included("b".to_owned()),
+            Bound::Included("d".to_owned())
+        )?),
+        vec!["b", "d"]
+    );
+    assert_eq!(
+        keys(store.scan(
+            Bound::Excluded("b".to_owned()),
+            Bound::Excluded("e".to_owned())
+        )?),
+        vec!["d"]
+    );
+    assert_eq!(
+        keys(store.scan(Bound::Excluded("a".to_owned()), Bound::Unbounded)?),
+        vec!["b", "d", "e"]
+    );
+    assert_eq!(
+        keys(store.scan(Bound::Unbounded, Bound::Excluded("b".to_owned()))?),
+        vec!["a"]
+    );
+
+    // empty and inverted ranges
+    assert!(store
+        .scan(
+            Bound::Excluded("b".to_owned()),
+            Bound::Excluded("b".to_owned())
+        )?
+        .is_empty());
+    assert!(store
+        .scan(
+            Bound::Included("e".to_owned()),
+            Bound::Included("a".to_owned())
+        )?
+        .is_empty());
+    assert!(store
+        .scan(Bound::Included("x".to_owned()), Bound::Unbounded)?
+        .is_empty());
+
+    // Open from disk again and check the order survives the log re-play
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        keys(store.scan(Bound::Unbounded, Bound::Unbounded)?),
+        vec!["a", "b", "d", "e"]
+    );
+
+    Ok(())
+}
+
+// Only keys that really start with the prefix should be returned, in order.
+#[test]
+fn keys_with_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key in &[
+        "username", "user:2", "user", "use", "user:1", "usher", "user:3",
+    ] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("user:3".to_owned())?;
+
+    assert_eq!(
+        store.keys_with_prefix("user"),
+        vec!["user", "user:1", "user:2", "username"]
+    );
+    assert_eq!(store.keys_with_prefix("user:"), vec!["user:1", "user:2"]);
+    assert_eq!(
+        store.keys_with_prefix("us"),
+        vec!["use", "user", "user:1", "user:2", "username", "usher"]
+    );
+    assert!(store.keys_with_prefix("admin").is_empty());
+    assert_eq!(store.keys_with_prefix("").len(), 6);
+
+    Ok(())
+}
+
+// Expired keys should read as absent, also after reopen, and be reclaimed by compaction.
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    assert_eq!(store.get("short".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+    assert!(store.remove("short".to_owned()).is_err());
+    assert_eq!(store.keys_with_prefix(""), vec!["long"]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_drops_expired_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let dir_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    // ~2MB of values that expire almost immediately
+    let value = "v".repeat(20 * 1024);
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            value.clone(),
+            Duration::from_millis(50),
+        )?;
+    }
+    let before = dir_size();
+    thread::sleep(Duration::from_millis(100));
+
+    // reading the expired keys counts their space as stale, the next write compacts
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    store.set("trigger".to_owned(), "value".to_owned())?;
+
+    assert!(dir_size() < before / 10);
+    assert_eq!(store.keys_with_prefix(""), vec!["trigger"]);
+
+    Ok(())
+}
+
+// A sweep should drop the expired keys from the index without reading them, and
+// write removes that survive reopen.
+#[test]
+fn sweep_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set_with_ttl(
+            format!("short{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("long{}", key_id),
+            "value".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+    }
+    assert_eq!(store.sweep_expired()?, 0);
+    assert_eq!(store.stats()?.live_keys, 1010);
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.sweep_expired()?, 1000);
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 10);
+    assert!(stats.uncompacted_bytes > 0);
+    assert_eq!(store.sweep_expired()?, 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.live_keys, 10);
+    assert_eq!(store.get("long0".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(store.sweep_expired(), Err(KvsError::ReadOnly)));
+
+    // background 线程定期 sweep
+    let store = KvStoreOptions::new()
+        .expiry_sweep_interval(Duration::from_millis(20))
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set_with_ttl(
+            format!("short{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(50),
+        )?;
+    }
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.stats()?.live_keys, 10);
+    Ok(())
+}
+
+// A retried idempotent set should be written once, also after a reopen, and a
+// duplicate record of the same token in a log replayed once.
+#[test]
+fn set_idempotent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.set_idempotent("key1".to_owned(), "value1".to_owned(), "t1".to_owned())?);
+    assert!(!store.set_idempotent("key1".to_owned(), "value1".to_owned(), "t1".to_owned())?);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // 模拟另一个进程重试：把第一条 record 再追加一次
+    let mut records = Vec::new();
+    KvStore::dump_log(temp_dir.path(), None, |record| records.push(record))?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].op, "SetIdempotent");
+    let path = temp_dir.path().join(format!("{}.log", records[0].gen));
+    let mut content = fs::read(&path)?;
+    let start = records[0].offset as usize;
+    let duplicate = content[start..start + records[0].len as usize].to_vec();
+    content.extend_from_slice(&duplicate);
+    fs::write(&path, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 1);
+    // 被覆盖的第一条和重复的一条
+    assert_eq!(stats.uncompacted_bytes, 2 * records[0].len);
+
+    // 重启之后的重试同样被跳过，不会覆盖之后写入的 value
+    assert!(!store.set_idempotent("key1".to_owned(), "value3".to_owned(), "t1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    // 打开时新建的空 log 不会让之前的 token 失效
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.set_idempotent("key1".to_owned(), "value3".to_owned(), "t1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    // 其它 key 的 token 互不影响，compaction 之后也保持不变
+    assert!(store.set_idempotent("key2".to_owned(), "value1".to_owned(), "t1".to_owned())?);
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(!store.set_idempotent("key2".to_owned(), "value2".to_owned(), "t1".to_owned())?);
+    Ok(())
+}
+
+// A batch should be applied in order with one flush and survive reopen.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let ops: Vec<_> = (0..10_000)
+        .map(|i| BatchOp::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .chain(vec![
+            BatchOp::Remove {
+                key: "key0".to_owned(),
+            },
+            BatchOp::Set {
+                key: "key1".to_owned(),
+                value: "overwritten".to_owned(),
+            },
+        ])
+        .collect();
+    store.write_batch(ops)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("overwritten".to_owned())
+        );
+        for i in 2..10_000 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// A batch removing an absent key fails without touching the store.
+#[test]
+fn write_batch_remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let ops = vec![
+        BatchOp::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "key2".to_owned(),
+        },
+    ];
+    assert!(matches!(store.write_batch(ops), Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Each key of a batch, written as one record, should be readable and removable
+// on its own, across reopen and compaction, with every log encoding.
+#[test]
+fn batch_record_keys_independent() -> Result<()> {
+    for encoding in [
+        LogEncoding::Json,
+        LogEncoding::Bincode,
+        LogEncoding::JsonLines,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStoreBuilder::new()
+                .dir(temp_dir.path())
+                .log_encoding(encoding)
+                .build()
+        };
+        let store = open()?;
+        store.set("key0".to_owned(), "value0".to_owned())?;
+        let ops = (1..100)
+            .map(|i| BatchOp::Set {
+                key: format!("key{}", i),
+                value: format!("value{}", i),
+            })
+            .chain(vec![BatchOp::Remove {
+                key: "key0".to_owned(),
+            }])
+            .collect();
+        store.write_batch(ops)?;
+
+        store.remove("key1".to_owned())?;
+        store.set("key2".to_owned(), "overwritten".to_owned())?;
+        let check = |store: &KvStore| -> Result<()> {
+            assert_eq!(store.get("key0".to_owned())?, None);
+            assert_eq!(store.get("key1".to_owned())?, None);
+            assert_eq!(
+                store.get("key2".to_owned())?,
+                Some("overwritten".to_owned())
+            );
+            for i in 3..100 {
+                assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+            }
+            assert!(store.verify()?.problems.is_empty());
+            Ok(())
+        };
+        check(&store)?;
+
+        drop(store);
+        let store = open()?;
+        check(&store)?;
+        store.remove("key3".to_owned())?;
+        assert_eq!(store.get("key3".to_owned())?, None);
+
+        // compaction 把 batch 中仍然有效的 command 写成单独的 record
+        store.compact()?;
+        store.remove("key4".to_owned())?;
+        drop(store);
+        let store = open()?;
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, None);
+        for i in 5..100 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the path of the only `.log` file in `dir` that contains `needle`.
+fn log_file_containing(dir: &Path, needle: &[u8]) -> PathBuf {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .find(|path| {
+            fs::read(path)
+                .unwrap()
+                .windows(needle.len())
+                .any(|window| window == needle)
+        })
+        .expect("no log file contains the needle")
+}
+
+// Flips the first byte of `needle` in the log containing it.
+fn corrupt_log(dir: &Path, needle: &[u8]) -> Result<()> {
+    let path = log_file_containing(dir, needle);
+    let mut content = fs::read(&path)?;
+    let offset = content
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .unwrap();
+    content[offset] ^= 0xff;
+    fs::write(&path, content)?;
+    Ok(())
+}
+
+// A flipped byte in the last record should be detected and every record before
+// it recovered.
+#[test]
+fn recover_from_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    corrupt_log(temp_dir.path(), b"value9")?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..9 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key9".to_owned())?, None);
+
+    // the log was truncated at the bad record, new writes are readable after reopen
+    store.set("key9".to_owned(), "new_value9".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key8".to_owned())?, Some("value8".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("new_value9".to_owned()));
+
+    Ok(())
+}
+
+// A bad record followed by good ones is not a torn write: opening should fail
+// instead of dropping the records after it.
+#[test]
+fn corrupted_record_before_the_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    corrupt_log(temp_dir.path(), b"value5")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::CorruptLog { .. })
+    ));
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path()),
+        Err(KvsError::CorruptLog { .. })
+    ));
+
+    Ok(())
+}
+
+// Half a record appended by a crash during a write should be dropped on open,
+// with every record before it intact.
+#[test]
+fn recover_from_torn_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // 把第一条记录的前一半追加到 log 末尾
+    let path = log_file_containing(temp_dir.path(), b"value1");
+    let mut content = fs::read(&path)?;
+    // length 的最高两位是 flag
+    let frame_len =
+        8 + (u32::from_le_bytes([content[1], content[2], content[3], content[4]]) & 0x3fff_ffff);
+    let half = content[1..1 + frame_len as usize / 2].to_vec();
+    content.extend_from_slice(&half);
+    let torn_len = content.len() as u64;
+    fs::write(&path, content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(fs::metadata(&path)?.len() < torn_len);
+    drop(store);
+
+    // 旧格式的 log 中被截断的 json
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Set":{"key":"ke"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Logs written before the framed format (plain concatenated json) still load.
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// With `SyncPolicy::EveryWrite` every acknowledged write is on disk, even if the
+// store is never dropped (as when the process is killed).
+#[test]
+fn sync_every_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::EveryWrite)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::new().sync_policy(SyncPolicy::Interval(Duration::from_millis(10))),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A small compaction threshold set through the builder should compact after a few overwrites.
+#[test]
+fn builder_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .compaction_threshold(512)
+        .build()?;
+
+    let log_count = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    // 10 keys * 100 overwrites leaves far more than 512 stale bytes
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    // a compaction writes a new generation, so the first log must be gone
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(log_count() <= 2);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn builder_invalid_options() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .compaction_threshold(0)
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new().build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .max_open_files(0)
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .buffer_size(0)
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .overwrite_in_place(true)
+            .reader_pool(4)
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .max_keys(0)
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .expiry_sweep_interval(Duration::ZERO)
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+    assert!(matches!(
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .compaction_strategy(CompactionStrategy::SizeTiered { threshold: 0 })
+            .build(),
+        Err(KvsError::InvalidOption(_))
+    ));
+}
+
+// The generations of the store, oldest first, with the size of their logs.
+fn log_sizes(dir: &Path) -> Vec<(u64, u64)> {
+    let mut logs: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| {
+            let gen = path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+            (gen, fs::metadata(&path).unwrap().len())
+        })
+        .collect();
+    logs.sort_unstable();
+    logs
+}
+
+// Under size-tiered compaction, small generations should merge while a large
+// generation is left alone, and keys removed since should stay removed.
+#[test]
+fn size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStoreBuilder::new()
+            .dir(temp_dir.path())
+            .compaction_strategy(CompactionStrategy::SizeTiered {
+                threshold: 64 * 1024,
+            })
+            .build()
+    };
+    let value = "v".repeat(100);
+    let store = open()?;
+    for i in 0..300 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    drop(store);
+    let large = log_sizes(temp_dir.path())[0];
+    assert!(large.1 >= 32 * 1024);
+
+    // 每次 open 都会留下一个小的 generation
+    let store = open()?;
+    store.remove("key0".to_owned())?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    for i in 0..2 {
+        let store = open()?;
+        store.set(format!("reopened{}", i), value.clone())?;
+    }
+    let small: Vec<_> = log_sizes(temp_dir.path())[1..]
+        .iter()
+        .map(|&(gen, _)| gen)
+        .collect();
+    assert_eq!(small.len(), 3);
+
+    let store = open()?;
+    for i in 0..1000 {
+        store.set(format!("new{}", i), value.clone())?;
+    }
+    let logs = log_sizes(temp_dir.path());
+    assert_eq!(logs[0], large);
+    assert!(small.iter().all(|gen| logs.iter().all(|log| log.0 != *gen)));
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key299".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("reopened1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("new999".to_owned())?, Some(value));
+    assert_eq!(store.stats()?.live_keys, 299 + 2 + 1000);
+    Ok(())
+}
+
+// A manual compaction should reclaim the space of overwritten values.
+#[test]
+fn manual_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let stats = store.compact()?;
+    assert!(stats.bytes_after < stats.bytes_before);
+    assert_eq!(stats.entries_retained, 2);
+    assert_eq!(stats.files_removed, 1);
+
+    // nothing is stale any more, so a second compaction is a no-op
+    let stats = store.compact()?;
+    assert_eq!(stats.bytes_after, stats.bytes_before);
+    assert_eq!(stats.files_removed, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A bincode log should load again, also when reopened with another encoding.
+#[test]
+fn bincode_log_encoding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .log_encoding(LogEncoding::Bincode)
+        .build()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_bytes("bytes".to_owned(), vec![0, 159, 146, 150])?;
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    // json 写入的新 log 和 bincode 写入的旧 log 可以共存
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(
+        store.get_bytes("bytes".to_owned())?,
+        Some(vec![0, 159, 146, 150])
+    );
+    store.set("key1".to_owned(), "json".to_owned())?;
+
+    // compaction 会把 bincode 的 record 重新编码为 json
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("json".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.generation_count, 1);
+
+    for iter in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let overwritten = store.stats()?;
+    assert_eq!(overwritten.live_keys, 2);
+    assert!(overwritten.uncompacted_bytes > 0);
+    assert!(overwritten.total_log_bytes > stats.total_log_bytes);
+
+    store.compact()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.live_keys, 2);
+    assert_eq!(compacted.uncompacted_bytes, 0);
+    assert!(compacted.total_log_bytes < overwritten.total_log_bytes);
+    // the compaction log and the new current log
+    assert_eq!(compacted.generation_count, 2);
+
+    Ok(())
+}
+
+// The estimate should count exactly the records superseded by overwrites, and
+// what a compaction then keeps.
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    let empty = store.compaction_estimate();
+    assert_eq!(empty.reclaimable_bytes, 0);
+    assert_eq!(empty.live_entries, 0);
+
+    let mut superseded = 0;
+    for iter in 0..10 {
+        for i in 0..20 {
+            let key = format!("key{}", i);
+            if let Some((_, meta)) = store.get_with_meta(key.clone())? {
+                superseded += meta.length;
+            }
+            store.set(key, format!("value{}-{}", i, iter))?;
+        }
+    }
+    let estimate = store.compaction_estimate();
+    assert_eq!(estimate.reclaimable_bytes, superseded);
+    assert_eq!(estimate.live_entries, 20);
+    let live: u64 = (0..20)
+        .map(|i| {
+            store
+                .get_with_meta(format!("key{}", i))
+                .unwrap()
+                .unwrap()
+                .1
+                .length
+        })
+        .sum();
+    assert_eq!(estimate.live_bytes, live);
+
+    let stats = store.compact()?;
+    assert_eq!(stats.entries_retained, estimate.live_entries);
+    // compaction log 和新的 current log 各有一个 header byte
+    assert_eq!(stats.bytes_after, estimate.live_bytes + 2);
+    let compacted = store.compaction_estimate();
+    assert_eq!(compacted.reclaimable_bytes, 0);
+    assert_eq!(compacted.live_bytes, estimate.live_bytes);
+
+    Ok(())
+}
+
+// `contains_key` is answered from the index, so a damaged value does not matter.
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+    assert!(!store.contains_key("key3")?);
+
+    let needle = b"value1";
+    let path = log_file_containing(temp_dir.path(), needle);
+    let mut content = fs::read(&path)?;
+    let offset = content
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .unwrap();
+    content[offset] ^= 0xff;
+    fs::write(&path, content)?;
+
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.contains_key("key1")?);
+
+    Ok(())
+}
+
+#[test]
+fn sled_contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+
+    assert!(engine.contains_key("key1")?);
+    assert!(!engine.contains_key("key2")?);
+    assert!(!engine.contains_key("key3")?);
+
+    Ok(())
+}
+
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    // create if absent
+    assert!(store.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // swap only on a matching value
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("other".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // delete
+    assert!(store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+
+    assert!(engine.compare_and_swap("key1".to_owned(), None, Some("value1".to_owned()))?);
+    assert!(!engine.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?);
+    assert!(!engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("other".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(engine.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert!(engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?);
+    assert_eq!(engine.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key1".to_owned(), "new\nline \"quoted\"".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let mut snapshot = Vec::new();
+    assert_eq!(store.export(&mut snapshot)?, 99);
+    assert_eq!(String::from_utf8(snapshot.clone())?.lines().count(), 99);
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::open(other_dir.path())?;
+    assert_eq!(other.import(&snapshot[..])?, 99);
+    assert_eq!(
+        other.scan(Bound::Unbounded, Bound::Unbounded)?,
+        store.scan(Bound::Unbounded, Bound::Unbounded)?
+    );
+    assert_eq!(
+        other.get("key1".to_owned())?,
+        Some("new\nline \"quoted\"".to_owned())
+    );
+    assert_eq!(other.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_files = || {
+        let mut names: Vec<_> = fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    let before = log_files();
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    // a second reader of the same directory
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.scan(Bound::Unbounded, Bound::Unbounded)?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned())
+        ]
+    );
+
+    assert!(matches!(
+        store.set("key3".to_owned(), "value3".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(log_files(), before);
+
+    Ok(())
+}
+
+// Only one instance may write to a directory at a time.
+#[test]
+fn lock_store_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::AlreadyLocked { path }) => assert_eq!(path, temp_dir.path()),
+        _ => panic!("expected the directory to be locked"),
+    }
+    // clones share the lock, readers do not need it
+    let clone = store.clone();
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::AlreadyLocked { .. })
+    ));
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Opening the data directory of one engine with the other should fail and name
+// the engine the directory contains.
+#[test]
+fn wrong_engine_layout() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    match SledKvsEngine::open(kvs_dir.path()) {
+        Err(KvsError::WrongEngine {
+            expected, found, ..
+        }) => assert_eq!((expected, found), ("sled", "kvs")),
+        _ => panic!("expected KvsError::WrongEngine"),
+    }
+    // 目录没有被 sled 改动
+    assert_eq!(
+        KvStore::open(kvs_dir.path())?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(sled_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    match KvStore::open(sled_dir.path()) {
+        Err(e @ KvsError::WrongEngine { .. }) => assert!(e.to_string().contains("sled")),
+        _ => panic!("expected KvsError::WrongEngine"),
+    }
+
+    Ok(())
+}
+
+// The histograms of an InstrumentedEngine should count every operation.
+#[test]
+fn instrumented_engine_latencies() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = InstrumentedEngine::new(KvStore::open(temp_dir.path())?);
+    let clone = engine.clone();
+
+    for i in 0..1000 {
+        clone.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..1000 {
+        engine.get(format!("key{}", i))?;
+    }
+    for i in 0..500 {
+        engine.remove(format!("key{}", i))?;
+    }
+    // 失败的操作也会被记录
+    assert!(engine.remove("key0".to_owned()).is_err());
+
+    let latencies = engine.snapshot_latencies();
+    assert_eq!(latencies.set.count(), 1000);
+    assert_eq!(latencies.get.count(), 1000);
+    assert_eq!(latencies.remove.count(), 501);
+    assert_eq!(latencies.contains_key.count(), 0);
+    assert!(latencies.set.percentile(50.0) <= latencies.set.percentile(99.0));
+    assert_eq!(latencies.contains_key.percentile(50.0), None);
+
+    Ok(())
+}
+
+// `verify` should report the keys whose records were lost, and only them.
+#[test]
+fn verify_truncated_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let report = store.verify()?;
+    assert_eq!(report.checked, 3);
+    assert!(report.problems.is_empty());
+
+    // 模拟 crash：截掉最后一条记录的末尾
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .expect("no log file");
+    let file = fs::OpenOptions::new().write(true).open(&log)?;
+    file.set_len(file.metadata()?.len() - 3)?;
+
+    let report = store.verify()?;
+    assert_eq!(report.checked, 3);
+    assert_eq!(report.problems.len(), 1);
+    assert_eq!(report.problems[0].key, "key2");
+    assert_eq!(report.problems[0].kind, VerifyProblemKind::Dangling);
+
+    Ok(())
+}
+
+// Keys of different namespaces should not see each other, and dropping one
+// namespace should leave the others intact, also after a reopen.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_ns("a", "key1".to_owned(), "a1".to_owned())?;
+    store.set_ns("a", "key2".to_owned(), "a2".to_owned())?;
+    store.set_ns("b", "key1".to_owned(), "b1".to_owned())?;
+    store.set("key1".to_owned(), "plain".to_owned())?;
+
+    assert_eq!(store.get_ns("a", "key1".to_owned())?, Some("a1".to_owned()));
+    assert_eq!(store.get_ns("b", "key1".to_owned())?, Some("b1".to_owned()));
+    assert_eq!(store.get_ns("b", "key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("plain".to_owned()));
+    assert_eq!(store.keys_ns("a")?, vec!["key1", "key2"]);
+    assert_eq!(
+        store.scan_ns("a", Bound::Excluded("key1".to_owned()), Bound::Unbounded)?,
+        vec![("key2".to_owned(), "a2".to_owned())]
+    );
+    assert_eq!(
+        store.scan_ns("b", Bound::Unbounded, Bound::Unbounded)?,
+        vec![("key1".to_owned(), "b1".to_owned())]
+    );
+    store.remove_ns("b", "key1".to_owned())?;
+    assert!(matches!(
+        store.remove_ns("b", "key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set_ns("b", "key1".to_owned(), "b1".to_owned())?;
+
+    assert_eq!(store.drop_namespace("a")?, 2);
+    assert_eq!(store.drop_namespace("a")?, 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys_ns("a")?.is_empty());
+    assert_eq!(store.get_ns("b", "key1".to_owned())?, Some("b1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("plain".to_owned()));
+
+    assert!(matches!(
+        store.set_ns("", "key1".to_owned(), "value".to_owned()),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+    assert!(matches!(
+        store.keys_ns("a\u{0}b"),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+
+    Ok(())
+}
+
+// A flush should make buffered writes durable even if the store is never
+// closed, as after a crash.
+#[test]
+fn flush_before_crash() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .buffered_writes(true)
+        .sync_policy(SyncPolicy::Interval(Duration::from_secs(3600)))
+        .build()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key0".to_owned())?, None);
+    drop(reader);
+
+    store.flush()?;
+    store.set("lost".to_owned(), "value".to_owned())?;
+    // 模拟进程被 kill：不 drop，buffer 中的 write 不会 flush
+    std::mem::forget(store);
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(
+            reader.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(reader.get("lost".to_owned())?, None);
+    Ok(())
+}
+
+// Buffered writes should be readable right away, and reach the log file on an
+// explicit flush.
+#[test]
+fn buffered_writes_flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .buffered_writes(true)
+        .build()?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // 还在 buffer 中，其它 instance 看不到
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key9".to_owned())?, None);
+    drop(reader);
+
+    store.flush()?;
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    for i in 0..10 {
+        assert_eq!(
+            reader.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // 读取刚写入的 key 会先 flush
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+
+    Ok(())
+}
+
+// Memory-mapped readers should see records appended after the mapping and the
+// logs created by a compaction.
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .mmap_reads(true)
+        .build()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.compact()?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    for i in 0..=100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    drop(store);
+
+    let store = KvStoreOptions::new()
+        .mmap_reads(true)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+
+    Ok(())
+}
+
+// Compressed records should take less disk than plain ones, and logs mixing
+// both should read back whether or not compression is on.
+#[cfg(feature = "compression")]
+#[test]
+fn compress_records() -> Result<()> {
+    let log_size = |dir: &Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let value = |i: usize| format!("value{} ", i).repeat(100);
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compressed_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStore::open(plain_dir.path())?;
+    let compressed = KvStoreBuilder::new()
+        .dir(compressed_dir.path())
+        .compress_records(true)
+        .build()?;
+    for i in 0..100 {
+        plain.set(format!("key{}", i), value(i))?;
+        compressed.set(format!("key{}", i), value(i))?;
+    }
+    // 太短的 value 压缩之后不会变小，不压缩
+    compressed.set("short".to_owned(), "v".to_owned())?;
+    for i in 0..100 {
+        assert_eq!(compressed.get(format!("key{}", i))?, Some(value(i)));
+    }
+    assert_eq!(compressed.get("short".to_owned())?, Some("v".to_owned()));
+    drop(plain);
+    drop(compressed);
+    assert!(log_size(compressed_dir.path()) * 5 < log_size(plain_dir.path()));
+
+    // 不开启压缩也能读出压缩过的 record，新写入的 record 不压缩
+    let store = KvStore::open(compressed_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), value(i + 1))?;
+    }
+    drop(store);
+
+    let store = KvStoreBuilder::new()
+        .dir(compressed_dir.path())
+        .compress_records(true)
+        .build()?;
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..100 {
+            let expected = if i < 50 { value(i + 1) } else { value(i) };
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected));
+        }
+        assert!(store.verify()?.problems.is_empty());
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+    let store = KvStore::open(compressed_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Clones of an InMemoryKvsEngine should share their keys across threads.
+#[test]
+fn in_memory_engine_threads() -> Result<()> {
+    let engine = InMemoryKvsEngine::new();
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    engine
+                        .set(format!("key{}_{}", t, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    for t in 0..4 {
+        assert_eq!(
+            engine.get(format!("key{}_99", t))?,
+            Some("value99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// A value over `max_value_bytes` should be rejected before anything is written.
+#[test]
+fn max_value_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .max_value_bytes(16)
+        .build()?;
+    store.set("key1".to_owned(), "v".repeat(16))?;
+    let log_size = store.stats()?.total_log_bytes;
+
+    assert!(matches!(
+        store.set("key1".to_owned(), "v".repeat(17)),
+        Err(KvsError::ValueTooLarge {
+            size: 17,
+            limit: 16
+        })
+    ));
+    assert!(matches!(
+        store.set_bytes("key2".to_owned(), vec![0; 17]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            },
+            BatchOp::Set {
+                key: "key3".to_owned(),
+                value: "v".repeat(17),
+            },
+        ]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert_eq!(store.stats()?.total_log_bytes, log_size);
+    assert_eq!(store.get("key1".to_owned())?, Some("v".repeat(16)));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Empty keys and keys over `max_key_bytes` should be rejected by every operation.
+#[test]
+fn key_validation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .max_key_bytes(8)
+        .build()?;
+    let long_key = "k".repeat(9);
+    for key in &["", long_key.as_str()] {
+        let invalid = |res: Result<_>| matches!(res, Err(KvsError::InvalidKey(_)));
+        assert!(invalid(
+            store.set(key.to_string(), "value".to_owned()).map(drop)
+        ));
+        assert!(invalid(store.get(key.to_string()).map(drop)));
+        assert!(invalid(store.remove(key.to_string()).map(drop)));
+        assert!(invalid(store.contains_key(key).map(drop)));
+        assert!(invalid(
+            store
+                .write_batch(vec![BatchOp::Set {
+                    key: key.to_string(),
+                    value: "value".to_owned(),
+                }])
+                .map(drop)
+        ));
+    }
+    // 正好等于上限的 key 是合法的
+    store.set("k".repeat(8), "value".to_owned())?;
+    assert_eq!(store.get("k".repeat(8))?, Some("value".to_owned()));
+    assert_eq!(store.keys_with_prefix(""), vec!["k".repeat(8)]);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(sled_dir.path())?;
+    assert!(matches!(
+        engine.set(String::new(), "value".to_owned()),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        engine.get(String::new()),
+        Err(KvsError::InvalidKey(_))
+    ));
+
+    Ok(())
+}
+
+// Every generation keeps a bloom filter of its keys, saved once the generation
+// is no longer written.
+#[test]
+fn bloom_filters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.generations_with("key1"), vec![1]);
+    assert!(store.generations_with("key2").is_empty());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(temp_dir.path().join("1.bloom").exists());
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.generations_with("key1"), vec![1, 2]);
+    assert_eq!(store.generations_with("key2"), vec![2]);
+
+    // compaction 之后只剩下 live 的 key
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.bloom").exists());
+    assert_eq!(store.generations_with("key2"), vec![3]);
+    assert!(store.generations_with("key1").is_empty());
+    assert!(temp_dir.path().join("3.bloom").exists());
+
+    assert!(matches!(
+        KvStoreOptions::new()
+            .bloom_false_positive_rate(1.0)
+            .open(temp_dir.path()),
+        Err(KvsError::InvalidOption(_))
+    ));
+    Ok(())
+}
+
+fn assert_values(store: &KvStore, expected: &HashMap<String, String>) -> Result<()> {
+    for key_id in 0..200 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.clone())?.as_ref(), expected.get(&key));
+    }
+    Ok(())
+}
+
+// Reads and writes between the steps of an incremental compaction should see
+// the latest values.
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    let mut expected = HashMap::new();
+    for iter in 0..5 {
+        for key_id in 0..200 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key.clone(), value.clone())?;
+            expected.insert(key, value);
+        }
+    }
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id);
+        store.remove(key.clone())?;
+        expected.remove(&key);
+    }
+
+    let bytes_before = store.stats()?.total_log_bytes;
+    let mut steps = 0;
+    let stats = loop {
+        if let Some(stats) = store.compact_step(256)? {
+            break stats;
+        }
+        steps += 1;
+        assert!(store.is_compacting());
+        assert_values(&store, &expected)?;
+        // compaction 过程中的写入
+        let key = format!("key{}", steps * 7 % 200);
+        if steps % 3 == 0 {
+            if expected.remove(&key).is_some() {
+                store.remove(key)?;
+            }
+        } else {
+            store.set(key.clone(), format!("step{}", steps))?;
+            expected.insert(key, format!("step{}", steps));
+        }
+        assert_values(&store, &expected)?;
+    };
+    assert!(steps > 1);
+    assert!(!store.is_compacting());
+    assert_eq!(stats.bytes_before, bytes_before);
+    assert!(stats.bytes_after < stats.bytes_before);
+    assert_values(&store, &expected)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_values(&store, &expected)?;
+    // 再做一次完整的 compaction，stale 的数据全部被回收
+    store.compact()?;
+    assert_eq!(store.stats()?.uncompacted_bytes, 0);
+    assert_values(&store, &expected)?;
+    Ok(())
+}
+
+// Concurrent writes with compactions on the background thread should not lose
+// any value, and the stale logs should be removed eventually.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreBuilder::new()
+        .dir(temp_dir.path())
+        .compaction_threshold(16 * 1024)
+        .background_compaction(true)
+        .build()?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..500 {
+                    let key = format!("key{}_{}", thread_id, iter % 50);
+                    let value = format!("{}", iter);
+                    store.set(key.clone(), value.clone())?;
+                    assert_eq!(store.get(key)?, Some(value));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // 等待 background compaction 完成
+    let mut waited = 0;
+    while store.is_compacting() || store.stats()?.generation_count > 2 {
+        assert!(waited < 500, "stale logs are not removed");
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    for thread_id in 0..4 {
+        for key_id in 0..50 {
+            let key = format!("key{}_{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some(format!("{}", 450 + key_id)));
+        }
+    }
+    Ok(())
+}
+
+// `close` should flush buffered writes, so they survive a reopen.
+#[test]
+fn close_flushes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .buffered_writes(true)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Reopening a store should not pile up generations past `max_generations`, even
+// when the stale bytes never reach the compaction threshold.
+#[test]
+fn max_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(matches!(
+        KvStoreOptions::new()
+            .max_generations(1)
+            .open(temp_dir.path()),
+        Err(KvsError::InvalidOption(_))
+    ));
+
+    // 没有上限时每次 open 都多一个 generation
+    for i in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert_eq!(KvStore::open(temp_dir.path())?.stats()?.generation_count, 6);
+
+    for i in 5..20 {
+        let store = KvStoreOptions::new()
+            .max_generations(4)
+            .open(temp_dir.path())?;
+        assert!(store.stats()?.generation_count <= 4);
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert!(store.stats()?.generation_count <= 4);
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..20 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// `get_with_meta` should point at the latest record of a key, in the compaction
+// log once a compaction moved it.
+#[test]
+fn get_with_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (value, meta) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1");
+    assert_eq!((meta.gen, meta.start), (1, 1));
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let (value, overwritten) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert_eq!(overwritten.gen, 1);
+    assert_eq!(overwritten.start, meta.start + meta.length);
+
+    store.compact()?;
+    let (value, compacted) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value2");
+    assert!(compacted.gen > 1);
+    assert_eq!(compacted.length, overwritten.length);
+    Ok(())
+}
+
+// Every key should be gone after `clear`, and stay gone after a reopen.
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.clear()?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    assert!(!temp_dir.path().join("1.log").exists());
+    store.set("key1".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    store.clear()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    Ok(())
+}
+
+// `iter` should yield every live pair once, in key order, and ignore writes
+// made after it started.
+#[test]
+fn iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10_000u64 {
+        store.set(format!("key{:05}", i), i.to_string())?;
+    }
+    store.remove("key00000".to_owned())?;
+
+    let mut iter = store.iter()?;
+    let (first_key, first_value) = iter.next().unwrap()?;
+    assert_eq!(
+        (first_key.as_str(), first_value.as_str()),
+        ("key00001", "1")
+    );
+    store.set("key00002".to_owned(), "overwritten".to_owned())?;
+    store.set("key10000".to_owned(), "10000".to_owned())?;
+
+    let mut count = 1;
+    let mut sum: u64 = first_value.parse().unwrap();
+    let mut last_key = first_key;
+    for pair in iter {
+        let (key, value) = pair?;
+        assert!(key > last_key);
+        assert_eq!(key, format!("key{:05}", value.parse::<u64>().unwrap()));
+        sum += value.parse::<u64>().unwrap();
+        count += 1;
+        last_key = key;
+    }
+    assert_eq!(count, 9_999);
+    assert_eq!(sum, (1..10_000).sum::<u64>());
+    Ok(())
+}
+
+// Reads through the reader pool should never see a torn or foreign value, even
+// while writes and compactions move the records.
+#[test]
+fn reader_pool_concurrent_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .reader_pool(2)
+        .compaction_threshold(16 * 1024)
+        .open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(
+            format!("key{}", i),
+            format!("key{}-v0-{}", i, "x".repeat(i % 100)),
+        )?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..20 {
+                for i in (0..1000).step_by(7) {
+                    store.set(format!("key{}", i), format!("key{}-v{}", i, round))?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for n in 0..5000 {
+                    let i = (n * 31 + t * 97) % 1000;
+                    let value = store.get(format!("key{}", i))?.expect("key not found");
+                    assert!(value.starts_with(&format!("key{}-v", i)), "{}", value);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.get("key7".to_owned())?, Some("key7-v19".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("key1-v0-x".to_owned()));
+    Ok(())
+}
+
+fn blob_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "blob"))
+        .collect()
+}
+
+#[test]
+fn large_values_in_blob_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().blob_threshold(1024 * 1024).clone();
+    let store = options.open(temp_dir.path())?;
+
+    let big = "x".repeat(5 * 1024 * 1024);
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    let blobs = blob_files(temp_dir.path());
+    assert_eq!(blobs.len(), 1);
+    assert_eq!(fs::metadata(&blobs[0])?.len(), big.len() as u64);
+    // log 中只有 value 的位置
+    let (_, meta) = store.get_with_meta("big".to_owned())?.unwrap();
+    assert!(meta.length < 1024);
+    assert_eq!(store.get("big".to_owned())?, Some(big.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    // compaction 把 value 移动到新的 blob 文件
+    store.set("small".to_owned(), "other".to_owned())?;
+    store.compact()?;
+    assert_eq!(blob_files(temp_dir.path()).len(), 1);
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(big));
+
+    store.remove("big".to_owned())?;
+    store.compact()?;
+    assert!(blob_files(temp_dir.path()).is_empty());
+    assert_eq!(store.get("big".to_owned())?, None);
+    assert_eq!(store.get("small".to_owned())?, Some("other".to_owned()));
+    Ok(())
+}
+
+// A large value is streamed from its blob file through the reader, a small one from memory.
+#[test]
+fn get_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .blob_threshold(1024 * 1024)
+        .open(temp_dir.path())?;
+
+    let big: String = (0..3 * 1024 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    store.set("big".to_owned(), big.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    let mut reader = store.get_reader("big".to_owned())?.unwrap();
+    let mut read = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&chunk[..n]);
+    }
+    assert_eq!(read, big.as_bytes());
+
+    let mut small = String::new();
+    store
+        .get_reader("small".to_owned())?
+        .unwrap()
+        .read_to_string(&mut small)?;
+    assert_eq!(small, "value");
+    assert!(store.get_reader("missing".to_owned())?.is_none());
+    Ok(())
+}
+
+// Records larger than the buffers are read and written through them.
+#[test]
+fn small_buffer_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().buffer_size(16).clone();
+    let store = options.open(temp_dir.path())?;
+    for iter in 0..10 {
+        for i in 0..20 {
+            store.set(
+                format!("key{}", i),
+                format!("value{}-{}", i, iter).repeat(8),
+            )?;
+        }
+    }
+    store.compact()?;
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("value{}-9", i).repeat(8))
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn current_lsn() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_lsn(), 0);
+    for i in 1..=10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        assert_eq!(store.current_lsn(), i);
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_lsn(), 10);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.current_lsn(), 11);
+
+    // 最后的 remove 被 compaction 丢弃之后，LSN 也不会倒退
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_lsn(), 11);
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert_eq!(store.current_lsn(), 12);
+    Ok(())
+}
+
+#[test]
+fn get_as_of() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())?;
+    let mut lsns = Vec::new();
+    for i in 1..=3 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+        lsns.push(store.current_lsn());
+        store.set("other".to_owned(), format!("other{}", i))?;
+    }
+    store.remove("key".to_owned())?;
+    let removed = store.current_lsn();
+
+    assert_eq!(store.get_as_of("key".to_owned(), lsns[0] - 1)?, None);
+    for (i, &lsn) in lsns.iter().enumerate() {
+        let expected = Some(format!("value{}", i + 1));
+        assert_eq!(store.get_as_of("key".to_owned(), lsn)?, expected);
+        // 之后写入的其它 key 不影响
+        assert_eq!(store.get_as_of("key".to_owned(), lsn + 1)?, expected);
+    }
+    assert_eq!(store.get_as_of("key".to_owned(), removed)?, None);
+    assert_eq!(
+        store.get_as_of("other".to_owned(), removed)?,
+        Some("other3".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn overwrite_in_place() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().overwrite_in_place(true).clone();
+    let store = options.open(temp_dir.path())?;
+    store.set("counter".to_owned(), format!("{:04}", 0))?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    let size = store.stats()?.total_log_bytes;
+    for i in 1..1000 {
+        store.set("counter".to_owned(), format!("{:04}", i))?;
+        assert_eq!(store.get("counter".to_owned())?, Some(format!("{:04}", i)));
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.total_log_bytes, size);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(store.current_lsn(), 1001);
+
+    // 长度不同的 value 照常追加
+    store.set("counter".to_owned(), "10000".to_owned())?;
+    assert!(store.stats()?.total_log_bytes > size);
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("counter".to_owned())?, Some("10000".to_owned()));
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.current_lsn(), 1002);
+    store.compact()?;
+    assert_eq!(store.get("counter".to_owned())?, Some("10000".to_owned()));
+
+    // 与 SetIdempotent 的 record 长度相同的 set 也要追加，replay 时还需要它的 token
+    assert!(store.set_idempotent("k".to_owned(), "v".to_owned(), "token".to_owned())?);
+    let idempotent_len = store.get_with_meta("k".to_owned())?.unwrap().1.length;
+    store.set("p".to_owned(), "x".to_owned())?;
+    let set_len = store.get_with_meta("p".to_owned())?.unwrap().1.length;
+    let value = "x".repeat((idempotent_len - set_len + 1) as usize);
+    let size = store.stats()?.total_log_bytes;
+    store.set("k".to_owned(), value.clone())?;
+    assert_eq!(
+        store.get_with_meta("k".to_owned())?.unwrap().1.length,
+        idempotent_len
+    );
+    assert!(store.stats()?.total_log_bytes > size);
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("k".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Opening a store in a directory that is not writable should fail with an error
+// naming the path.
+#[cfg(unix)]
+#[test]
+fn open_read_only_dir() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("store");
+    fs::create_dir(&dir)?;
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o555))?;
+    // root 不受目录权限限制
+    if fs::write(dir.join("probe"), b"").is_ok() {
+        return Ok(());
+    }
+
+    match KvStore::open(&dir) {
+        Err(e @ KvsError::PathIo { .. }) => {
+            assert!(e.to_string().contains(&dir.display().to_string()), "{}", e)
+        }
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+// With `RejectNew`, a store at `max_keys` should reject new keys but still
+// update and remove existing ones.
+#[test]
+fn max_keys_reject_new() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new().max_keys(10).open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(matches!(
+        store.set("key10".to_owned(), "value".to_owned()),
+        Err(KvsError::Capacity { max_keys: 10 })
+    ));
+    assert!(matches!(
+        store.write_batch(vec![
+            BatchOp::Set {
+                key: "key10".to_owned(),
+                value: "value".to_owned(),
+            },
+            BatchOp::Set {
+                key: "key11".to_owned(),
+                value: "value".to_owned(),
+            },
+            BatchOp::Remove {
+                key: "key0".to_owned(),
+            },
+        ]),
+        Err(KvsError::Capacity { max_keys: 10 })
+    ));
+    assert_eq!(store.stats()?.live_keys, 10);
+    assert_eq!(store.get("key10".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key10".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.live_keys, 10);
+    Ok(())
+}
+
+// With `EvictLru`, a store at `max_keys` should evict the least recently read or
+// written keys, also after a reopen.
+#[test]
+fn max_keys_evict_lru() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .max_keys(10)
+        .eviction_policy(EvictionPolicy::EvictLru)
+        .clone();
+    let store = options.open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    // key0 被读过，淘汰的是 key1
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    store.set("key10".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.live_keys, 10);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+
+    for i in 11..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        assert!(store.stats()?.live_keys <= 10);
+    }
+    // batch 中的 key 不会被淘汰
+    store.write_batch(
+        (90..92)
+            .chain(100..105)
+            .map(|i| BatchOp::Set {
+                key: format!("key{}", i),
+                value: "value".to_owned(),
+            })
+            .collect(),
+    )?;
+    assert_eq!(store.stats()?.live_keys, 10);
+    for i in (90..92).chain(100..105) {
+        assert_eq!(store.get(format!("key{}", i))?, Some("value".to_owned()));
+    }
+    drop(store);
+
+    // 淘汰写入了 log，reopen 之后按写入顺序继续淘汰
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.stats()?.live_keys, 10);
+    store.set("key105".to_owned(), "value".to_owned())?;
+    assert_eq!(store.stats()?.live_keys, 10);
+    assert_eq!(store.get("key104".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key105".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// A set of a new key that fails to be written should not evict another key.
+#[test]
+fn max_keys_failed_write_evicts_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .max_keys(2)
+        .eviction_policy(EvictionPolicy::EvictLru)
+        .blob_threshold(16)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    store.set("key2".to_owned(), "value".to_owned())?;
+    // 同名的目录让 blob 文件打不开，大的 value 写入失败
+    fs::create_dir(temp_dir.path().join("1.blob"))?;
+    assert!(store.set("key3".to_owned(), "v".repeat(64)).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    fs::remove_dir(temp_dir.path().join("1.blob"))?;
+    store.set("key3".to_owned(), "v".repeat(64))?;
+    assert_eq!(store.stats()?.live_keys, 2);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("v".repeat(64)));
+    Ok(())
+}
+
+// A store over an in-memory cursor should set, get and remove, and load the
+// same state again from the bytes it wrote.
+#[test]
+fn from_io_cursor() -> Result<()> {
+    let mut store = KvStore::from_io(Cursor::new(Vec::new()))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.remove(String::new()),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    let mut log = store.into_inner().into_inner();
+    let mut store = KvStore::from_io(Cursor::new(log.clone()))?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+
+    // 最后一条 record 被截断，丢弃之后可以继续写
+    log.truncate(log.len() - 3);
+    let mut store = KvStore::from_io(Cursor::new(log))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    let mut store = KvStore::from_io(Cursor::new(store.into_inner().into_inner()))?;
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    Ok(())
+}
+
+// A log file written by a `KvStore` should open with `from_io`.
+#[test]
+fn from_io_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.write_batch(vec![
+        BatchOp::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "key1".to_owned(),
+        },
+    ])?;
+    drop(store);
+
+    let log = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    let mut store = KvStore::from_io(log)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A log in `JsonLines` should hold one json record per line, reload, drop a
+// torn last line, and mix with logs of another encoding.
+#[test]
+fn json_lines_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .log_encoding(LogEncoding::JsonLines)
+        .clone();
+    let store = options.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value\n1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.write_batch(vec![
+        BatchOp::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+        BatchOp::Set {
+            key: "key4".to_owned(),
+            value: "value4".to_owned(),
+        },
+    ])?;
+    drop(store);
+
+    let log = fs::read(temp_dir.path().join("1.log"))?;
+    let log = String::from_utf8(log[1..].to_vec()).expect("log is not text");
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 4);
+    for (i, line) in lines.iter().enumerate() {
+        let record: serde_json::Value = serde_json::from_str(line)?;
+        assert_eq!(record["lsn"], i as u64 + 1);
+    }
+    assert_eq!(lines.iter().filter(|line| line.contains("key2")).count(), 2);
+
+    // 最后一行被截断
+    let path = temp_dir.path().join("2.log");
+    let store = options.open(temp_dir.path())?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+    let len = fs::metadata(&path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 3)?;
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value\n1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, None);
+    assert_eq!(store.current_lsn(), 4);
+    drop(store);
+
+    let store = KvStoreOptions::new()
+        .log_encoding(LogEncoding::Bincode)
+        .open(temp_dir.path())?;
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value\n1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key6".to_owned())?, Some("value6".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,997 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    open_engine, serve_metrics, AuditLog, BoxedEngine, ConnectOptions, ErrorCode,
+    InMemoryKvsEngine, KvStore, KvStoreOptions, KvsClient, KvsClientPool, KvsEngine, KvsError,
+    KvsServer, Protocol, Result, MAX_FRAME_LEN, PROTOCOL_VERSION,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::panic;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn frame(payload: &str) -> Vec<u8> {
+    let mut buf = (payload.len() as u32).to_be_bytes().to_vec();
+    buf.extend_from_slice(payload.as_bytes());
+    buf
+}
+
+fn handshake(protocol: u8, version: u32) -> [u8; 5] {
+    let mut buf = [protocol; 5];
+    buf[1..].copy_from_slice(&version.to_be_bytes());
+    buf
+}
+
+fn read_frame(stream: &mut TcpStream) -> String {
+    let mut len_buf = [0u8; 4];
+    stream.read_exact(&mut len_buf).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    String::from_utf8(payload).unwrap()
+}
+
+// Requests sent back to back before reading should be answered in order.
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4200";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&handshake(b'J', PROTOCOL_VERSION))?;
+    let mut answer = [0u8; 5];
+    stream.read_exact(&mut answer)?;
+    assert_eq!(answer, handshake(b'J', PROTOCOL_VERSION));
+
+    let mut requests = Vec::new();
+    for value in &["value1", "value2", "value3"] {
+        requests.extend(frame(&format!(
+            r#"{{"Set":{{"key":"key1","value":"{}"}}}}"#,
+            value
+        )));
+    }
+    stream.write_all(&requests)?;
+    for _ in 0..3 {
+        assert_eq!(read_frame(&mut stream), r#"{"Ok":null}"#);
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A frame longer than `MAX_FRAME_LEN` should close the connection instead of being read.
+#[test]
+fn oversized_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&handshake(b'J', PROTOCOL_VERSION))?;
+    let mut answer = [0u8; 5];
+    stream.read_exact(&mut answer)?;
+    stream.write_all(&(MAX_FRAME_LEN + 1).to_be_bytes())?;
+    // server 不等待 payload，直接关闭连接
+    let mut buf = [0u8; 1];
+    assert_eq!(stream.read(&mut buf)?, 0);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    handle.shutdown()?;
+    Ok(())
+}
+
+// A bincode client should interoperate with the server, next to json clients.
+#[test]
+fn bincode_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4201";
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect_with_protocol(addr, Protocol::Bincode)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(client.contains_key("key1".to_owned())?);
+    assert!(!client.contains_key("key2".to_owned())?);
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert!(client.remove("key2".to_owned()).is_err());
+
+    let mut json_client = KvsClient::connect(addr)?;
+    assert_eq!(
+        json_client.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned())?;
+    assert_eq!(json_client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// `shutdown` should stop the accept loop and close idle connections.
+#[test]
+fn shutdown_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the client keeps its connection open, shutdown must not wait for it forever
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(handle.shutdown()));
+    receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server did not shut down in time")?;
+
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+
+    Ok(())
+}
+
+// Threads sharing a small pool should all get correct answers.
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let pool = Arc::new(KvsClientPool::connect(addr, 4)?);
+    let threads: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || -> Result<()> {
+                // 8 threads * 125 iterations = 1000 operations of each kind
+                for i in 0..125 {
+                    let key = format!("key{}_{}", thread_id, i);
+                    pool.with_client(|client| client.set(key.clone(), format!("value{}", i)))?;
+                    let value = pool.with_client(|client| client.get(key.clone()))?;
+                    assert_eq!(value, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    handle.shutdown()?;
+
+    // dead connections are replaced once the server is back
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+    );
+    let handle = server.run_in_background(addr)?;
+    let mut failures = 0;
+    let value = loop {
+        match pool.with_client(|client| client.get("key0_0".to_owned())) {
+            Ok(value) => break value,
+            Err(_) => failures += 1,
+        }
+        assert!(failures <= 4, "dead connections were not replaced");
+    };
+    assert_eq!(value, Some("value0".to_owned()));
+    handle.shutdown()?;
+
+    Ok(())
+}
+
+// A closure that panics should not take its client out of the pool for good.
+#[test]
+fn client_pool_panicking_closure() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(2)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let pool = Arc::new(KvsClientPool::connect(handle.local_addr(), 1)?);
+    let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        pool.with_client(|_| -> Result<()> { panic!("deliberate panic in a closure") })
+    }));
+    assert!(res.is_err());
+
+    // 池中唯一的 slot 丢失时这里会一直等待
+    let (tx, rx) = mpsc::channel();
+    let pool2 = Arc::clone(&pool);
+    thread::spawn(move || {
+        let res = pool2.with_client(|client| client.set("key1".to_owned(), "value1".to_owned()));
+        tx.send(res).unwrap();
+    });
+    rx.recv_timeout(Duration::from_secs(5))
+        .expect("the pool lost the slot of the panicking closure")?;
+    assert_eq!(
+        pool.with_client(|client| client.get("key1".to_owned()))?,
+        Some("value1".to_owned())
+    );
+
+    drop(pool);
+    handle.shutdown()
+}
+
+// Both sides should report a protocol version mismatch instead of garbage.
+#[test]
+fn protocol_version_mismatch() -> Result<()> {
+    // a server from the future
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let fake_server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, handshake(b'J', PROTOCOL_VERSION));
+        stream
+            .write_all(&handshake(b'J', PROTOCOL_VERSION + 1))
+            .unwrap();
+    });
+    match KvsClient::connect(addr) {
+        Err(KvsError::ProtocolVersionMismatch { client, server }) => {
+            assert_eq!(client, PROTOCOL_VERSION);
+            assert_eq!(server, PROTOCOL_VERSION + 1);
+        }
+        _ => panic!("expected a protocol version mismatch"),
+    }
+    fake_server.join().unwrap();
+
+    // a client from the future
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.write_all(&handshake(b'J', PROTOCOL_VERSION + 1))?;
+    let mut answer = [0u8; 5];
+    stream.read_exact(&mut answer)?;
+    assert_eq!(answer, handshake(b'J', PROTOCOL_VERSION));
+    // the server closes the connection after answering
+    assert_eq!(stream.read(&mut answer)?, 0);
+    handle.shutdown()?;
+
+    Ok(())
+}
+
+// `get_many` should answer with one value per key, in the order of the keys.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    let keys = vec!["key3", "key2", "key1", "key4", "key1"];
+    let values = client.get_many(keys.into_iter().map(String::from).collect())?;
+    assert_eq!(
+        values,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            Some("value1".to_owned()),
+            None,
+            Some("value1".to_owned())
+        ]
+    );
+    assert_eq!(client.get_many(Vec::new())?, Vec::<Option<String>>::new());
+
+    handle.shutdown()?;
+    Ok(())
+}
+
+fn scrape(addr: std::net::SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.0 200 OK"));
+    response
+}
+
+// The metrics endpoint should expose the counters of the server and the gauges of
+// the store.
+#[test]
+fn metrics_endpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store.clone(), SharedQueueThreadPool::new(1)?);
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = listener.local_addr()?;
+    let metrics = server.metrics();
+    thread::spawn(move || serve_metrics(listener, metrics, move || store.stats().map(Some)));
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let before = scrape(metrics_addr);
+    for name in &[
+        "kvs_requests_total",
+        "kvs_errors_total",
+        "kvs_keys",
+        "kvs_uncompacted_bytes",
+    ] {
+        assert!(before.contains(&format!("# TYPE {} ", name)));
+    }
+    assert!(before.contains("\nkvs_requests_total 0\n"));
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.remove("key2".to_owned()).is_err());
+    let after = scrape(metrics_addr);
+    assert!(after.contains("\nkvs_requests_total 2\n"));
+    assert!(after.contains("\nkvs_errors_total 1\n"));
+    assert!(after.contains("\nkvs_keys 1\n"));
+
+    drop(client);
+    handle.shutdown()
+}
+
+// A connection to the metrics endpoint that never sends a request should not
+// block the scrapes after it.
+#[test]
+fn metrics_endpoint_idle_connection() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = listener.local_addr()?;
+    let metrics =
+        KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?).metrics();
+    thread::spawn(move || serve_metrics(listener, metrics, || Ok(None)));
+
+    let _idle = TcpStream::connect(metrics_addr)?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(scrape(metrics_addr)).unwrap());
+    let response = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("scrape blocked by an idle connection");
+    assert!(response.contains("\nkvs_requests_total 0\n"));
+    Ok(())
+}
+
+// A server with an auth token should only serve connections that sent it.
+#[test]
+fn auth_token() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(2)?)
+        .auth_token("secret");
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    // 没有认证
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+
+    // 错误的 token
+    assert!(matches!(
+        client.auth("secreT".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+    assert!(matches!(
+        client.auth("secret2".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+
+    // 正确的 token
+    client.auth("secret".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    // 认证只对当前连接有效
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Unauthorized)
+    ));
+    drop(client);
+    handle.shutdown()
+}
+
+// A connection that sends nothing should be closed after the client timeout.
+#[test]
+fn client_timeout() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?)
+        .client_timeout(Duration::from_millis(200));
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut stream = TcpStream::connect(handle.local_addr())?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let start = Instant::now();
+    let mut buf = [0u8; 1];
+    // server 关闭连接之后读到 EOF
+    assert_eq!(stream.read(&mut buf)?, 0);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // 唯一的线程被释放之后，其它 client 可以正常使用
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+    handle.shutdown()
+}
+
+// `get_or_error` should answer a missing key with `KvsError::KeyNotFound`.
+#[test]
+fn get_or_error() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get_or_error("key1".to_owned())?, "value1");
+    assert!(matches!(
+        client.get_or_error("key2".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(client);
+    handle.shutdown()
+}
+
+// `rename` over the wire should move the value and fail on a missing source.
+#[test]
+fn rename() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        client.rename("key1".to_owned(), "key3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(client.get("key3".to_owned())?, None);
+    drop(client);
+    handle.shutdown()
+}
+
+// `get_set` over the wire should return the previous value.
+#[test]
+fn get_set() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    assert_eq!(
+        client.get_set("key1".to_owned(), "value1".to_owned())?,
+        None
+    );
+    assert_eq!(
+        client.get_set("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(client);
+    handle.shutdown()
+}
+
+// Of the clients racing on `set_if_absent`, exactly one should win.
+#[test]
+fn set_if_absent() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(4)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || -> Result<bool> {
+                let mut client = KvsClient::connect(addr)?;
+                client.set_if_absent("lock".to_owned(), format!("owner{}", i))
+            })
+        })
+        .collect();
+    let mut winners = Vec::new();
+    for (i, thread) in threads.into_iter().enumerate() {
+        if thread.join().unwrap()? {
+            winners.push(format!("owner{}", i));
+        }
+    }
+    assert_eq!(winners.len(), 1);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("lock".to_owned())?, winners.pop());
+    assert!(!client.set_if_absent("lock".to_owned(), "owner".to_owned())?);
+    drop(client);
+    handle.shutdown()
+}
+
+// `clear` over the wire should remove every key.
+#[test]
+fn clear() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.clear()?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value3".to_owned()));
+    drop(client);
+    handle.shutdown()
+}
+
+// An engine whose every call waits for `busy` to be released, standing in for
+// an engine held up by a long compaction.
+#[derive(Clone)]
+struct BusyEngine {
+    engine: InMemoryKvsEngine,
+    busy: Arc<Mutex<()>>,
+}
+
+impl KvsEngine for BusyEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.remove(key)
+    }
+
+    fn rename(&self, from: String, to: String) -> Result<()> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.rename(from, to)
+    }
+
+    fn get_set(&self, key: String, value: String) -> Result<Option<String>> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.get_set(key, value)
+    }
+
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.set_if_absent(key, value)
+    }
+
+    fn clear(&self) -> Result<()> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.clear()
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let _busy = self.busy.lock().unwrap();
+        self.engine.contains_key(key)
+    }
+}
+
+// `ping` should be answered even while the engine is stuck.
+#[test]
+fn ping() -> Result<()> {
+    let busy = Arc::new(Mutex::new(()));
+    let engine = BusyEngine {
+        engine: InMemoryKvsEngine::new(),
+        busy: Arc::clone(&busy),
+    };
+    let server = KvsServer::new(engine, SharedQueueThreadPool::new(2)?).auth_token("secret");
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    client.ping()?;
+
+    // 一个 request 卡在 engine 中，ping 仍然能返回
+    let guard = busy.lock().unwrap();
+    let writer = thread::spawn(move || -> Result<()> {
+        let mut client = KvsClient::connect(addr)?;
+        client.auth("secret".to_owned())?;
+        client.set("key1".to_owned(), "value1".to_owned())
+    });
+    for _ in 0..3 {
+        assert!(client.ping()? < Duration::from_secs(1));
+    }
+    assert!(!writer.is_finished());
+    drop(guard);
+    writer.join().unwrap()?;
+    drop(client);
+    handle.shutdown()
+}
+
+// The counters in a `Stats` response should match the requests sent before it.
+#[test]
+fn stats() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(4)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                let key = format!("key{}", i);
+                client.set(key.clone(), "value".to_owned())?;
+                client.set(key.clone(), "value".to_owned())?;
+                client.get(key.clone())?;
+                client.remove(key.clone())?;
+                // remove 不存在的 key 是一个 error
+                assert!(client.remove(key).is_err());
+                client.ping()?;
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    let stats = client.stats()?;
+    assert_eq!(stats.total_requests, 24);
+    assert_eq!(stats.sets, 8);
+    assert_eq!(stats.gets, 4);
+    assert_eq!(stats.removes, 8);
+    assert_eq!(stats.errors, 4);
+    assert!(stats.uptime_secs < 60);
+    // Stats 自己在返回之后才计数
+    assert_eq!(client.stats()?.total_requests, 25);
+    drop(client);
+    handle.shutdown()
+}
+
+// Connections over the limit should be rejected at the handshake, without
+// disturbing the connections being served.
+#[test]
+fn max_connections() -> Result<()> {
+    let server =
+        KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(4)?).max_connections(2);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut client1 = KvsClient::connect(addr)?;
+    let mut client2 = KvsClient::connect(addr)?;
+    for _ in 0..5 {
+        assert!(matches!(
+            KvsClient::connect(addr),
+            Err(KvsError::TooManyConnections { limit: 2 })
+        ));
+    }
+    client1.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client2.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // 连接关闭之后空出一个位置
+    drop(client1);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut client3 = loop {
+        match KvsClient::connect(addr) {
+            Err(KvsError::TooManyConnections { .. }) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            res => break res?,
+        }
+    };
+    assert_eq!(client3.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client2);
+    drop(client3);
+    handle.shutdown()
+}
+
+// Connections over the limit that never send a handshake should not hold up the
+// answers to the next ones.
+#[test]
+fn max_connections_silent_clients() -> Result<()> {
+    let server =
+        KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(2)?).max_connections(1);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let addr = handle.local_addr();
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut silent = Vec::new();
+    for _ in 0..20 {
+        silent.push(TcpStream::connect(addr)?);
+    }
+    // 每个连接都等待 handshake 100ms 的话，最后一个连接要等 2s
+    let start = Instant::now();
+    for stream in &mut silent {
+        let mut answer = [0u8; 1];
+        stream.read_exact(&mut answer)?;
+        assert_eq!(answer, [b'!']);
+    }
+    assert!(start.elapsed() < Duration::from_secs(1));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(silent);
+    drop(client);
+    handle.shutdown()
+}
+
+// Errors should keep their kind over the wire, whatever the protocol.
+#[test]
+fn typed_remote_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let server = KvsServer::new(
+        KvStore::open_read_only(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    for protocol in [Protocol::Json, Protocol::Bincode] {
+        let mut client = KvsClient::connect_with_protocol(handle.local_addr(), protocol)?;
+        assert!(matches!(
+            client.remove("missing".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert!(matches!(
+            client.set("key2".to_owned(), "value2".to_owned()),
+            Err(KvsError::ReadOnly)
+        ));
+        match client.get(String::new()) {
+            Err(KvsError::StringError(msg)) => assert!(msg.contains("the key is empty")),
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+    handle.shutdown()
+}
+
+// An I/O error of the engine should reach the client as `KvsError::Remote`, and
+// the pool should keep using the connection.
+#[test]
+fn remote_io_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .blob_threshold(16)
+        .open(temp_dir.path())?;
+    // 同名的目录让 blob 文件打不开
+    fs::create_dir(temp_dir.path().join("1.blob"))?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let pool = KvsClientPool::connect(handle.local_addr(), 1)?;
+    for _ in 0..2 {
+        match pool.with_client(|client| client.set("key1".to_owned(), "v".repeat(64))) {
+            Err(KvsError::Remote {
+                code: ErrorCode::Io,
+                ..
+            }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+    pool.with_client(|client| client.set("key1".to_owned(), "value1".to_owned()))?;
+    assert_eq!(
+        pool.with_client(|client| client.get("key1".to_owned()))?,
+        Some("value1".to_owned())
+    );
+    drop(pool);
+    handle.shutdown()
+}
+
+// Polls `replica` until `key` has `expected`, failing after a few seconds.
+fn wait_for(replica: &KvStore, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let value = replica.get(key.to_owned())?;
+        if value.as_deref() == expected {
+            return Ok(());
+        }
+        assert!(Instant::now() < deadline, "{}: {:?}", key, value);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// A replica following a primary should see the writes made to the primary.
+#[test]
+fn replication() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary = KvStore::open(primary_dir.path())?;
+    // 一个线程一直用于向 replica 发送 record
+    let server = KvsServer::new(primary, SharedQueueThreadPool::new(2)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let replica = KvStore::open(replica_dir.path())?;
+    let follower = replica.follow(KvsClient::connect(handle.local_addr())?)?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.remove("key0".to_owned())?;
+    client.rename("key1".to_owned(), "renamed".to_owned())?;
+    wait_for(&replica, "renamed", Some("value1"))?;
+    for i in 2..100 {
+        assert_eq!(
+            replica.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(replica.get("key0".to_owned())?, None);
+    assert_eq!(replica.get("key1".to_owned())?, None);
+
+    client.clear()?;
+    client.set("after".to_owned(), "clear".to_owned())?;
+    wait_for(&replica, "after", Some("clear"))?;
+    assert_eq!(replica.get("key2".to_owned())?, None);
+
+    // primary 停止之后 replica 的线程退出
+    drop(client);
+    handle.shutdown()?;
+    follower.join().unwrap()?;
+    drop(replica);
+    let replica = KvStore::open(replica_dir.path())?;
+    assert_eq!(replica.get("after".to_owned())?, Some("clear".to_owned()));
+    assert_eq!(replica.get("renamed".to_owned())?, None);
+
+    // 没有 log 的 engine 不能被 follow
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    assert!(matches!(
+        replica.follow(KvsClient::connect(handle.local_addr())?),
+        Err(KvsError::StringError(_))
+    ));
+    handle.shutdown()
+}
+
+// Connecting with retries should give up after the last attempt when nothing
+// listens, and succeed when the server starts while retrying.
+#[test]
+fn connect_with_retries() -> Result<()> {
+    let options = ConnectOptions {
+        timeout: Duration::from_millis(200),
+        retries: 2,
+        backoff: Duration::from_millis(50),
+        ..ConnectOptions::default()
+    };
+    let start = Instant::now();
+    match KvsClient::connect_with("127.0.0.1:4202", options) {
+        Err(KvsError::ConnectFailed { attempts, .. }) => assert_eq!(attempts, 3),
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    // 3 次尝试，每次最多 200ms，中间等待 50ms + 100ms
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let addr = "127.0.0.1:4203";
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(300));
+        KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?).run(addr)
+    });
+    let options = ConnectOptions {
+        timeout: Duration::from_millis(200),
+        retries: 10,
+        backoff: Duration::from_millis(50),
+        ..ConnectOptions::default()
+    };
+    let mut client = KvsClient::connect_with(addr, options)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Turning TCP_NODELAY off and keepalive on only changes how the bytes are sent.
+#[test]
+fn tcp_options() -> Result<()> {
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?)
+        .nodelay(false)
+        .keepalive(Duration::from_secs(60));
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let options = ConnectOptions {
+        nodelay: false,
+        keepalive: Some(Duration::from_secs(60)),
+        ..ConnectOptions::default()
+    };
+    let mut client = KvsClient::connect_with(handle.local_addr(), options)?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    handle.shutdown()
+}
+
+// A server should serve an engine picked at runtime behind a trait object.
+#[test]
+fn boxed_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Box<dyn KvsEngine> = open_engine("kvs".parse()?, temp_dir.path())?;
+    let server = KvsServer::new_boxed(engine, SharedQueueThreadPool::new(2)?);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+    handle.shutdown()?;
+
+    // clone 共享同一个 engine
+    let engine = BoxedEngine::new(Box::new(InMemoryKvsEngine::new()));
+    engine.clone().set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Every request should get a parseable line in the audit file, rotated to
+// `.1` once it is full.
+#[test]
+fn audit_log_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("audit.log");
+    let audit = AuditLog::open(&path, 8 * 1024)?.max_files(1);
+    let server =
+        KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?).audit_log(audit);
+    let handle = server.run_in_background("127.0.0.1:0")?;
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    for i in 0..200 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+        client.get(format!("key{}", i))?;
+    }
+    client.remove("missing".to_owned()).unwrap_err();
+    drop(client);
+    handle.shutdown()?;
+
+    let mut files: Vec<_> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["audit.log", "audit.log.1"]);
+    let mut lines = Vec::new();
+    // 从旧到新
+    for file in files.iter().rev() {
+        let content = fs::read_to_string(temp_dir.path().join(file))?;
+        assert!(content.len() <= 8 * 1024);
+        for line in content.lines() {
+            lines.push(serde_json::from_str::<serde_json::Value>(line)?);
+        }
+    }
+    // 更早的 line 已经随着 rotation 删除了
+    assert!(lines.len() < 401);
+    let last = lines.last().unwrap();
+    assert_eq!(last["op"], "remove");
+    assert_eq!(last["key"], "missing");
+    assert_eq!(last["result"], "err");
+    assert!(last["latency_ms"].is_number());
+    assert!(lines
+        .iter()
+        .all(|line| line["op"] == "set" || line["op"] == "get" || line == last));
+    Ok(())
+}
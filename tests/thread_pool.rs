@@ -0,0 +1,144 @@
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+const TASK_NUM: usize = 20;
+const ADD_COUNT: usize = 1000;
+
+// Submit more jobs than threads and wait until all of them complete.
+fn spawn_counter<P: ThreadPool>(pool: P) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..TASK_NUM {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            for _ in 0..ADD_COUNT {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            tx.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..TASK_NUM {
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("job did not finish in time");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM * ADD_COUNT);
+    Ok(())
+}
+
+// Panicking jobs must not stop the pool from running later jobs.
+fn spawn_panic_task<P: ThreadPool>(pool: P) -> Result<()> {
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || panic!("deliberate panic in a job"));
+    }
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_spawn_counter() -> Result<()> {
+    let pool = NaiveThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_panic_task() -> Result<()> {
+    let pool = NaiveThreadPool::new(4)?;
+    spawn_panic_task(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_task() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(4)?;
+    spawn_panic_task(pool)
+}
+
+// A pool without a worker thread would never run a job, so it is refused.
+#[test]
+fn shared_queue_thread_pool_zero_threads() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+}
+
+// Workers replaced after panics keep the pool at full strength.
+#[test]
+fn shared_queue_thread_pool_survives_panicking_jobs() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(8)?;
+    let (tx, rx) = mpsc::channel();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for i in 0..100 {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            if i % 3 == 0 {
+                panic!("deliberate panic in job {}", i);
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+            tx.send(()).unwrap();
+        });
+    }
+    for i in 0..100 {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            // keep the workers busy so that every one of them is needed
+            std::thread::sleep(Duration::from_millis(i % 5));
+            counter.fetch_add(1, Ordering::SeqCst);
+            tx.send(()).unwrap();
+        });
+    }
+
+    let expected = (0..100).filter(|i| i % 3 != 0).count() + 100;
+    for _ in 0..expected {
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("job did not finish in time");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), expected);
+    Ok(())
+}
+
+#[test]
+fn rayon_thread_pool_spawn_counter() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    spawn_panic_task(pool)
+}
+
+#[test]
+fn rayon_thread_pool_sums_increment_jobs() -> Result<()> {
+    let pool = RayonThreadPool::new(4)?;
+    let (tx, rx) = mpsc::channel();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    for i in 0..1000 {
+        let counter = Arc::clone(&counter);
+        let tx = tx.clone();
+        pool.spawn(move || {
+            counter.fetch_add(i, Ordering::SeqCst);
+            tx.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..1000 {
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("job did not finish in time");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), (0..1000).sum::<usize>());
+    Ok(())
+}
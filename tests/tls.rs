@@ -0,0 +1,43 @@
+#![cfg(feature = "tls")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{InMemoryKvsEngine, KvsClient, KvsError, KvsServer, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+// Writes a self-signed certificate for 127.0.0.1 and its key, returns their paths.
+fn self_signed(dir: &Path) -> (PathBuf, PathBuf) {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()])
+        .expect("unable to generate a certificate");
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    fs::write(&cert, certified.cert.pem()).unwrap();
+    fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+    (cert, key)
+}
+
+// A set/get round-trip over TLS, trusting the self-signed certificate.
+#[test]
+fn tls_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (cert, key) = self_signed(temp_dir.path());
+    let server = KvsServer::new(InMemoryKvsEngine::new(), SharedQueueThreadPool::new(1)?);
+    let handle = server.run_tls_in_background("127.0.0.1:0", &cert, &key)?;
+    let addr = handle.local_addr().to_string();
+
+    let mut client = KvsClient::connect_tls(&addr, &cert)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    drop(client);
+
+    // 证书不是由信任的 CA 签发的
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (other_ca, _) = self_signed(other_dir.path());
+    assert!(matches!(
+        KvsClient::connect_tls(&addr, &other_ca),
+        Err(KvsError::Io(_))
+    ));
+    handle.shutdown()
+}
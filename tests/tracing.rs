@@ -0,0 +1,93 @@
+#![cfg(feature = "tracing")]
+
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsClient, KvsServer, Result};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+type Fields = HashMap<String, String>;
+
+// Records the fields of the `request` spans and of the events inside them.
+#[derive(Default, Clone)]
+struct Capture {
+    spans: Arc<Mutex<Vec<Fields>>>,
+    events: Arc<Mutex<Vec<Fields>>>,
+}
+
+struct Recorder<'a>(&'a mut Fields);
+
+impl Visit for Recorder<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value));
+    }
+}
+
+impl<S> Layer<S> for Capture
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        if attrs.metadata().name() == "request" {
+            let mut fields = Fields::new();
+            attrs.record(&mut Recorder(&mut fields));
+            self.spans.lock().unwrap().push(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if ctx.event_span(event).map(|span| span.name()) == Some("request") {
+            let mut fields = Fields::new();
+            event.record(&mut Recorder(&mut fields));
+            self.events.lock().unwrap().push(fields);
+        }
+    }
+}
+
+// A request should run in a span with the peer and a request id, and report
+// its key, latency and result.
+#[test]
+fn request_span_fields() -> Result<()> {
+    let capture = Capture::default();
+    tracing::subscriber::set_global_default(Registry::default().with(capture.clone()))
+        .expect("unable to install the subscriber");
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    let handle = server.run_in_background("127.0.0.1:0")?;
+
+    let mut client = KvsClient::connect(handle.local_addr())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+    // shutdown 会等待所有连接处理完
+    handle.shutdown()?;
+
+    let spans = capture.spans.lock().unwrap();
+    assert_eq!(spans.len(), 1);
+    assert!(spans[0]["peer"].starts_with("127.0.0.1:"));
+    assert!(spans[0].contains_key("request_id"));
+
+    let events = capture.events.lock().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0]["op"], "set");
+    assert_eq!(events[0]["key"], "key1");
+    assert_eq!(events[0]["result"], "ok");
+    assert!(events[0]["latency_ms"].parse::<f64>().is_ok());
+
+    Ok(())
+}